@@ -0,0 +1,321 @@
+//! Portable export and import of a gym.
+//!
+//! An archive is a NDJSON file: a header with the format version followed by
+//! one record per line for every object, patch, snapshot and session of the
+//! gym. Object ids and revision ids are kept, so an archive can be restored
+//! into any backend and under another gym name. Views are not part of it,
+//! they are rebuilt from the snapshots after an import.
+
+use std::{
+    collections::BTreeSet,
+    fmt,
+    io::{BufRead, Write},
+};
+
+use chrono::{DateTime, Utc};
+use otp::{ObjectId, ZERO_REV_ID};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppError, AppState,
+    backend::WriteBatch,
+    passport::Session,
+    rebuild,
+    types::{Gym, Patch, Snapshot, object::ObjectDoc},
+};
+
+/// version of the archive format, bump on incompatible changes
+const ARCHIVE_VERSION: u32 = 1;
+
+/// patches and snapshots written per commit, below the transaction limit
+/// of Firestore
+const IMPORT_BATCH_SIZE: usize = 400;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Header {
+    version: u32,
+    /// the gym the archive was exported from
+    gym: String,
+    exported_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Record {
+    Header(Header),
+    Object(ObjectDoc),
+    Patch(Patch),
+    Snapshot(Snapshot),
+    Session(Session),
+}
+
+/// number of records exported or imported
+#[derive(Default, Debug, PartialEq)]
+pub struct Counts {
+    pub objects: usize,
+    pub patches: usize,
+    pub snapshots: usize,
+    pub sessions: usize,
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} objects, {} patches, {} snapshots, {} sessions",
+            self.objects, self.patches, self.snapshots, self.sessions
+        )
+    }
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> Result<(), AppError> {
+    serde_json::to_writer(&mut *out, record).map_err(|e| {
+        AppError::Internal(format!("serialisation failed: {e}"))
+    })?;
+    writeln!(out).map_err(|e| AppError::Internal(format!("write failed: {e}")))
+}
+
+/// write all objects, patches, snapshots and sessions of the gym to `out`
+pub async fn export_gym(
+    state: &AppState,
+    gym: &str,
+    mut out: impl Write,
+) -> Result<Counts, AppError> {
+    let mut counts = Counts::default();
+    let header = Header {
+        version: ARCHIVE_VERSION,
+        gym: gym.to_string(),
+        exported_at: Utc::now(),
+    };
+    write_record(&mut out, &Record::Header(header))?;
+
+    let object_ids: BTreeSet<ObjectId> =
+        state.db.object_ids(gym).await?.into_iter().collect();
+    for object_id in &object_ids {
+        if let Some(object) = state.db.lookup_object(gym, object_id).await? {
+            write_record(&mut out, &Record::Object(object))?;
+            counts.objects += 1;
+        }
+    }
+
+    // including the history of missing objects, an export loses nothing
+    let history = state.db.history_object_ids(gym).await?;
+    for object_id in object_ids.union(&history) {
+        for patch in
+            Patch::after_revision(state, gym, object_id, ZERO_REV_ID - 1)
+                .await?
+        {
+            write_record(&mut out, &Record::Patch(patch))?;
+            counts.patches += 1;
+        }
+        for snapshot in Snapshot::lookup_stored(state, gym, object_id).await? {
+            write_record(&mut out, &Record::Snapshot(snapshot))?;
+            counts.snapshots += 1;
+        }
+    }
+
+    for session in state.db.sessions(gym).await? {
+        write_record(&mut out, &Record::Session(session))?;
+        counts.sessions += 1;
+    }
+
+    out.flush()
+        .map_err(|e| AppError::Internal(format!("write failed: {e}")))?;
+    Ok(counts)
+}
+
+async fn flush_batch(
+    state: &AppState,
+    gym: &str,
+    batch: &mut WriteBatch,
+) -> Result<(), AppError> {
+    if !batch.patches.is_empty() || !batch.snapshots.is_empty() {
+        state.db.commit(gym, &std::mem::take(batch)).await?;
+    }
+    Ok(())
+}
+
+/// Recreate the gym of an archive, under `gym` if given. The target gym must
+/// not have any objects, patches or snapshots yet.
+pub async fn import_gym(
+    state: &AppState,
+    gym: Option<&str>,
+    input: impl BufRead,
+) -> Result<Counts, AppError> {
+    let mut lines = input.lines().enumerate();
+    let mut next_record = || -> Result<Option<Record>, AppError> {
+        for (n, line) in lines.by_ref() {
+            let line = line
+                .map_err(|e| AppError::Internal(format!("read failed: {e}")))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return serde_json::from_str(&line).map(Some).map_err(|e| {
+                AppError::ParseError(format!("{e} in line {}", n + 1))
+            });
+        }
+        Ok(None)
+    };
+
+    let header = match next_record()? {
+        Some(Record::Header(header)) => header,
+        _ => {
+            return Err(AppError::Query(
+                "archive does not start with a header".to_string(),
+            ));
+        }
+    };
+    if header.version != ARCHIVE_VERSION {
+        return Err(AppError::Query(format!(
+            "unsupported archive version {}",
+            header.version
+        )));
+    }
+    let gym = gym.unwrap_or(&header.gym);
+    if !state.db.object_ids(gym).await?.is_empty()
+        || !state.db.history_object_ids(gym).await?.is_empty()
+    {
+        return Err(AppError::Query(format!("gym {gym} is not empty")));
+    }
+    if Gym::register(state, gym).await? {
+        tracing::info!("registered gym {gym}");
+    }
+
+    let mut counts = Counts::default();
+    let mut batch = WriteBatch::default();
+    while let Some(record) = next_record()? {
+        match record {
+            Record::Header(_) => {
+                return Err(AppError::Query(
+                    "archive with several headers".to_string(),
+                ));
+            }
+            Record::Object(object) => {
+                state.db.import_object(gym, &object).await?;
+                counts.objects += 1;
+            }
+            Record::Patch(patch) => {
+                batch.patches.push(patch);
+                counts.patches += 1;
+            }
+            Record::Snapshot(snapshot) => {
+                batch.snapshots.push(snapshot);
+                counts.snapshots += 1;
+            }
+            Record::Session(session) => {
+                let session_id = session
+                    .id
+                    .clone()
+                    .ok_or(AppError::Query("session without id".to_string()))?;
+                state.db.store_session(gym, &session_id, &session).await?;
+                counts.sessions += 1;
+            }
+        }
+        if batch.patches.len() + batch.snapshots.len() >= IMPORT_BATCH_SIZE {
+            flush_batch(state, gym, &mut batch).await?;
+        }
+    }
+    flush_batch(state, gym, &mut batch).await?;
+
+    rebuild::rebuild_views(state, gym, false).await?;
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        storage::apply_object_updates,
+        test_boulder,
+        types::{BouldersView, Object, ObjectType, SnapshotPolicy, view},
+    };
+
+    #[tokio::test]
+    async fn export_and_import_under_another_name() {
+        let state = AppState {
+            snapshot_policy: SnapshotPolicy {
+                revisions: 1,
+                ..SnapshotPolicy::default()
+            },
+            ..AppState::in_memory()
+        };
+        let obj = Object::from_value(
+            &state,
+            "old",
+            String::from("author"),
+            ObjectType::Boulder,
+            &test_boulder(),
+        )
+        .await
+        .unwrap();
+        let operation = otp::Operation::new_set("grade", json!("yellow"));
+        let _ = apply_object_updates(
+            &state,
+            "old",
+            obj.id.clone(),
+            0,
+            String::from("author"),
+            vec![operation],
+        )
+        .await
+        .unwrap();
+        let created_at = Utc::now() - chrono::TimeDelta::days(100);
+        let session = Session {
+            id: Some(String::from("session")),
+            created_at: Some(created_at),
+            ..Session::new(String::from("author"), None, None)
+        };
+        state
+            .db
+            .store_session("old", "session", &session)
+            .await
+            .unwrap();
+
+        let mut archive = Vec::new();
+        let exported = export_gym(&state, "old", &mut archive).await.unwrap();
+        let expected = Counts {
+            objects: 1,
+            patches: 2,
+            snapshots: 1,
+            sessions: 1,
+        };
+        assert_eq!(expected, exported);
+
+        let imported = import_gym(&state, Some("new"), archive.as_slice())
+            .await
+            .unwrap();
+        assert_eq!(expected, imported);
+        let patches =
+            Patch::after_revision(&state, "new", &obj.id, ZERO_REV_ID - 1)
+                .await
+                .unwrap();
+        let revisions: Vec<_> = patches.iter().map(|p| p.revision_id).collect();
+        assert_eq!(vec![0, 1], revisions);
+        let row: Option<serde_json::Value> =
+            view::lookup(&state, "new", &BouldersView, &obj.id)
+                .await
+                .unwrap();
+        assert_eq!(
+            Some(&json!("yellow")),
+            row.as_ref().and_then(|r| r.get("grade"))
+        );
+        // sessions expire as they would have without the import
+        let session = state
+            .db
+            .lookup_session("new", "session")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(created_at), session.created_at);
+
+        // importing twice would mix two histories
+        assert!(
+            import_gym(&state, Some("new"), archive.as_slice())
+                .await
+                .is_err()
+        );
+    }
+}
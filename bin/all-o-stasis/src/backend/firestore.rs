@@ -0,0 +1,1057 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use firestore::{
+    FirestoreDb, FirestoreDocument, FirestoreListenEvent,
+    FirestoreListenerTarget, FirestoreMemListenStateStorage,
+    FirestoreQueryDirection, FirestoreResult, FirestoreWritePrecondition,
+    errors::FirestoreError, path_camel_case,
+};
+use futures::{StreamExt, TryStreamExt, stream, stream::BoxStream};
+use otp::{ObjectId, RevId, ZERO_REV_ID};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::mpsc::{self, Sender};
+
+use crate::{
+    AppError,
+    backend::{PatchStream, Storage, WriteBatch},
+    passport::{Session, new_id},
+    types::{Filter, Gym, Patch, Snapshot, ViewQuery, object::ObjectDoc},
+};
+
+const OBJECTS: &str = "objects";
+const PATCHES: &str = "patches";
+const SNAPSHOTS: &str = "snapshots";
+const SESSIONS: &str = "sessions";
+/// registry of gyms, the documents are the parents of their collections
+const GYMS: &str = "gyms";
+
+// `IN` filters accept at most 30 values
+const MAX_IN_VALUES: usize = 30;
+
+// listener targets only need to be unique per listener
+static LISTENER_TARGET: AtomicU32 = AtomicU32::new(1);
+
+macro_rules! store {
+    ($db:expr, $gym:expr, $entity:expr, $collection:expr) => {{
+        let parent_path = $db.parent_path("gyms", $gym)?;
+        let result = $db
+            .fluent()
+            .insert()
+            .into($collection)
+            .generate_document_id()
+            .parent(&parent_path)
+            .object($entity)
+            .execute()
+            .await?;
+
+        match &result {
+            Some(r) => tracing::debug!("storing: {r}"),
+            None => tracing::warn!("failed to store: {}", $entity),
+        }
+
+        result
+    }};
+}
+
+/// view rows without the id, it is the document id
+fn to_document_fields(row: &Value) -> Value {
+    let mut row = row.clone();
+    if let Some(fields) = row.as_object_mut() {
+        fields.remove("id");
+    }
+    row
+}
+
+/// view rows with the document id as id and without other document metadata
+fn from_document_fields(mut row: Value) -> Value {
+    if let Some(fields) = row.as_object_mut() {
+        let id = fields.remove("_firestore_id");
+        fields.retain(|key, _| !key.starts_with("_firestore_"));
+        if let Some(id) = id {
+            fields.insert(String::from("id"), id);
+        }
+    }
+    row
+}
+
+/// the last segment of the document name
+fn document_id(doc: &FirestoreDocument) -> &str {
+    doc.name.rsplit('/').next().unwrap_or_default()
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SnapshotRevision {
+    revision_id: RevId,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryObjectId {
+    object_id: ObjectId,
+}
+
+/// A session as read from Firestore. The creation time is stored as a field
+/// since migrations and imports store sessions anew, documents from before
+/// only have the time they were created at.
+#[derive(Deserialize)]
+struct SessionDocument {
+    #[serde(flatten)]
+    session: Session,
+    #[serde(rename = "_firestore_created")]
+    document_created_at: Option<DateTime<Utc>>,
+}
+
+impl From<SessionDocument> for Session {
+    fn from(document: SessionDocument) -> Self {
+        Self {
+            created_at: document
+                .session
+                .created_at
+                .or(document.document_created_at),
+            ..document.session
+        }
+    }
+}
+
+pub struct FirestoreStorage {
+    db: Arc<FirestoreDb>,
+}
+
+impl FirestoreStorage {
+    pub fn new(db: FirestoreDb) -> Self {
+        Self { db: Arc::new(db) }
+    }
+}
+
+async fn handle_listener_event(
+    event: FirestoreListenEvent,
+    patches: Sender<Patch>,
+    listener_start_time: DateTime<Utc>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match event {
+        FirestoreListenEvent::DocumentChange(ref doc_change) => {
+            tracing::debug!("document changed: {doc_change:?}");
+
+            if let Some(doc) = &doc_change.document {
+                let patch: Patch =
+                    FirestoreDb::deserialize_doc_to::<Patch>(doc)?;
+
+                // the listener starts with all existing documents, only send
+                // patches that came in after we started the listener
+                match patch.created_at {
+                    Some(created) if created >= listener_start_time => {
+                        if let Err(err) = patches.send(patch).await {
+                            tracing::error!("failed to sent patch with {err}");
+                        }
+                    }
+                    Some(_) => {}
+                    None => tracing::error!("patch without created_at"),
+                }
+            }
+        }
+        _ => {
+            tracing::error!(
+                "received a listen response event to handle: {event:?}"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl Storage for FirestoreStorage {
+    async fn healthz(&self) -> Result<(), AppError> {
+        let _db_is_alive = self
+            .db
+            .fluent()
+            .list()
+            .collections()
+            .stream_all_with_errors()
+            .await?;
+        Ok(())
+    }
+
+    async fn create_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<ObjectDoc, AppError> {
+        let s: Option<ObjectDoc> = store!(self.db, gym, object, OBJECTS);
+        s.ok_or(AppError::Internal("storing object failed".to_string()))
+    }
+
+    async fn import_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<(), AppError> {
+        let id = object
+            .id
+            .clone()
+            .ok_or(AppError::Query("object without id".to_string()))?;
+        // id and creation time are document metadata
+        let object = ObjectDoc {
+            id: None,
+            created_at: None,
+            ..object.clone()
+        };
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let _: Option<ObjectDoc> = self
+            .db
+            .fluent()
+            .update()
+            .in_col(OBJECTS)
+            .document_id(id)
+            .parent(&parent_path)
+            .object(&object)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn gyms(&self) -> Result<Vec<String>, AppError> {
+        // objects of all gyms: gyms/{gym}/objects/{id}
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .fields([path_camel_case!(ObjectDoc::object_type)])
+            .from(OBJECTS)
+            .all_descendants()
+            .query()
+            .await?;
+
+        let gyms: BTreeSet<String> = docs
+            .iter()
+            .filter_map(|doc| doc.name.rsplit('/').nth(2))
+            .map(String::from)
+            .collect();
+        Ok(gyms.into_iter().collect())
+    }
+
+    async fn store_gym(&self, gym: &Gym) -> Result<(), AppError> {
+        let _: Option<Gym> = self
+            .db
+            .fluent()
+            .update()
+            .in_col(GYMS)
+            .document_id(&gym.name)
+            .object(gym)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn lookup_gym(&self, name: &str) -> Result<Option<Gym>, AppError> {
+        Ok(self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(GYMS)
+            .obj()
+            .one(name)
+            .await?)
+    }
+
+    async fn registered_gyms(&self) -> Result<Vec<Gym>, AppError> {
+        Ok(self.db.fluent().select().from(GYMS).obj().query().await?)
+    }
+
+    async fn object_ids(&self, gym: &str) -> Result<Vec<ObjectId>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let docs = self
+            .db
+            .fluent()
+            .select()
+            .fields([path_camel_case!(ObjectDoc::object_type)])
+            .from(OBJECTS)
+            .parent(&parent_path)
+            .query()
+            .await?;
+
+        Ok(docs.iter().map(|d| document_id(d).to_string()).collect())
+    }
+
+    async fn history_object_ids(
+        &self,
+        gym: &str,
+    ) -> Result<BTreeSet<ObjectId>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let mut ids = BTreeSet::new();
+        for collection in [PATCHES, SNAPSHOTS] {
+            let docs: Vec<HistoryObjectId> = self
+                .db
+                .fluent()
+                .select()
+                .fields([path_camel_case!(Patch::object_id)])
+                .from(collection)
+                .parent(&parent_path)
+                .obj()
+                .query()
+                .await?;
+            ids.extend(docs.into_iter().map(|d| d.object_id));
+        }
+        Ok(ids)
+    }
+
+    async fn lookup_object(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<ObjectDoc>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        Ok(self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(OBJECTS)
+            .parent(&parent_path)
+            .obj()
+            .one(object_id)
+            .await?)
+    }
+
+    async fn lookup_objects(
+        &self,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<Vec<ObjectDoc>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let objects: Vec<(String, Option<ObjectDoc>)> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(OBJECTS)
+            .parent(&parent_path)
+            .obj()
+            .batch_with_errors(object_ids)
+            .await?
+            .try_collect()
+            .await?;
+
+        Ok(objects.into_iter().filter_map(|(_, doc)| doc).collect())
+    }
+
+    async fn set_object_deleted(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        deleted: bool,
+    ) -> Result<(), AppError> {
+        let mut object = self
+            .lookup_object(gym, object_id)
+            .await?
+            .ok_or(AppError::Query(format!("no object {object_id}")))?;
+        object.deleted = Some(deleted);
+
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let _: ObjectDoc = self
+            .db
+            .fluent()
+            .update()
+            .fields([path_camel_case!(ObjectDoc::deleted)])
+            .in_col(OBJECTS)
+            .document_id(object_id.clone())
+            .parent(parent_path)
+            .object(&object)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn store_patch(
+        &self,
+        gym: &str,
+        patch: &Patch,
+    ) -> Result<Patch, AppError> {
+        // the document id makes the revision unique, inserts fail if the
+        // document already exists. The creation time is the one of the
+        // document.
+        let patch = &Patch {
+            created_at: None,
+            ..patch.clone()
+        };
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let result: Result<Option<Patch>, _> = self
+            .db
+            .fluent()
+            .insert()
+            .into(PATCHES)
+            .document_id(format!("{}@{}", patch.object_id, patch.revision_id))
+            .parent(&parent_path)
+            .object(patch)
+            .execute()
+            .await;
+
+        match result {
+            Ok(Some(p)) => Ok(p),
+            Ok(None) => {
+                Err(AppError::Internal("storing patch failed".to_string()))
+            }
+            Err(FirestoreError::DataConflictError(_)) => {
+                Err(AppError::RevisionConflict(
+                    patch.object_id.clone(),
+                    patch.revision_id,
+                ))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn commit(
+        &self,
+        gym: &str,
+        batch: &WriteBatch,
+    ) -> Result<Vec<Patch>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let mut tx = self.db.begin_transaction().await?;
+        for patch in &batch.patches {
+            // like store_patch, the document id makes the revision unique
+            self.db
+                .fluent()
+                .update()
+                .in_col(PATCHES)
+                .precondition(FirestoreWritePrecondition::Exists(false))
+                .document_id(format!(
+                    "{}@{}",
+                    patch.object_id, patch.revision_id
+                ))
+                .parent(&parent_path)
+                .object(&Patch {
+                    created_at: None,
+                    ..patch.clone()
+                })
+                .add_to_transaction(&mut tx)?;
+        }
+        for snapshot in &batch.snapshots {
+            self.db
+                .fluent()
+                .update()
+                .in_col(SNAPSHOTS)
+                .document_id(new_id(20))
+                .parent(&parent_path)
+                .object(snapshot)
+                .add_to_transaction(&mut tx)?;
+        }
+        for view in &batch.views {
+            let row = to_document_fields(&view.row);
+            self.db
+                .fluent()
+                .update()
+                .in_col(view.collection)
+                .document_id(&view.object_id)
+                .parent(&parent_path)
+                .object(&row)
+                .add_to_transaction(&mut tx)?;
+        }
+
+        let committed_at = match tx.commit().await {
+            Ok(response) => response.commit_time,
+            Err(FirestoreError::DataConflictError(_)) => {
+                // the commit does not tell which of the patches conflicted
+                let patch = batch.patches.first().ok_or(AppError::Internal(
+                    "conflict without patches".to_string(),
+                ))?;
+                return Err(AppError::RevisionConflict(
+                    patch.object_id.clone(),
+                    patch.revision_id,
+                ));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(batch
+            .patches
+            .iter()
+            .map(|p| Patch {
+                created_at: committed_at,
+                ..p.clone()
+            })
+            .collect())
+    }
+
+    async fn lookup_patch(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Option<Patch>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let patch_stream: BoxStream<FirestoreResult<Patch>> = self
+            .db
+            .fluent()
+            .select()
+            .from(PATCHES)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all([
+                    q.field(path_camel_case!(Patch::object_id))
+                        .eq(object_id.clone()),
+                    q.field(path_camel_case!(Patch::revision_id)).eq(rev_id),
+                ])
+            })
+            .limit(1)
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let mut patches: Vec<Patch> = patch_stream.try_collect().await?;
+        Ok(patches.pop())
+    }
+
+    async fn patches_after(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Vec<Patch>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let object_stream: BoxStream<FirestoreResult<Patch>> = self
+            .db
+            .fluent()
+            .select()
+            .from(PATCHES)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all([
+                    q.field(path_camel_case!(Patch::object_id)).eq(object_id),
+                    q.field(path_camel_case!(Patch::revision_id))
+                        .greater_than(rev_id),
+                ])
+            })
+            .order_by([(
+                path_camel_case!(Patch::revision_id),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        Ok(object_stream.try_collect().await?)
+    }
+
+    async fn patches_in_range(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+        limit: usize,
+    ) -> Result<Vec<Patch>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let object_stream: BoxStream<FirestoreResult<Patch>> = self
+            .db
+            .fluent()
+            .select()
+            .from(PATCHES)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all(
+                    [
+                        Some(
+                            q.field(path_camel_case!(Patch::object_id))
+                                .eq(object_id),
+                        ),
+                        Some(
+                            q.field(path_camel_case!(Patch::revision_id))
+                                .greater_than_or_equal(range.0),
+                        ),
+                        range.1.map(|h| {
+                            q.field(path_camel_case!(Patch::revision_id))
+                                .less_than_or_equal(h)
+                        }),
+                    ]
+                    .into_iter()
+                    .flatten(),
+                )
+            })
+            .limit(u32::try_from(limit).unwrap_or(u32::MAX))
+            .order_by([(
+                path_camel_case!(Patch::revision_id),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        Ok(object_stream.try_collect().await?)
+    }
+
+    async fn patches_after_many(
+        &self,
+        gym: &str,
+        revisions: &[(ObjectId, RevId)],
+    ) -> Result<Vec<Patch>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let mut patches = Vec::new();
+        for chunk in revisions.chunks(MAX_IN_VALUES) {
+            let after: HashMap<&ObjectId, RevId> =
+                chunk.iter().map(|(id, rev_id)| (id, *rev_id)).collect();
+            let Some(&min_rev_id) = after.values().min() else {
+                continue;
+            };
+            let object_ids: Vec<&ObjectId> = after.keys().copied().collect();
+
+            let chunk_patches: Vec<Patch> = self
+                .db
+                .fluent()
+                .select()
+                .from(PATCHES)
+                .parent(&parent_path)
+                .filter(|q| {
+                    q.for_all([
+                        q.field(path_camel_case!(Patch::object_id))
+                            .is_in(object_ids.clone()),
+                        q.field(path_camel_case!(Patch::revision_id))
+                            .greater_than(min_rev_id),
+                    ])
+                })
+                .obj()
+                .stream_query_with_errors()
+                .await?
+                .try_collect()
+                .await?;
+
+            patches.extend(chunk_patches.into_iter().filter(|p| {
+                after
+                    .get(&p.object_id)
+                    .is_some_and(|rev_id| p.revision_id > *rev_id)
+            }));
+        }
+
+        patches.sort_by(|a, b| {
+            (&a.object_id, a.revision_id).cmp(&(&b.object_id, b.revision_id))
+        });
+        Ok(patches)
+    }
+
+    async fn subscribe_patches(
+        &self,
+        gym: &str,
+    ) -> Result<PatchStream, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let listener_id = FirestoreListenerTarget::new(
+            LISTENER_TARGET.fetch_add(1, Ordering::Relaxed),
+        );
+        tracing::debug!("firestore listener id: {listener_id:?}");
+
+        // now start streaming patches using firestore listeners: https://github.com/abdolence/firestore-rs/blob/master/examples/listen-changes.rs
+        let mut listener = self
+            .db
+            .create_listener(FirestoreMemListenStateStorage::new())
+            .await?;
+
+        self.db
+            .fluent()
+            .select()
+            .from(PATCHES)
+            .parent(&parent_path)
+            .listen()
+            .add_target(listener_id, &mut listener)?;
+
+        let (tx, mut rx) = mpsc::channel(1000);
+        let tx_listener = tx.clone();
+        // hack to only send out patches that are added to the collection after
+        // we start the listener
+        let listener_start_time = Utc::now();
+
+        // so this calls tokio::spawn
+        // starting the listener_loop: https://docs.rs/firestore/0.44.1/src/firestore/db/listen_changes.rs.html#360
+        listener
+            .start(move |event| {
+                handle_listener_event(
+                    event,
+                    tx_listener.clone(),
+                    listener_start_time,
+                )
+            })
+            .await?;
+
+        // stop listening once the subscriber drops the stream
+        tokio::spawn(async move {
+            tx.closed().await;
+            let _ = listener.shutdown().await;
+        });
+
+        Ok(stream::poll_fn(move |cx| rx.poll_recv(cx)).boxed())
+    }
+
+    async fn store_snapshot(
+        &self,
+        gym: &str,
+        snapshot: &Snapshot,
+    ) -> Result<Snapshot, AppError> {
+        let s: Option<Snapshot> = store!(self.db, gym, snapshot, SNAPSHOTS);
+        s.ok_or(AppError::Internal("storing snapshot failed".to_string()))
+    }
+
+    async fn latest_snapshot(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+    ) -> Result<Option<Snapshot>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let object_stream: BoxStream<FirestoreResult<Snapshot>> = self
+            .db
+            .fluent()
+            .select()
+            .from(SNAPSHOTS)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all(
+                    [
+                        Some(
+                            q.field(path_camel_case!(Snapshot::object_id))
+                                .eq(object_id),
+                        ),
+                        Some(
+                            q.field(path_camel_case!(Snapshot::revision_id))
+                                .greater_than_or_equal(range.0),
+                        ),
+                        range.1.map(|h| {
+                            q.field(path_camel_case!(Snapshot::revision_id))
+                                .less_than_or_equal(h)
+                        }),
+                    ]
+                    .into_iter()
+                    .flatten(),
+                )
+            })
+            .limit(1)
+            .order_by([(
+                path_camel_case!(Snapshot::revision_id),
+                FirestoreQueryDirection::Descending,
+            )])
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        let mut snapshots: Vec<Snapshot> = object_stream.try_collect().await?;
+        Ok(snapshots.pop())
+    }
+
+    async fn latest_snapshots(
+        &self,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<Vec<Snapshot>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let mut latest: HashMap<ObjectId, Snapshot> = HashMap::new();
+        for chunk in object_ids.chunks(MAX_IN_VALUES) {
+            let snapshots: Vec<Snapshot> = self
+                .db
+                .fluent()
+                .select()
+                .from(SNAPSHOTS)
+                .parent(&parent_path)
+                .filter(|q| {
+                    q.for_all([
+                        q.field(path_camel_case!(Snapshot::object_id))
+                            .is_in(chunk.to_vec()),
+                        q.field(path_camel_case!(Snapshot::revision_id))
+                            .greater_than_or_equal(ZERO_REV_ID),
+                    ])
+                })
+                .obj()
+                .stream_query_with_errors()
+                .await?
+                .try_collect()
+                .await?;
+
+            for snapshot in snapshots {
+                match latest.get(&snapshot.object_id) {
+                    Some(l) if l.revision_id >= snapshot.revision_id => {}
+                    _ => {
+                        latest.insert(snapshot.object_id.clone(), snapshot);
+                    }
+                }
+            }
+        }
+        Ok(latest.into_values().collect())
+    }
+
+    async fn snapshot_revisions(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Vec<RevId>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let revisions: Vec<SnapshotRevision> = self
+            .db
+            .fluent()
+            .select()
+            .fields([path_camel_case!(Snapshot::revision_id)])
+            .from(SNAPSHOTS)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.field(path_camel_case!(Snapshot::object_id)).eq(object_id)
+            })
+            .order_by([(
+                path_camel_case!(Snapshot::revision_id),
+                FirestoreQueryDirection::Ascending,
+            )])
+            .obj()
+            .query()
+            .await?;
+
+        Ok(revisions.into_iter().map(|r| r.revision_id).collect())
+    }
+
+    async fn delete_snapshots(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        revisions: &[RevId],
+    ) -> Result<(), AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        for chunk in revisions.chunks(MAX_IN_VALUES) {
+            let docs = self
+                .db
+                .fluent()
+                .select()
+                .fields([path_camel_case!(Snapshot::revision_id)])
+                .from(SNAPSHOTS)
+                .parent(&parent_path)
+                .filter(|q| {
+                    q.for_all([
+                        q.field(path_camel_case!(Snapshot::object_id))
+                            .eq(object_id),
+                        q.field(path_camel_case!(Snapshot::revision_id))
+                            .is_in(chunk.to_vec()),
+                    ])
+                })
+                .query()
+                .await?;
+
+            for doc in docs {
+                self.db
+                    .fluent()
+                    .delete()
+                    .from(SNAPSHOTS)
+                    .parent(&parent_path)
+                    .document_id(document_id(&doc))
+                    .execute()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn store_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+        row: &Value,
+    ) -> Result<(), AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let row = to_document_fields(row);
+        let _: Option<Value> = self
+            .db
+            .fluent()
+            .update()
+            .in_col(collection)
+            .document_id(object_id.clone())
+            .parent(parent_path)
+            .object(&row)
+            .execute()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn lookup_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<Value>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let row: Option<Value> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(collection)
+            .parent(&parent_path)
+            .obj()
+            .one(object_id)
+            .await?;
+        Ok(row.map(from_document_fields))
+    }
+
+    async fn query_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        query: &ViewQuery,
+    ) -> Result<Vec<Value>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let mut select = self
+            .db
+            .fluent()
+            .select()
+            .from(collection)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all(query.filters.iter().map(|filter| match filter {
+                    Filter::Eq(field, value) => q.field(*field).eq(value),
+                    Filter::Ne(field, value) => q.field(*field).neq(value),
+                }))
+            })
+            .order_by(
+                query
+                    .order_by_desc
+                    .map(|field| (field, FirestoreQueryDirection::Descending)),
+            );
+        if let Some(limit) = query.limit {
+            select = select.limit(u32::try_from(limit).unwrap_or(u32::MAX));
+        }
+
+        let object_stream: BoxStream<FirestoreResult<Value>> =
+            select.obj().stream_query_with_errors().await?;
+        let rows: Vec<Value> = object_stream.try_collect().await?;
+        Ok(rows.into_iter().map(from_document_fields).collect())
+    }
+
+    async fn delete_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<(), AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        self.db
+            .fluent()
+            .delete()
+            .from(collection)
+            .parent(parent_path)
+            .document_id(object_id.clone())
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn store_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+        session: &Session,
+    ) -> Result<Session, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        // the id is document metadata
+        let session = &Session {
+            id: None,
+            created_at: session.created_at.or(Some(Utc::now())),
+            ..session.clone()
+        };
+        let p: Option<SessionDocument> = self
+            .db
+            .fluent()
+            .update()
+            .in_col(SESSIONS)
+            .document_id(session_id)
+            .parent(&parent_path)
+            .object(session)
+            .execute()
+            .await?;
+
+        match p.map(Session::from) {
+            Some(p) => {
+                tracing::debug!("storing session: {p}");
+                Ok(p)
+            }
+            None => {
+                tracing::warn!(
+                    "failed to update session: {session} (no such object exists"
+                );
+                Err(AppError::NoSession())
+            }
+        }
+    }
+
+    async fn lookup_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<Option<Session>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let session: Option<SessionDocument> = self
+            .db
+            .fluent()
+            .select()
+            .by_id_in(SESSIONS)
+            .parent(&parent_path)
+            .obj()
+            .one(session_id)
+            .await?;
+        Ok(session.map(Session::from))
+    }
+
+    async fn delete_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<(), AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        self.db
+            .fluent()
+            .delete()
+            .from(SESSIONS)
+            .parent(&parent_path)
+            .document_id(session_id)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn sessions(&self, gym: &str) -> Result<Vec<Session>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let sessions: Vec<SessionDocument> = self
+            .db
+            .fluent()
+            .select()
+            .from(SESSIONS)
+            .parent(&parent_path)
+            .obj()
+            .query()
+            .await?;
+        Ok(sessions.into_iter().map(Session::from).collect())
+    }
+
+    async fn account_sessions(
+        &self,
+        gym: &str,
+        obj_id: &ObjectId,
+    ) -> Result<Vec<Session>, AppError> {
+        let parent_path = self.db.parent_path("gyms", gym)?;
+        let sessions_stream: BoxStream<FirestoreResult<SessionDocument>> = self
+            .db
+            .fluent()
+            .select()
+            .from(SESSIONS)
+            .parent(&parent_path)
+            .filter(|q| {
+                q.for_all([q
+                    .field(path_camel_case!(Session::obj_id))
+                    .eq(obj_id.clone())])
+            })
+            .obj()
+            .stream_query_with_errors()
+            .await?;
+
+        Ok(sessions_stream.map_ok(Session::from).try_collect().await?)
+    }
+}
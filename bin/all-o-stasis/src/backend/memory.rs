@@ -0,0 +1,808 @@
+use std::collections::{BTreeSet, HashMap};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use otp::{ObjectId, RevId};
+use serde_json::Value;
+use tokio::sync::{Mutex, broadcast};
+
+use crate::{
+    AppError,
+    backend::{PatchStream, Storage, WriteBatch, patch_stream},
+    passport::{Session, new_id},
+    types::{Gym, Patch, Snapshot, ViewQuery, object::ObjectDoc},
+};
+
+#[derive(Default)]
+struct GymData {
+    objects: HashMap<ObjectId, ObjectDoc>,
+    patches: Vec<Patch>,
+    snapshots: Vec<Snapshot>,
+    /// rows of each view collection
+    views: HashMap<String, HashMap<ObjectId, Value>>,
+    sessions: HashMap<String, Session>,
+}
+
+impl GymData {
+    fn has_patch(&self, patch: &Patch) -> bool {
+        self.patches.iter().any(|p| {
+            p.object_id == patch.object_id && p.revision_id == patch.revision_id
+        })
+    }
+
+    fn insert_patch(&mut self, patch: &Patch) -> Result<Patch, AppError> {
+        if self.has_patch(patch) {
+            return Err(AppError::RevisionConflict(
+                patch.object_id.clone(),
+                patch.revision_id,
+            ));
+        }
+
+        let patch = Patch {
+            created_at: patch.created_at.or(Some(Utc::now())),
+            ..patch.clone()
+        };
+        self.patches.push(patch.clone());
+        Ok(patch)
+    }
+
+    fn insert_view(
+        &mut self,
+        collection: &str,
+        object_id: &ObjectId,
+        row: &Value,
+    ) {
+        self.views
+            .entry(collection.to_string())
+            .or_default()
+            .insert(object_id.clone(), row.clone());
+    }
+}
+
+/// Keeps all gyms in process memory, nothing is persisted.
+pub struct MemoryStorage {
+    gyms: Mutex<HashMap<String, GymData>>,
+    registry: Mutex<HashMap<String, Gym>>,
+    patches: broadcast::Sender<(String, Patch)>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        let (patches, _) = broadcast::channel(1000);
+        Self {
+            gyms: Mutex::new(HashMap::new()),
+            registry: Mutex::new(HashMap::new()),
+            patches,
+        }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn healthz(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn gyms(&self) -> Result<Vec<String>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .iter()
+            .filter(|(_, g)| !g.objects.is_empty())
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+
+    async fn store_gym(&self, gym: &Gym) -> Result<(), AppError> {
+        let mut registry = self.registry.lock().await;
+        registry.insert(gym.name.clone(), gym.clone());
+        Ok(())
+    }
+
+    async fn lookup_gym(&self, name: &str) -> Result<Option<Gym>, AppError> {
+        Ok(self.registry.lock().await.get(name).cloned())
+    }
+
+    async fn registered_gyms(&self) -> Result<Vec<Gym>, AppError> {
+        Ok(self.registry.lock().await.values().cloned().collect())
+    }
+
+    async fn object_ids(&self, gym: &str) -> Result<Vec<ObjectId>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .map(|g| g.objects.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn history_object_ids(
+        &self,
+        gym: &str,
+    ) -> Result<BTreeSet<ObjectId>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .map(|g| {
+                let patches = g.patches.iter().map(|p| p.object_id.clone());
+                let snapshots = g.snapshots.iter().map(|s| s.object_id.clone());
+                patches.chain(snapshots).collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn create_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<ObjectDoc, AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let objects = &mut gyms.entry(gym.to_string()).or_default().objects;
+
+        let id = new_id(20);
+        let object = ObjectDoc {
+            id: Some(id.clone()),
+            created_at: Some(Utc::now()),
+            ..object.clone()
+        };
+        objects.insert(id, object.clone());
+        Ok(object)
+    }
+
+    async fn import_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<(), AppError> {
+        let id = object
+            .id
+            .clone()
+            .ok_or(AppError::Query("object without id".to_string()))?;
+        let mut gyms = self.gyms.lock().await;
+        let objects = &mut gyms.entry(gym.to_string()).or_default().objects;
+        objects.insert(id, object.clone());
+        Ok(())
+    }
+
+    async fn lookup_object(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<ObjectDoc>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .and_then(|g| g.objects.get(object_id))
+            .cloned())
+    }
+
+    async fn set_object_deleted(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        deleted: bool,
+    ) -> Result<(), AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let object = gyms
+            .get_mut(gym)
+            .and_then(|g| g.objects.get_mut(object_id))
+            .ok_or(AppError::Query(format!("no object {object_id}")))?;
+        object.deleted = Some(deleted);
+        Ok(())
+    }
+
+    async fn store_patch(
+        &self,
+        gym: &str,
+        patch: &Patch,
+    ) -> Result<Patch, AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let patch = gyms
+            .entry(gym.to_string())
+            .or_default()
+            .insert_patch(patch)?;
+        // nobody listening is not an error
+        let _ = self.patches.send((gym.to_string(), patch.clone()));
+        Ok(patch)
+    }
+
+    async fn commit(
+        &self,
+        gym: &str,
+        batch: &WriteBatch,
+    ) -> Result<Vec<Patch>, AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let g = gyms.entry(gym.to_string()).or_default();
+        // check all patches before writing anything
+        if let Some(patch) = batch.patches.iter().find(|p| g.has_patch(p)) {
+            return Err(AppError::RevisionConflict(
+                patch.object_id.clone(),
+                patch.revision_id,
+            ));
+        }
+
+        let patches = batch
+            .patches
+            .iter()
+            .map(|patch| g.insert_patch(patch))
+            .collect::<Result<Vec<_>, _>>()?;
+        g.snapshots.extend(batch.snapshots.iter().cloned());
+        for view in &batch.views {
+            g.insert_view(view.collection, &view.object_id, &view.row);
+        }
+
+        for patch in &patches {
+            let _ = self.patches.send((gym.to_string(), patch.clone()));
+        }
+        Ok(patches)
+    }
+
+    async fn lookup_patch(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Option<Patch>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms.get(gym).and_then(|g| {
+            g.patches
+                .iter()
+                .find(|p| p.object_id == *object_id && p.revision_id == rev_id)
+                .cloned()
+        }))
+    }
+
+    async fn patches_after(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Vec<Patch>, AppError> {
+        let gyms = self.gyms.lock().await;
+        let mut patches: Vec<Patch> = gyms
+            .get(gym)
+            .map(|g| {
+                g.patches
+                    .iter()
+                    .filter(|p| {
+                        p.object_id == *object_id && p.revision_id > rev_id
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        patches.sort_by_key(|p| p.revision_id);
+        Ok(patches)
+    }
+
+    async fn patches_in_range(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+        limit: usize,
+    ) -> Result<Vec<Patch>, AppError> {
+        let gyms = self.gyms.lock().await;
+        let mut patches: Vec<Patch> = gyms
+            .get(gym)
+            .map(|g| {
+                g.patches
+                    .iter()
+                    .filter(|p| {
+                        p.object_id == *object_id
+                            && p.revision_id >= range.0
+                            && range.1.is_none_or(|h| p.revision_id <= h)
+                    })
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        patches.sort_by_key(|p| p.revision_id);
+        patches.truncate(limit);
+        Ok(patches)
+    }
+
+    async fn subscribe_patches(
+        &self,
+        gym: &str,
+    ) -> Result<PatchStream, AppError> {
+        Ok(patch_stream(self.patches.subscribe(), gym))
+    }
+
+    async fn store_snapshot(
+        &self,
+        gym: &str,
+        snapshot: &Snapshot,
+    ) -> Result<Snapshot, AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let snapshots = &mut gyms.entry(gym.to_string()).or_default().snapshots;
+        snapshots.push(snapshot.clone());
+        Ok(snapshot.clone())
+    }
+
+    async fn latest_snapshot(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+    ) -> Result<Option<Snapshot>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms.get(gym).and_then(|g| {
+            g.snapshots
+                .iter()
+                .filter(|s| {
+                    s.object_id == *object_id
+                        && s.revision_id >= range.0
+                        && range.1.is_none_or(|h| s.revision_id <= h)
+                })
+                .max_by_key(|s| s.revision_id)
+                .cloned()
+        }))
+    }
+
+    async fn snapshot_revisions(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Vec<RevId>, AppError> {
+        let gyms = self.gyms.lock().await;
+        let mut revisions: Vec<RevId> = gyms
+            .get(gym)
+            .map(|g| {
+                g.snapshots
+                    .iter()
+                    .filter(|s| s.object_id == *object_id)
+                    .map(|s| s.revision_id)
+                    .collect()
+            })
+            .unwrap_or_default();
+        revisions.sort();
+        Ok(revisions)
+    }
+
+    async fn delete_snapshots(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        revisions: &[RevId],
+    ) -> Result<(), AppError> {
+        let mut gyms = self.gyms.lock().await;
+        if let Some(g) = gyms.get_mut(gym) {
+            g.snapshots.retain(|s| {
+                s.object_id != *object_id || !revisions.contains(&s.revision_id)
+            });
+        }
+        Ok(())
+    }
+
+    async fn store_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+        row: &Value,
+    ) -> Result<(), AppError> {
+        let mut gyms = self.gyms.lock().await;
+        gyms.entry(gym.to_string())
+            .or_default()
+            .insert_view(collection, object_id, row);
+        Ok(())
+    }
+
+    async fn lookup_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<Value>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .and_then(|g| g.views.get(collection))
+            .and_then(|rows| rows.get(object_id))
+            .cloned())
+    }
+
+    async fn query_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        query: &ViewQuery,
+    ) -> Result<Vec<Value>, AppError> {
+        let gyms = self.gyms.lock().await;
+        let rows = gyms.get(gym).and_then(|g| g.views.get(collection));
+        Ok(query
+            .apply(rows.into_iter().flat_map(|rows| rows.values().cloned())))
+    }
+
+    async fn delete_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<(), AppError> {
+        let mut gyms = self.gyms.lock().await;
+        if let Some(rows) =
+            gyms.get_mut(gym).and_then(|g| g.views.get_mut(collection))
+        {
+            rows.remove(object_id);
+        }
+        Ok(())
+    }
+
+    async fn store_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+        session: &Session,
+    ) -> Result<Session, AppError> {
+        let mut gyms = self.gyms.lock().await;
+        let sessions = &mut gyms.entry(gym.to_string()).or_default().sessions;
+        let session = Session {
+            id: Some(session_id.to_string()),
+            created_at: session.created_at.or(Some(Utc::now())),
+            ..session.clone()
+        };
+        sessions.insert(session_id.to_string(), session.clone());
+        Ok(session)
+    }
+
+    async fn lookup_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<Option<Session>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .and_then(|g| g.sessions.get(session_id))
+            .cloned())
+    }
+
+    async fn delete_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<(), AppError> {
+        let mut gyms = self.gyms.lock().await;
+        if let Some(g) = gyms.get_mut(gym) {
+            g.sessions.remove(session_id);
+        }
+        Ok(())
+    }
+
+    async fn sessions(&self, gym: &str) -> Result<Vec<Session>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .map(|g| g.sessions.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn account_sessions(
+        &self,
+        gym: &str,
+        obj_id: &ObjectId,
+    ) -> Result<Vec<Session>, AppError> {
+        let gyms = self.gyms.lock().await;
+        Ok(gyms
+            .get(gym)
+            .map(|g| {
+                g.sessions
+                    .values()
+                    .filter(|s| s.obj_id == *obj_id)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use otp::Operation;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        AppState,
+        passport::Session,
+        storage::{ObjectUpdates, apply_batch_updates, apply_object_updates},
+        test_boulder,
+        tokens::keyed_hash,
+        types::{Boulder, BouldersView, Object, ObjectType, Snapshot, view},
+    };
+
+    #[tokio::test]
+    async fn create_and_patch_object() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let obj = Object::from_value(
+            &state,
+            gym,
+            String::from("author"),
+            ObjectType::Boulder,
+            &test_boulder(),
+        )
+        .await
+        .unwrap();
+
+        let op = Operation::new_set("grade", json!("red"));
+        let response = apply_object_updates(
+            &state,
+            gym,
+            obj.id.clone(),
+            otp::ZERO_REV_ID,
+            String::from("author"),
+            vec![op],
+        )
+        .await
+        .unwrap();
+        let response = serde_json::to_value(&response.0).unwrap();
+        assert_eq!(Some(&json!(1)), response.get("numProcessedOperations"));
+
+        let snapshot =
+            Snapshot::lookup_latest(&state, gym, &obj.id).await.unwrap();
+        assert_eq!(1, snapshot.revision_id);
+        assert_eq!(Some(&json!("red")), snapshot.content.get("grade"));
+        // a single revision is not worth a snapshot
+        let stored = state.db.snapshot_revisions(gym, &obj.id).await.unwrap();
+        assert!(stored.is_empty());
+
+        let view = view::lookup::<Boulder>(&state, gym, &BouldersView, &obj.id)
+            .await
+            .unwrap();
+        assert_eq!(Some(String::from("red")), view.map(|b| b.grade));
+    }
+
+    #[tokio::test]
+    async fn delete_and_restore_object() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let obj = Object::from_value(
+            &state,
+            gym,
+            String::from("author"),
+            ObjectType::Boulder,
+            &test_boulder(),
+        )
+        .await
+        .unwrap();
+
+        obj.delete(&state, gym).await.unwrap();
+        assert!(matches!(
+            Object::lookup(&state, gym, &obj.id).await,
+            Err(AppError::Gone(_))
+        ));
+        assert!(
+            view::lookup::<Boulder>(&state, gym, &BouldersView, &obj.id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        obj.restore(&state, gym).await.unwrap();
+        assert!(!Object::lookup(&state, gym, &obj.id).await.unwrap().deleted);
+        let view = view::lookup::<Boulder>(&state, gym, &BouldersView, &obj.id)
+            .await
+            .unwrap();
+        assert_eq!(Some(String::from("blue")), view.map(|b| b.grade));
+    }
+
+    #[tokio::test]
+    async fn lookup_latest_of_many_objects() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let mut ids = Vec::new();
+        for grade in ["yellow", "red"] {
+            let obj = Object::from_value(
+                &state,
+                gym,
+                String::from("author"),
+                ObjectType::Boulder,
+                &test_boulder(),
+            )
+            .await
+            .unwrap();
+            let op = Operation::new_set("grade", json!(grade));
+            let _ = apply_object_updates(
+                &state,
+                gym,
+                obj.id.clone(),
+                otp::ZERO_REV_ID,
+                String::from("author"),
+                vec![op],
+            )
+            .await
+            .unwrap();
+            ids.push(obj.id);
+        }
+        // cached and not cached objects
+        let first = ids.first().unwrap();
+        Snapshot::lookup_latest(&state, gym, first).await.unwrap();
+        ids.push(String::from("missing"));
+
+        let snapshots = Snapshot::lookup_latest_many(&state, gym, &ids)
+            .await
+            .unwrap();
+        assert_eq!(3, snapshots.len());
+        for (id, grade) in ids.iter().zip(["yellow", "red"]) {
+            let snapshot = snapshots.get(id).unwrap();
+            assert_eq!(1, snapshot.revision_id);
+            assert_eq!(Some(&json!(grade)), snapshot.content.get("grade"));
+        }
+        assert_eq!(-1, snapshots.get("missing").unwrap().revision_id);
+
+        let objects = Object::lookup_many(&state, gym, &ids).await.unwrap();
+        assert_eq!(2, objects.len());
+    }
+
+    #[tokio::test]
+    async fn batch_updates_of_several_objects() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let mut updates = Vec::new();
+        for _ in 0..2 {
+            let obj = Object::from_value(
+                &state,
+                gym,
+                String::from("author"),
+                ObjectType::Boulder,
+                &test_boulder(),
+            )
+            .await
+            .unwrap();
+            updates.push(ObjectUpdates {
+                object_id: obj.id,
+                revision_id: otp::ZERO_REV_ID,
+                operations: vec![Operation::new_set("removed", json!(1))],
+            });
+        }
+
+        let responses =
+            apply_batch_updates(&state, gym, String::from("author"), &updates)
+                .await
+                .unwrap();
+        assert_eq!(2, responses.len());
+        for update in &updates {
+            let snapshot =
+                Snapshot::lookup_latest(&state, gym, &update.object_id)
+                    .await
+                    .unwrap();
+            assert_eq!(1, snapshot.revision_id);
+            let view = view::lookup::<Boulder>(
+                &state,
+                gym,
+                &BouldersView,
+                &update.object_id,
+            )
+            .await
+            .unwrap();
+            assert_eq!(Some(1), view.map(|b| b.removed));
+        }
+    }
+
+    #[tokio::test]
+    async fn patches_in_range_are_limited() {
+        let db = MemoryStorage::new();
+        let object_id = String::from("obj");
+        for rev_id in [3, 0, 2, 1] {
+            let patch = Patch::new_revision(
+                rev_id,
+                object_id.clone(),
+                String::new(),
+                Operation::new_set("x", json!(rev_id)),
+            );
+            db.store_patch("test", &patch).await.unwrap();
+        }
+
+        let revisions = |range, limit| {
+            let db = &db;
+            let object_id = &object_id;
+            async move {
+                db.patches_in_range("test", object_id, range, limit)
+                    .await
+                    .unwrap()
+                    .iter()
+                    .map(|p| p.revision_id)
+                    .collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(vec![0, 1], revisions((RevId::MIN, None), 2).await);
+        assert_eq!(vec![1, 2], revisions((1, Some(2)), 10).await);
+        assert_eq!(vec![3], revisions((3, None), 10).await);
+        assert!(revisions((4, None), 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_patches_of_gym() {
+        let db = MemoryStorage::new();
+        let mut patches = db.subscribe_patches("test").await.unwrap();
+
+        let patch = Patch::new(String::from("obj"), String::new(), &json!({}));
+        db.store_patch("other", &patch).await.unwrap();
+        db.store_patch("test", &patch).await.unwrap();
+
+        let received = patches.next().await.unwrap();
+        assert_eq!(patch.object_id, received.object_id);
+        assert!(received.created_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_when_idle_or_too_old() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let key = &state.config.token_key;
+        let now = Utc::now();
+        let hour = chrono::Duration::hours(1);
+        let idle = chrono::Duration::seconds(
+            i64::try_from(state.config.session_idle_secs).unwrap(),
+        );
+        let zero = chrono::Duration::zero();
+        let sessions = [
+            ("fresh", "a", hour, zero),
+            ("idle", "a", idle * 2, idle * 2),
+            ("old", "a", chrono::Duration::weeks(53), zero),
+            ("other", "b", hour, zero),
+        ];
+        for (id, account, age, unused) in sessions {
+            let session = Session {
+                created_at: Some(now - age),
+                last_accessed_at: now - unused,
+                hashed: true,
+                ..Session::new(account.to_string(), None, None)
+            };
+            let hash = keyed_hash(key, id);
+            state.db.store_session(gym, &hash, &session).await.unwrap();
+        }
+
+        let active = Session::of_account(&state, gym, &String::from("a"))
+            .await
+            .unwrap();
+        let handles: Vec<_> = active.iter().map(Session::handle).collect();
+        assert_eq!(vec![Some(keyed_hash(key, "fresh"))], handles);
+
+        // expired sessions are deleted once used
+        assert!(matches!(
+            Session::lookup_active(&state, gym, "idle").await,
+            Err(AppError::NoSession())
+        ));
+        let idle = keyed_hash(key, "idle");
+        assert!(state.db.lookup_session(gym, &idle).await.unwrap().is_none());
+        let fresh = Session::lookup_active(&state, gym, "fresh").await.unwrap();
+        assert!(now - fresh.last_accessed_at < chrono::Duration::seconds(1));
+    }
+
+    #[tokio::test]
+    async fn migrate_sessions_to_hashed_ids() {
+        let state = AppState::in_memory();
+        let gym = Gym::new("test", "Test").unwrap();
+        state.db.store_gym(&gym).await.unwrap();
+        let created_at = Utc::now() - chrono::Duration::days(100);
+        let session = Session {
+            created_at: Some(created_at),
+            ..Session::new(String::from("a"), None, None)
+        };
+        state
+            .db
+            .store_session(&gym.name, "legacy", &session)
+            .await
+            .unwrap();
+
+        assert_eq!(1, Session::migrate(&state).await.unwrap());
+        assert_eq!(0, Session::migrate(&state).await.unwrap());
+        let legacy = state.db.lookup_session(&gym.name, "legacy").await;
+        assert!(legacy.unwrap().is_none());
+        let migrated = Session::lookup_active(&state, &gym.name, "legacy")
+            .await
+            .unwrap();
+        assert_eq!("a", migrated.obj_id);
+        // the migration does not extend the lifetime of sessions
+        assert_eq!(Some(created_at), migrated.created_at);
+    }
+}
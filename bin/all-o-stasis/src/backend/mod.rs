@@ -0,0 +1,313 @@
+//! Storage backends for objects, patches, snapshots, views and sessions.
+//!
+//! All persistent state of a gym goes through the [`Storage`] trait. The
+//! production deployment uses [`FirestoreStorage`], [`SqliteStorage`] keeps
+//! all gyms in a single file for self-hosted installations and
+//! [`MemoryStorage`] keeps everything in process for tests and local
+//! development.
+
+use std::collections::BTreeSet;
+
+use async_trait::async_trait;
+use futures::{
+    StreamExt,
+    stream::{self, BoxStream},
+};
+use otp::{ObjectId, RevId, ZERO_REV_ID};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::{
+    AppError,
+    passport::Session,
+    types::{Gym, Patch, Snapshot, ViewQuery, object::ObjectDoc},
+};
+
+mod firestore;
+mod memory;
+mod sqlite;
+
+pub use self::{
+    firestore::FirestoreStorage, memory::MemoryStorage, sqlite::SqliteStorage,
+};
+
+/// Stream of patches stored after the subscription was created
+pub type PatchStream = BoxStream<'static, Patch>;
+
+/// Patches of gym from a channel shared by all gyms of a local backend
+fn patch_stream(
+    rx: broadcast::Receiver<(String, Patch)>,
+    gym: &str,
+) -> PatchStream {
+    let gym = gym.to_string();
+    stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(patch) => return Some((patch, rx)),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("patch subscriber lagged by {n}");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter_map(move |(g, patch)| {
+        let patch = (g == gym).then_some(patch);
+        async move { patch }
+    })
+    .boxed()
+}
+
+/// The row of an object in a view collection
+#[derive(Clone)]
+pub struct ViewWrite {
+    pub collection: &'static str,
+    pub object_id: ObjectId,
+    pub row: Value,
+}
+
+/// Writes which [`Storage::commit`] stores together or not at all
+#[derive(Clone, Default)]
+pub struct WriteBatch {
+    pub patches: Vec<Patch>,
+    pub snapshots: Vec<Snapshot>,
+    pub views: Vec<ViewWrite>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// check that the backend is reachable
+    async fn healthz(&self) -> Result<(), AppError>;
+
+    /// all gyms with at least one object
+    async fn gyms(&self) -> Result<Vec<String>, AppError>;
+
+    /// create or replace a gym in the registry
+    async fn store_gym(&self, gym: &Gym) -> Result<(), AppError>;
+
+    async fn lookup_gym(&self, name: &str) -> Result<Option<Gym>, AppError>;
+
+    /// all gyms in the registry
+    async fn registered_gyms(&self) -> Result<Vec<Gym>, AppError>;
+
+    async fn object_ids(&self, gym: &str) -> Result<Vec<ObjectId>, AppError>;
+
+    /// ids of all objects with at least one patch or snapshot, including
+    /// ones which are missing from the objects
+    async fn history_object_ids(
+        &self,
+        gym: &str,
+    ) -> Result<BTreeSet<ObjectId>, AppError>;
+
+    /// store a new object, the backend assigns id and creation time
+    async fn create_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<ObjectDoc, AppError>;
+
+    async fn lookup_object(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<ObjectDoc>, AppError>;
+
+    /// all existing objects with one of the ids
+    async fn lookup_objects(
+        &self,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<Vec<ObjectDoc>, AppError> {
+        let mut objects = Vec::new();
+        for object_id in object_ids {
+            objects.extend(self.lookup_object(gym, object_id).await?);
+        }
+        Ok(objects)
+    }
+
+    /// store an object with its id, for imports. The creation time is kept
+    /// if the backend can set it.
+    async fn import_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<(), AppError>;
+
+    /// mark an object as deleted or restore it
+    async fn set_object_deleted(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        deleted: bool,
+    ) -> Result<(), AppError>;
+
+    /// store a new patch, the backend assigns the creation time unless it
+    /// has one and the backend can keep it. Fails with
+    /// [`AppError::RevisionConflict`] if the object already has a patch with
+    /// the same revision id.
+    async fn store_patch(
+        &self,
+        gym: &str,
+        patch: &Patch,
+    ) -> Result<Patch, AppError>;
+
+    /// store all writes of the batch atomically and return the stored
+    /// patches, creation times are assigned like [`Storage::store_patch`]
+    /// does. Fails with [`AppError::RevisionConflict`] without storing
+    /// anything if one of the patches conflicts with a stored one.
+    async fn commit(
+        &self,
+        gym: &str,
+        batch: &WriteBatch,
+    ) -> Result<Vec<Patch>, AppError>;
+
+    async fn lookup_patch(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Option<Patch>, AppError>;
+
+    /// all patches of an object with revision id > rev_id ordered by revision
+    async fn patches_after(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Vec<Patch>, AppError>;
+
+    /// At most `limit` patches of an object ordered by revision, with
+    /// revision ids from `range.0` up to `range.1` (inclusive, if any).
+    async fn patches_in_range(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+        limit: usize,
+    ) -> Result<Vec<Patch>, AppError>;
+
+    /// patches of several objects with revision id > the revision given for
+    /// their object, grouped by object and ordered by revision
+    async fn patches_after_many(
+        &self,
+        gym: &str,
+        revisions: &[(ObjectId, RevId)],
+    ) -> Result<Vec<Patch>, AppError> {
+        let mut patches = Vec::new();
+        for (object_id, rev_id) in revisions {
+            patches.extend(self.patches_after(gym, object_id, *rev_id).await?);
+        }
+        Ok(patches)
+    }
+
+    /// subscribe to all patches of a gym stored from now on
+    async fn subscribe_patches(
+        &self,
+        gym: &str,
+    ) -> Result<PatchStream, AppError>;
+
+    async fn store_snapshot(
+        &self,
+        gym: &str,
+        snapshot: &Snapshot,
+    ) -> Result<Snapshot, AppError>;
+
+    /// the snapshot with the highest revision between low and high (inclusive)
+    async fn latest_snapshot(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+    ) -> Result<Option<Snapshot>, AppError>;
+
+    /// the latest stored snapshot of each of the objects which has one
+    async fn latest_snapshots(
+        &self,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<Vec<Snapshot>, AppError> {
+        let mut snapshots = Vec::new();
+        for object_id in object_ids {
+            let range = (ZERO_REV_ID, None);
+            snapshots
+                .extend(self.latest_snapshot(gym, object_id, range).await?);
+        }
+        Ok(snapshots)
+    }
+
+    /// revision ids of all stored snapshots of an object in ascending order
+    async fn snapshot_revisions(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Vec<RevId>, AppError>;
+
+    /// delete all snapshots of an object with one of the revision ids
+    async fn delete_snapshots(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        revisions: &[RevId],
+    ) -> Result<(), AppError>;
+
+    /// create or replace the row of an object in a view collection
+    async fn store_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+        row: &Value,
+    ) -> Result<(), AppError>;
+
+    async fn lookup_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<Value>, AppError>;
+
+    async fn query_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        query: &ViewQuery,
+    ) -> Result<Vec<Value>, AppError>;
+
+    /// remove the row of an object from a view collection
+    async fn delete_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<(), AppError>;
+
+    /// create or update the session with session_id
+    async fn store_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+        session: &Session,
+    ) -> Result<Session, AppError>;
+
+    async fn lookup_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<Option<Session>, AppError>;
+
+    async fn delete_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<(), AppError>;
+
+    /// all sessions of a gym
+    async fn sessions(&self, gym: &str) -> Result<Vec<Session>, AppError>;
+
+    /// all sessions belonging to the account with obj_id
+    async fn account_sessions(
+        &self,
+        gym: &str,
+        obj_id: &ObjectId,
+    ) -> Result<Vec<Session>, AppError>;
+}
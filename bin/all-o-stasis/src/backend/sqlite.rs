@@ -0,0 +1,1076 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use otp::{ObjectId, RevId};
+use rusqlite::{
+    Connection, ErrorCode, OptionalExtension, Params, params, params_from_iter,
+    types::Value as SqlValue,
+};
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+use crate::{
+    AppError,
+    backend::{PatchStream, Storage, WriteBatch, patch_stream},
+    passport::{Session, new_id},
+    types::{
+        Filter, Gym, Patch, Snapshot, ViewQuery, object::ObjectDoc, view::views,
+    },
+};
+
+/// Schema migrations, applied in order. The number of applied migrations is
+/// tracked in `PRAGMA user_version`. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE objects (
+    gym TEXT NOT NULL,
+    id TEXT NOT NULL,
+    doc TEXT NOT NULL,
+    PRIMARY KEY (gym, id)
+);
+
+CREATE TABLE patches (
+    gym TEXT NOT NULL,
+    object_id TEXT NOT NULL,
+    revision_id INTEGER NOT NULL,
+    doc TEXT NOT NULL
+);
+CREATE INDEX patches_object_revision
+    ON patches (gym, object_id, revision_id);
+
+CREATE TABLE snapshots (
+    gym TEXT NOT NULL,
+    object_id TEXT NOT NULL,
+    revision_id INTEGER NOT NULL,
+    doc TEXT NOT NULL
+);
+CREATE INDEX snapshots_object_revision
+    ON snapshots (gym, object_id, revision_id);
+
+CREATE TABLE accounts_view (
+    gym TEXT NOT NULL,
+    id TEXT NOT NULL,
+    role TEXT NOT NULL,
+    email TEXT NOT NULL,
+    doc TEXT NOT NULL,
+    PRIMARY KEY (gym, id)
+);
+CREATE INDEX accounts_view_email ON accounts_view (gym, email);
+
+CREATE TABLE boulders_view (
+    gym TEXT NOT NULL,
+    id TEXT NOT NULL,
+    removed INTEGER NOT NULL,
+    is_draft INTEGER NOT NULL,
+    set_date INTEGER NOT NULL,
+    doc TEXT NOT NULL,
+    PRIMARY KEY (gym, id)
+);
+CREATE INDEX boulders_view_state
+    ON boulders_view (gym, removed, is_draft, set_date);
+
+CREATE TABLE sessions (
+    gym TEXT NOT NULL,
+    id TEXT NOT NULL,
+    obj_id TEXT NOT NULL,
+    doc TEXT NOT NULL,
+    PRIMARY KEY (gym, id)
+);
+CREATE INDEX sessions_obj_id ON sessions (gym, obj_id);
+"#,
+    r#"
+DELETE FROM patches WHERE rowid NOT IN (
+    SELECT MIN(rowid) FROM patches GROUP BY gym, object_id, revision_id
+);
+DROP INDEX patches_object_revision;
+CREATE UNIQUE INDEX patches_object_revision
+    ON patches (gym, object_id, revision_id);
+"#,
+    r#"
+CREATE TABLE views (
+    gym TEXT NOT NULL,
+    collection TEXT NOT NULL,
+    id TEXT NOT NULL,
+    doc TEXT NOT NULL,
+    PRIMARY KEY (gym, collection, id)
+);
+INSERT INTO views (gym, collection, id, doc)
+    SELECT gym, 'accounts_view', id, doc FROM accounts_view;
+INSERT INTO views (gym, collection, id, doc)
+    SELECT gym, 'boulders_view', id, doc FROM boulders_view;
+DROP TABLE accounts_view;
+DROP TABLE boulders_view;
+"#,
+    r#"
+CREATE TABLE gyms (
+    name TEXT NOT NULL PRIMARY KEY,
+    doc TEXT NOT NULL
+);
+"#,
+];
+
+fn migrate(conn: &mut Connection) -> Result<(), AppError> {
+    let version: i64 =
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate() {
+        let target = i as i64 + 1;
+        if target <= version {
+            continue;
+        }
+
+        tracing::info!("applying sqlite migration {target}");
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn to_doc<T: Serialize>(value: &T) -> Result<String, AppError> {
+    serde_json::to_string(value)
+        .map_err(|e| AppError::Internal(format!("serialisation failed: {e}")))
+}
+
+fn from_doc<T: DeserializeOwned>(doc: &str) -> Result<T, AppError> {
+    serde_json::from_str(doc)
+        .map_err(|e| AppError::ParseError(format!("{e} in: {doc}")))
+}
+
+/// insert a patch, fails with [`AppError::RevisionConflict`] if the revision
+/// already exists
+fn insert_patch(
+    conn: &Connection,
+    gym: &str,
+    patch: &Patch,
+) -> Result<(), AppError> {
+    let doc = to_doc(patch)?;
+    let result = conn
+        .prepare_cached(
+            "INSERT INTO patches (gym, object_id, revision_id, doc) \
+             VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute(params![gym, patch.object_id, patch.revision_id, doc]);
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::SqliteFailure(e, _))
+            if e.code == ErrorCode::ConstraintViolation =>
+        {
+            Err(AppError::RevisionConflict(
+                patch.object_id.clone(),
+                patch.revision_id,
+            ))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn insert_snapshot(
+    conn: &Connection,
+    gym: &str,
+    snapshot: &Snapshot,
+) -> Result<(), AppError> {
+    let doc = to_doc(snapshot)?;
+    conn.prepare_cached(
+        "INSERT INTO snapshots (gym, object_id, revision_id, doc) \
+         VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![
+        gym,
+        snapshot.object_id,
+        snapshot.revision_id,
+        doc
+    ])?;
+    Ok(())
+}
+
+fn insert_view(
+    conn: &Connection,
+    gym: &str,
+    collection: &str,
+    object_id: &ObjectId,
+    row: &Value,
+) -> Result<(), AppError> {
+    let doc = to_doc(row)?;
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO views (gym, collection, id, doc) \
+         VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![gym, collection, object_id, doc])?;
+    Ok(())
+}
+
+/// expression extracting a field of view rows, indexes use the same
+fn json_field(field: &str) -> Result<String, AppError> {
+    // field names are interpolated into SQL
+    if field.is_empty() || !field.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppError::Query(format!("invalid view field {field}")));
+    }
+    Ok(format!("json_extract(doc, '$.{field}')"))
+}
+
+/// the value json_extract returns for a JSON value
+fn sql_value(value: &Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(i64::from(*b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+/// index the fields queries of the registered views use
+fn create_view_indexes(conn: &Connection) -> Result<(), AppError> {
+    for view in views() {
+        for field in view.indexed_fields() {
+            conn.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS views_{}_{field} \
+                 ON views (gym, collection, {})",
+                view.collection(),
+                json_field(field)?,
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+/// run a query selecting a single `doc` column and parse all rows
+fn query_docs<T: DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Vec<T>, AppError> {
+    let mut stmt = conn.prepare_cached(sql)?;
+    let docs = stmt
+        .query_map(params, |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    docs.iter().map(|doc| from_doc(doc)).collect()
+}
+
+fn query_doc<T: DeserializeOwned>(
+    conn: &Connection,
+    sql: &str,
+    params: impl Params,
+) -> Result<Option<T>, AppError> {
+    let doc: Option<String> = conn
+        .prepare_cached(sql)?
+        .query_row(params, |row| row.get(0))
+        .optional()?;
+    doc.map(|doc| from_doc(&doc)).transpose()
+}
+
+/// Stores all gyms in a single SQLite database. Patch notifications are only
+/// delivered to subscribers within this process.
+pub struct SqliteStorage {
+    conn: Arc<Mutex<Connection>>,
+    patches: broadcast::Sender<(String, Patch)>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<Self, AppError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, AppError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut conn: Connection) -> Result<Self, AppError> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut conn)?;
+        create_view_indexes(&conn)?;
+
+        let (patches, _) = broadcast::channel(1000);
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            patches,
+        })
+    }
+
+    /// run f with the connection on the blocking thread pool
+    async fn call<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, AppError> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| {
+                AppError::Internal("sqlite connection poisoned".to_string())
+            })?;
+            f(&mut conn)
+        })
+        .await
+        .map_err(|e| AppError::Internal(format!("sqlite task failed: {e}")))?
+    }
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn healthz(&self) -> Result<(), AppError> {
+        self.call(|conn| {
+            conn.query_row("SELECT 1", [], |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn gyms(&self) -> Result<Vec<String>, AppError> {
+        self.call(|conn| {
+            let mut stmt =
+                conn.prepare_cached("SELECT DISTINCT gym FROM objects")?;
+            let gyms = stmt
+                .query_map([], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(gyms)
+        })
+        .await
+    }
+
+    async fn store_gym(&self, gym: &Gym) -> Result<(), AppError> {
+        let (name, doc) = (gym.name.clone(), to_doc(gym)?);
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO gyms (name, doc) VALUES (?1, ?2)",
+            )?
+            .execute(params![name, doc])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn lookup_gym(&self, name: &str) -> Result<Option<Gym>, AppError> {
+        let name = name.to_string();
+        self.call(move |conn| {
+            query_doc(conn, "SELECT doc FROM gyms WHERE name = ?1", [name])
+        })
+        .await
+    }
+
+    async fn registered_gyms(&self) -> Result<Vec<Gym>, AppError> {
+        self.call(|conn| {
+            query_docs(conn, "SELECT doc FROM gyms ORDER BY name", [])
+        })
+        .await
+    }
+
+    async fn object_ids(&self, gym: &str) -> Result<Vec<ObjectId>, AppError> {
+        let gym = gym.to_string();
+        self.call(move |conn| {
+            let mut stmt =
+                conn.prepare_cached("SELECT id FROM objects WHERE gym = ?1")?;
+            let ids = stmt
+                .query_map(params![gym], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn history_object_ids(
+        &self,
+        gym: &str,
+    ) -> Result<BTreeSet<ObjectId>, AppError> {
+        let gym = gym.to_string();
+        self.call(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT object_id FROM patches WHERE gym = ?1 \
+                 UNION SELECT object_id FROM snapshots WHERE gym = ?1",
+            )?;
+            let ids = stmt
+                .query_map(params![gym], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn create_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<ObjectDoc, AppError> {
+        let gym = gym.to_string();
+        let id = new_id(20);
+        let object = ObjectDoc {
+            id: Some(id.clone()),
+            created_at: Some(Utc::now()),
+            ..object.clone()
+        };
+        let doc = to_doc(&object)?;
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "INSERT INTO objects (gym, id, doc) VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![gym, id, doc])?;
+            Ok(())
+        })
+        .await?;
+        Ok(object)
+    }
+
+    async fn import_object(
+        &self,
+        gym: &str,
+        object: &ObjectDoc,
+    ) -> Result<(), AppError> {
+        let id = object
+            .id
+            .clone()
+            .ok_or(AppError::Query("object without id".to_string()))?;
+        let (gym, doc) = (gym.to_string(), to_doc(object)?);
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO objects (gym, id, doc) \
+                 VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![gym, id, doc])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn lookup_object(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<ObjectDoc>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        self.call(move |conn| {
+            query_doc(
+                conn,
+                "SELECT doc FROM objects WHERE gym = ?1 AND id = ?2",
+                params![gym, object_id],
+            )
+        })
+        .await
+    }
+
+    async fn set_object_deleted(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        deleted: bool,
+    ) -> Result<(), AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        self.call(move |conn| {
+            let updated = conn
+                .prepare_cached(
+                    "UPDATE objects SET doc = json_set(doc, '$.deleted', json(?3)) \
+                     WHERE gym = ?1 AND id = ?2",
+                )?
+                .execute(params![gym, object_id, deleted.to_string()])?;
+            if updated == 0 {
+                return Err(AppError::Query(format!("no object {object_id}")));
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store_patch(
+        &self,
+        gym: &str,
+        patch: &Patch,
+    ) -> Result<Patch, AppError> {
+        let patch = Patch {
+            created_at: patch.created_at.or(Some(Utc::now())),
+            ..patch.clone()
+        };
+        let (g, p) = (gym.to_string(), patch.clone());
+        self.call(move |conn| insert_patch(conn, &g, &p)).await?;
+
+        // nobody listening is not an error
+        let _ = self.patches.send((gym.to_string(), patch.clone()));
+        Ok(patch)
+    }
+
+    async fn commit(
+        &self,
+        gym: &str,
+        batch: &WriteBatch,
+    ) -> Result<Vec<Patch>, AppError> {
+        let now = Utc::now();
+        let patches: Vec<Patch> = batch
+            .patches
+            .iter()
+            .map(|p| Patch {
+                created_at: p.created_at.or(Some(now)),
+                ..p.clone()
+            })
+            .collect();
+        let g = gym.to_string();
+        let batch = WriteBatch {
+            patches: patches.clone(),
+            ..batch.clone()
+        };
+        self.call(move |conn| {
+            // dropping the transaction on error rolls it back
+            let tx = conn.transaction()?;
+            for patch in &batch.patches {
+                insert_patch(&tx, &g, patch)?;
+            }
+            for snapshot in &batch.snapshots {
+                insert_snapshot(&tx, &g, snapshot)?;
+            }
+            for view in &batch.views {
+                insert_view(
+                    &tx,
+                    &g,
+                    view.collection,
+                    &view.object_id,
+                    &view.row,
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await?;
+
+        for patch in &patches {
+            let _ = self.patches.send((gym.to_string(), patch.clone()));
+        }
+        Ok(patches)
+    }
+
+    async fn lookup_patch(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Option<Patch>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        self.call(move |conn| {
+            query_doc(
+                conn,
+                "SELECT doc FROM patches \
+                 WHERE gym = ?1 AND object_id = ?2 AND revision_id = ?3 \
+                 LIMIT 1",
+                params![gym, object_id, rev_id],
+            )
+        })
+        .await
+    }
+
+    async fn patches_after(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        rev_id: RevId,
+    ) -> Result<Vec<Patch>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        self.call(move |conn| {
+            query_docs(
+                conn,
+                "SELECT doc FROM patches \
+                 WHERE gym = ?1 AND object_id = ?2 AND revision_id > ?3 \
+                 ORDER BY revision_id ASC",
+                params![gym, object_id, rev_id],
+            )
+        })
+        .await
+    }
+
+    async fn patches_in_range(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+        limit: usize,
+    ) -> Result<Vec<Patch>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        self.call(move |conn| {
+            query_docs(
+                conn,
+                "SELECT doc FROM patches \
+                 WHERE gym = ?1 AND object_id = ?2 AND revision_id >= ?3 \
+                 AND (?4 IS NULL OR revision_id <= ?4) \
+                 ORDER BY revision_id ASC LIMIT ?5",
+                params![gym, object_id, range.0, range.1, limit],
+            )
+        })
+        .await
+    }
+
+    async fn subscribe_patches(
+        &self,
+        gym: &str,
+    ) -> Result<PatchStream, AppError> {
+        Ok(patch_stream(self.patches.subscribe(), gym))
+    }
+
+    async fn store_snapshot(
+        &self,
+        gym: &str,
+        snapshot: &Snapshot,
+    ) -> Result<Snapshot, AppError> {
+        let (g, s) = (gym.to_string(), snapshot.clone());
+        self.call(move |conn| insert_snapshot(conn, &g, &s)).await?;
+        Ok(snapshot.clone())
+    }
+
+    async fn latest_snapshot(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+    ) -> Result<Option<Snapshot>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        let (low, high) = (range.0, range.1.unwrap_or(RevId::MAX));
+        self.call(move |conn| {
+            query_doc(
+                conn,
+                "SELECT doc FROM snapshots \
+                 WHERE gym = ?1 AND object_id = ?2 \
+                   AND revision_id >= ?3 AND revision_id <= ?4 \
+                 ORDER BY revision_id DESC LIMIT 1",
+                params![gym, object_id, low, high],
+            )
+        })
+        .await
+    }
+
+    async fn snapshot_revisions(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Vec<RevId>, AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        self.call(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT revision_id FROM snapshots \
+                 WHERE gym = ?1 AND object_id = ?2 ORDER BY revision_id ASC",
+            )?;
+            let revisions = stmt
+                .query_map(params![gym, object_id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            Ok(revisions)
+        })
+        .await
+    }
+
+    async fn delete_snapshots(
+        &self,
+        gym: &str,
+        object_id: &ObjectId,
+        revisions: &[RevId],
+    ) -> Result<(), AppError> {
+        let (gym, object_id) = (gym.to_string(), object_id.clone());
+        let revisions = revisions.to_vec();
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(
+                    "DELETE FROM snapshots \
+                     WHERE gym = ?1 AND object_id = ?2 AND revision_id = ?3",
+                )?;
+                for rev_id in revisions {
+                    stmt.execute(params![gym, object_id, rev_id])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+        row: &Value,
+    ) -> Result<(), AppError> {
+        let (gym, collection, object_id, row) = (
+            gym.to_string(),
+            collection.to_string(),
+            object_id.clone(),
+            row.clone(),
+        );
+        self.call(move |conn| {
+            insert_view(conn, &gym, &collection, &object_id, &row)
+        })
+        .await
+    }
+
+    async fn lookup_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<Option<Value>, AppError> {
+        let (gym, collection, object_id) =
+            (gym.to_string(), collection.to_string(), object_id.clone());
+        self.call(move |conn| {
+            query_doc(
+                conn,
+                "SELECT doc FROM views \
+                 WHERE gym = ?1 AND collection = ?2 AND id = ?3",
+                params![gym, collection, object_id],
+            )
+        })
+        .await
+    }
+
+    async fn query_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        query: &ViewQuery,
+    ) -> Result<Vec<Value>, AppError> {
+        let mut sql = String::from(
+            "SELECT doc FROM views WHERE gym = ?1 AND collection = ?2",
+        );
+        let mut values = vec![
+            SqlValue::Text(gym.to_string()),
+            SqlValue::Text(collection.to_string()),
+        ];
+        for filter in &query.filters {
+            let (op, value) = match filter {
+                Filter::Eq(_, value) => ("=", value),
+                Filter::Ne(_, value) => ("!=", value),
+            };
+            values.push(sql_value(value));
+            let field = json_field(filter.field())?;
+            sql.push_str(&format!(" AND {field} {op} ?{}", values.len()));
+        }
+        if let Some(field) = query.order_by_desc {
+            sql.push_str(&format!(" ORDER BY {} DESC", json_field(field)?));
+        }
+        if let Some(limit) = query.limit {
+            sql.push_str(&format!(" LIMIT {limit}"));
+        }
+
+        self.call(move |conn| query_docs(conn, &sql, params_from_iter(values)))
+            .await
+    }
+
+    async fn delete_view(
+        &self,
+        gym: &str,
+        collection: &str,
+        object_id: &ObjectId,
+    ) -> Result<(), AppError> {
+        let (gym, collection, object_id) =
+            (gym.to_string(), collection.to_string(), object_id.clone());
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "DELETE FROM views \
+                 WHERE gym = ?1 AND collection = ?2 AND id = ?3",
+            )?
+            .execute(params![gym, collection, object_id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn store_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+        session: &Session,
+    ) -> Result<Session, AppError> {
+        let session = Session {
+            id: Some(session_id.to_string()),
+            created_at: session.created_at.or(Some(Utc::now())),
+            ..session.clone()
+        };
+        let doc = to_doc(&session)?;
+        let (gym, session_id, obj_id) = (
+            gym.to_string(),
+            session_id.to_string(),
+            session.obj_id.clone(),
+        );
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "INSERT OR REPLACE INTO sessions (gym, id, obj_id, doc) \
+                 VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .execute(params![gym, session_id, obj_id, doc])?;
+            Ok(())
+        })
+        .await?;
+        Ok(session)
+    }
+
+    async fn lookup_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<Option<Session>, AppError> {
+        let (gym, session_id) = (gym.to_string(), session_id.to_string());
+        self.call(move |conn| {
+            query_doc(
+                conn,
+                "SELECT doc FROM sessions WHERE gym = ?1 AND id = ?2",
+                params![gym, session_id],
+            )
+        })
+        .await
+    }
+
+    async fn delete_session(
+        &self,
+        gym: &str,
+        session_id: &str,
+    ) -> Result<(), AppError> {
+        let (gym, session_id) = (gym.to_string(), session_id.to_string());
+        self.call(move |conn| {
+            conn.prepare_cached(
+                "DELETE FROM sessions WHERE gym = ?1 AND id = ?2",
+            )?
+            .execute(params![gym, session_id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn sessions(&self, gym: &str) -> Result<Vec<Session>, AppError> {
+        let gym = gym.to_string();
+        self.call(move |conn| {
+            query_docs(conn, "SELECT doc FROM sessions WHERE gym = ?1", [gym])
+        })
+        .await
+    }
+
+    async fn account_sessions(
+        &self,
+        gym: &str,
+        obj_id: &ObjectId,
+    ) -> Result<Vec<Session>, AppError> {
+        let (gym, obj_id) = (gym.to_string(), obj_id.clone());
+        self.call(move |conn| {
+            query_docs(
+                conn,
+                "SELECT doc FROM sessions WHERE gym = ?1 AND obj_id = ?2",
+                params![gym, obj_id],
+            )
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use otp::Operation;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        AppState,
+        storage::{MAX_SAVE_RETRIES, apply_object_updates},
+        test_boulder,
+        types::{Object, ObjectType},
+    };
+
+    #[test]
+    fn migrations_are_idempotent() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+
+        let version: i64 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len() as i64, version);
+    }
+
+    #[tokio::test]
+    async fn view_rows_survive_migration() {
+        let conn = Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS.iter().take(2) {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", 2).unwrap();
+        for (id, set_date) in [("a", 1), ("b", 2)] {
+            let mut row = test_boulder();
+            let fields = row.as_object_mut().unwrap();
+            fields.insert(String::from("id"), json!(id));
+            fields.insert(String::from("setDate"), json!(set_date));
+            conn.execute(
+                "INSERT INTO boulders_view \
+                 (gym, id, removed, is_draft, set_date, doc) \
+                 VALUES ('test', ?1, 0, 0, ?2, ?3)",
+                params![id, set_date, row.to_string()],
+            )
+            .unwrap();
+        }
+
+        let db = SqliteStorage::with_connection(conn).unwrap();
+        let query = ViewQuery::default()
+            .filter(Filter::Eq("removed", json!(0)))
+            .order_by_desc("setDate");
+        let rows = db
+            .query_view("test", "boulders_view", &query)
+            .await
+            .unwrap();
+        let ids: Vec<_> = rows.iter().filter_map(|r| r.get("id")).collect();
+        assert_eq!(vec![&json!("b"), &json!("a")], ids);
+    }
+
+    #[tokio::test]
+    async fn patches_and_snapshots_by_revision() {
+        let db = SqliteStorage::open_in_memory().unwrap();
+        let mut patches = db.subscribe_patches("test").await.unwrap();
+        let object_id = String::from("obj");
+
+        for rev_id in [2, 0, 1] {
+            let patch = Patch::new_revision(
+                rev_id,
+                object_id.clone(),
+                String::new(),
+                Operation::new_set("x", json!(rev_id)),
+            );
+            db.store_patch("test", &patch).await.unwrap();
+            let snapshot = Snapshot {
+                object_id: object_id.clone(),
+                revision_id: rev_id,
+                content: json!({ "x": rev_id }),
+            };
+            db.store_snapshot("test", &snapshot).await.unwrap();
+        }
+
+        let after = db.patches_after("test", &object_id, 0).await.unwrap();
+        assert_eq!(
+            vec![1, 2],
+            after.iter().map(|p| p.revision_id).collect::<Vec<_>>()
+        );
+
+        let page = db
+            .patches_in_range("test", &object_id, (RevId::MIN, Some(1)), 10)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![0, 1],
+            page.iter().map(|p| p.revision_id).collect::<Vec<_>>()
+        );
+        let page = db
+            .patches_in_range("test", &object_id, (1, None), 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![1],
+            page.iter().map(|p| p.revision_id).collect::<Vec<_>>()
+        );
+
+        let snapshot = db
+            .latest_snapshot("test", &object_id, (0, Some(1)))
+            .await
+            .unwrap();
+        assert_eq!(Some(1), snapshot.map(|s| s.revision_id));
+
+        // all stored patches are streamed to subscribers
+        let received = patches.next().await.unwrap();
+        assert_eq!(2, received.revision_id);
+    }
+
+    #[tokio::test]
+    async fn conflicting_commit_stores_nothing() {
+        let db = SqliteStorage::open_in_memory().unwrap();
+        let patch = |object_id: &str, rev_id| {
+            Patch::new_revision(
+                rev_id,
+                object_id.to_string(),
+                String::new(),
+                Operation::new_set("x", json!(rev_id)),
+            )
+        };
+        db.store_patch("test", &patch("b", 0)).await.unwrap();
+
+        let batch = WriteBatch {
+            patches: vec![patch("a", 0), patch("b", 0)],
+            snapshots: vec![Snapshot::new(String::from("a"))],
+            views: Vec::new(),
+        };
+        assert!(matches!(
+            db.commit("test", &batch).await,
+            Err(AppError::RevisionConflict(_, 0))
+        ));
+        let a = String::from("a");
+        assert!(db.patches_after("test", &a, -1).await.unwrap().is_empty());
+        assert!(db.snapshot_revisions("test", &a).await.unwrap().is_empty());
+
+        let batch = WriteBatch {
+            patches: vec![patch("a", 0), patch("b", 1)],
+            ..batch
+        };
+        let stored = db.commit("test", &batch).await.unwrap();
+        assert!(stored.iter().all(|p| p.created_at.is_some()));
+        assert_eq!(1, db.patches_after("test", &a, -1).await.unwrap().len());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_patches_get_distinct_revisions() {
+        // blocking calls to sqlite interleave the requests
+        let state = AppState::for_tests(Arc::new(
+            SqliteStorage::open_in_memory().unwrap(),
+        ));
+        let gym = "test";
+        let obj = Object::from_value(
+            &state,
+            gym,
+            String::from("author"),
+            ObjectType::Boulder,
+            &test_boulder(),
+        )
+        .await
+        .unwrap();
+
+        // every request is based on the same revision, but sets its own value.
+        // Each conflict means another request stored a revision, so with
+        // these many requests none runs out of retries.
+        let writers = MAX_SAVE_RETRIES + 1;
+        let tasks: Vec<_> = (0..writers)
+            .map(|i| {
+                let (state, obj_id) = (state.clone(), obj.id.clone());
+                tokio::spawn(async move {
+                    apply_object_updates(
+                        &state,
+                        gym,
+                        obj_id,
+                        otp::ZERO_REV_ID,
+                        String::from("author"),
+                        vec![Operation::new_set("name", json!(format!("{i}")))],
+                    )
+                    .await
+                    .map(|response| response.0)
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let patches = Patch::after_revision(&state, gym, &obj.id, 0)
+            .await
+            .unwrap();
+        let revisions: Vec<_> = patches.iter().map(|p| p.revision_id).collect();
+        let last_revision = RevId::try_from(writers).unwrap();
+        assert_eq!((1..=last_revision).collect::<Vec<_>>(), revisions);
+
+        // the latest snapshot reflects the last stored patch
+        let snapshot =
+            Snapshot::lookup_latest(&state, gym, &obj.id).await.unwrap();
+        assert_eq!(last_revision, snapshot.revision_id);
+        let last = patches.last().unwrap();
+        assert_eq!(
+            last.operation.apply_to(json!({})).unwrap().get("name"),
+            snapshot.content.get("name")
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! In-process cache of the latest snapshot of objects.
+//!
+//! Looking up the latest snapshot needs at least two queries (snapshot and
+//! patches after it). The cache keeps the most recently used snapshots and
+//! follows the patch stream of every gym it holds snapshots of, so patches
+//! written by other instances are applied as well. An entry which can not be
+//! advanced by the next patch is evicted instead. Applied patches are passed
+//! on to subscribers, which wait for changes of objects without a patch
+//! subscription of their own.
+
+use std::{
+    collections::HashSet,
+    num::NonZeroUsize,
+    sync::{
+        Arc, Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use futures::StreamExt;
+use lru::LruCache;
+use otp::ObjectId;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    AppError, AppState,
+    backend::PatchStream,
+    types::{Patch, Snapshot},
+};
+
+/// patches subscribers may fall behind by before they miss some
+const PATCHED_CAPACITY: usize = 1024;
+
+struct Inner {
+    snapshots: LruCache<(String, ObjectId), Snapshot>,
+    /// gyms with a running patch subscription
+    watched: HashSet<String>,
+    /// incremented for every received patch, lookups only cache what they
+    /// loaded if no patch arrived in the meantime
+    epoch: u64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    entries: usize,
+    capacity: usize,
+}
+
+pub struct SnapshotCache {
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    /// gym and object of every applied patch
+    patched: broadcast::Sender<(String, ObjectId)>,
+}
+
+impl SnapshotCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                snapshots: LruCache::new(capacity),
+                watched: HashSet::new(),
+                epoch: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            patched: broadcast::channel(PATCHED_CAPACITY).0,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // the cache stays consistent even if a holder panicked
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.lock();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.snapshots.len(),
+            capacity: inner.snapshots.cap().get(),
+        }
+    }
+
+    /// the cached latest snapshot of the object, counts hits and misses
+    pub fn get(&self, gym: &str, object_id: &ObjectId) -> Option<Snapshot> {
+        let key = (gym.to_string(), object_id.clone());
+        let snapshot = self.lock().snapshots.get(&key).cloned();
+        let counter = match snapshot {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        snapshot
+    }
+
+    /// Make sure the patches of the gym are followed. Returns the epoch to
+    /// pass to [`SnapshotCache::insert`] after loading a snapshot.
+    pub async fn watch(
+        self: &Arc<Self>,
+        state: &AppState,
+        gym: &str,
+    ) -> Result<u64, AppError> {
+        if !self.lock().watched.contains(gym) {
+            let patches = Patch::subscribe(state, gym).await?;
+            // another lookup might have subscribed in the meantime
+            if self.lock().watched.insert(gym.to_string()) {
+                tokio::spawn(Arc::clone(self).follow(gym.to_string(), patches));
+            }
+        }
+
+        Ok(self.lock().epoch)
+    }
+
+    /// Receive the gym and object of patches of the gym from now on, after
+    /// they were applied to the cache. A lagging receiver has missed some.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        state: &AppState,
+        gym: &str,
+    ) -> Result<broadcast::Receiver<(String, ObjectId)>, AppError> {
+        let patched = self.patched.subscribe();
+        self.watch(state, gym).await?;
+        Ok(patched)
+    }
+
+    /// cache a snapshot loaded from storage unless patches arrived since epoch
+    pub fn insert(&self, gym: &str, snapshot: &Snapshot, epoch: u64) {
+        let mut inner = self.lock();
+        if inner.epoch == epoch {
+            let key = (gym.to_string(), snapshot.object_id.clone());
+            inner.snapshots.put(key, snapshot.clone());
+        }
+    }
+
+    /// replace a cached snapshot with a newer revision written by this process
+    pub fn advance(&self, gym: &str, snapshot: &Snapshot) {
+        let key = (gym.to_string(), snapshot.object_id.clone());
+        let mut inner = self.lock();
+        if let Some(cached) = inner.snapshots.peek_mut(&key)
+            && cached.revision_id < snapshot.revision_id
+        {
+            *cached = snapshot.clone();
+        }
+    }
+
+    /// Drop the cached snapshot, e.g. after deleting a stored one it might
+    /// have been built from. Lookups in flight do not cache what they loaded.
+    pub fn evict(&self, gym: &str, object_id: &ObjectId) {
+        let key = (gym.to_string(), object_id.clone());
+        let mut inner = self.lock();
+        inner.epoch += 1;
+        inner.snapshots.pop(&key);
+    }
+
+    fn apply(&self, gym: &str, patch: &Patch) {
+        let key = (gym.to_string(), patch.object_id.clone());
+        let mut inner = self.lock();
+        inner.epoch += 1;
+
+        let Some(cached) = inner.snapshots.peek_mut(&key) else {
+            return;
+        };
+        if cached.revision_id >= patch.revision_id {
+            // already advanced by the write
+            return;
+        }
+
+        let next = (cached.revision_id + 1 == patch.revision_id)
+            .then(|| cached.apply_patch(patch).ok())
+            .flatten();
+        match next {
+            Some(snapshot) => *cached = snapshot,
+            None => {
+                inner.snapshots.pop(&key);
+            }
+        }
+    }
+
+    async fn follow(self: Arc<Self>, gym: String, mut patches: PatchStream) {
+        while let Some(patch) = patches.next().await {
+            self.apply(&gym, &patch);
+            // fails only without subscribers
+            let _ = self.patched.send((gym.clone(), patch.object_id));
+        }
+
+        // without patches we can not tell whether entries are still fresh
+        tracing::warn!(
+            "patch stream of {gym} ended, evicting cached snapshots"
+        );
+        let mut inner = self.lock();
+        inner.watched.remove(&gym);
+        inner.epoch += 1;
+        let stale: Vec<_> = inner
+            .snapshots
+            .iter()
+            .filter(|((g, _), _)| *g == gym)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            inner.snapshots.pop(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use otp::Operation;
+    use serde_json::json;
+
+    use super::*;
+
+    fn state() -> AppState {
+        AppState {
+            snapshots: Arc::new(SnapshotCache::new(
+                NonZeroUsize::new(2).unwrap(),
+            )),
+            ..AppState::in_memory()
+        }
+    }
+
+    fn patch(rev_id: otp::RevId, value: i64) -> Patch {
+        Patch::new_revision(
+            rev_id,
+            String::from("obj"),
+            String::new(),
+            Operation::new_set("x", json!(value)),
+        )
+    }
+
+    #[tokio::test]
+    async fn follows_patches_of_other_writers() {
+        let state = state();
+        let (gym, object_id) = ("test", String::from("obj"));
+        let root = Patch::new(object_id.clone(), String::new(), &json!({}));
+        state.db.store_patch(gym, &root).await.unwrap();
+
+        let snapshot = Snapshot::lookup_latest(&state, gym, &object_id)
+            .await
+            .unwrap();
+        assert_eq!(0, snapshot.revision_id);
+        let _ = Snapshot::lookup_latest(&state, gym, &object_id)
+            .await
+            .unwrap();
+        let stats = state.snapshots.stats();
+        assert_eq!((1, 1), (stats.hits, stats.misses));
+
+        // written without going through the cache
+        state.db.store_patch(gym, &patch(1, 42)).await.unwrap();
+        for _ in 0..100 {
+            let cached = state.snapshots.get(gym, &object_id).unwrap();
+            if cached.revision_id == 1 {
+                assert_eq!(Some(&json!(42)), cached.content.get("x"));
+                return;
+            }
+            tokio::task::yield_now().await;
+        }
+        panic!("cached snapshot was not advanced");
+    }
+
+    #[tokio::test]
+    async fn subscribers_see_applied_patches() {
+        let state = state();
+        let (gym, object_id) = ("test", String::from("obj"));
+        let root = Patch::new(object_id.clone(), String::new(), &json!({}));
+        state.db.store_patch(gym, &root).await.unwrap();
+        let _ = Snapshot::lookup_latest(&state, gym, &object_id)
+            .await
+            .unwrap();
+
+        let mut patched = state.snapshots.subscribe(&state, gym).await.unwrap();
+        state.db.store_patch(gym, &patch(1, 42)).await.unwrap();
+        assert_eq!(
+            (gym.to_string(), object_id.clone()),
+            patched.recv().await.unwrap()
+        );
+        let cached = state.snapshots.get(gym, &object_id).unwrap();
+        assert_eq!(1, cached.revision_id);
+    }
+
+    #[test]
+    fn evicts_snapshots_with_missing_patches() {
+        let cache = SnapshotCache::new(NonZeroUsize::new(2).unwrap());
+        let snapshot = Snapshot {
+            object_id: String::from("obj"),
+            revision_id: 1,
+            content: json!({}),
+        };
+        cache.insert("test", &snapshot, 0);
+
+        // stale patches are ignored
+        cache.apply("test", &patch(1, 1));
+        assert!(cache.get("test", &snapshot.object_id).is_some());
+
+        // a revision was skipped
+        cache.apply("test", &patch(3, 3));
+        assert!(cache.get("test", &snapshot.object_id).is_none());
+
+        // loaded before the last patch arrived
+        cache.insert("test", &snapshot, 0);
+        assert!(cache.get("test", &snapshot.object_id).is_none());
+    }
+}
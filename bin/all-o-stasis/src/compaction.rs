@@ -0,0 +1,245 @@
+//! Pruning of snapshots.
+//!
+//! Snapshots are only checkpoints, every revision can be restored by replaying
+//! patches from the nearest older snapshot. Objects written before the
+//! [`SnapshotPolicy`] existed (or with a stricter policy) have more snapshots
+//! than needed, compaction deletes the ones the current policy would not have
+//! stored. The latest snapshot of an object is always kept.
+//!
+//! Compaction reads the patches of objects, so it is not run by every
+//! instance. Admins request it per gym, or a single instance runs it in the
+//! background when configured with an interval.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use otp::{ObjectId, RevId};
+
+use crate::{
+    AppError, AppState,
+    types::{Patch, SnapshotPolicy},
+};
+
+/// Revisions of snapshots which are redundant under the policy. `revisions`
+/// must be sorted, `patch_lens` maps revision ids to the size of their patch.
+/// Duplicate snapshots of a revision are either all kept or all redundant.
+fn redundant_snapshots(
+    policy: &SnapshotPolicy,
+    revisions: &[RevId],
+    patch_lens: &BTreeMap<RevId, usize>,
+) -> Vec<RevId> {
+    let mut revisions = revisions.to_vec();
+    revisions.dedup();
+    let Some((_latest, older)) = revisions.split_last() else {
+        return Vec::new();
+    };
+
+    // start from the implied empty snapshot, storing it is never needed
+    let mut kept = otp::ZERO_REV_ID - 1;
+    let mut redundant = Vec::new();
+    for &rev_id in older {
+        let is_due = rev_id > kept && {
+            let patch_bytes = patch_lens.range(kept + 1..=rev_id);
+            policy.is_due(rev_id - kept, patch_bytes.map(|(_, l)| l).sum())
+        };
+        if is_due {
+            kept = rev_id;
+        } else {
+            redundant.push(rev_id);
+        }
+    }
+
+    redundant
+}
+
+/// Whether every older snapshot is due by its number of revisions alone,
+/// then none is redundant whatever the sizes of the patches.
+fn due_by_revisions(policy: &SnapshotPolicy, revisions: &[RevId]) -> bool {
+    let mut revisions = revisions.to_vec();
+    revisions.dedup();
+    let Some((_latest, older)) = revisions.split_last() else {
+        return true;
+    };
+
+    let mut kept = otp::ZERO_REV_ID - 1;
+    older.iter().all(|&rev_id| {
+        let is_due = rev_id > kept && rev_id - kept >= policy.revisions;
+        kept = rev_id;
+        is_due
+    })
+}
+
+/// delete redundant snapshots of an object, returns how many were deleted
+pub async fn compact_object(
+    state: &AppState,
+    gym: &str,
+    object_id: &ObjectId,
+) -> Result<usize, AppError> {
+    let revisions = state.db.snapshot_revisions(gym, object_id).await?;
+    // loading the patches is only needed to tell whether a snapshot is due
+    if revisions.len() < 2
+        || due_by_revisions(&state.snapshot_policy, &revisions)
+    {
+        return Ok(0);
+    }
+
+    let patch_lens =
+        Patch::after_revision(state, gym, object_id, otp::ZERO_REV_ID - 1)
+            .await?
+            .iter()
+            .map(|p| (p.revision_id, p.operation_len()))
+            .collect();
+
+    let redundant =
+        redundant_snapshots(&state.snapshot_policy, &revisions, &patch_lens);
+    if !redundant.is_empty() {
+        state
+            .db
+            .delete_snapshots(gym, object_id, &redundant)
+            .await?;
+    }
+
+    Ok(redundant.len())
+}
+
+/// compact all objects of a gym, returns the number of deleted snapshots
+pub async fn compact_gym(
+    state: &AppState,
+    gym: &str,
+) -> Result<usize, AppError> {
+    let mut deleted = 0;
+    for object_id in state.db.object_ids(gym).await? {
+        deleted += compact_object(state, gym, &object_id).await?;
+    }
+    Ok(deleted)
+}
+
+/// compact all registered gyms every interval
+pub async fn run(state: AppState, interval: Duration) {
+    let start = tokio::time::Instant::now() + interval;
+    let mut ticks = tokio::time::interval_at(start, interval);
+    loop {
+        ticks.tick().await;
+
+        for gym in state.gyms.names() {
+            match compact_gym(&state, &gym).await {
+                Ok(deleted) => {
+                    tracing::info!("compacted {gym}: {deleted} snapshots");
+                }
+                Err(e) => tracing::warn!("compacting {gym} failed: {e:?}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::types::Snapshot;
+
+    fn policy(revisions: RevId, patch_bytes: usize) -> SnapshotPolicy {
+        SnapshotPolicy {
+            revisions,
+            patch_bytes,
+        }
+    }
+
+    #[test]
+    fn redundant_snapshot_revisions() {
+        let lens = |n: RevId, len: usize| (0..=n).map(|r| (r, len)).collect();
+
+        let cases: Vec<(
+            SnapshotPolicy,
+            Vec<RevId>,
+            BTreeMap<_, _>,
+            Vec<RevId>,
+        )> = vec![
+            // nothing to compact
+            (policy(5, 1000), vec![], lens(10, 1), vec![]),
+            (policy(5, 1000), vec![3], lens(10, 1), vec![]),
+            // the empty snapshot is always redundant
+            (policy(5, 1000), vec![-1, 10], lens(10, 1), vec![-1]),
+            // every revision was snapshotted
+            (
+                policy(3, 1000),
+                (0..=7).collect(),
+                lens(7, 1),
+                vec![0, 1, 3, 4, 6],
+            ),
+            // large patches make snapshots due earlier
+            (
+                policy(100, 10),
+                (0..=5).collect(),
+                lens(5, 5),
+                vec![0, 2, 4],
+            ),
+            // duplicates of kept revisions stay
+            (policy(1, 1000), vec![0, 0, 1, 1], lens(1, 1), vec![]),
+            (
+                policy(2, 1000),
+                vec![-1, -1, 0, 0, 1, 2],
+                lens(2, 1),
+                vec![-1, 0],
+            ),
+        ];
+
+        for (policy, revisions, lens, expected) in cases {
+            assert_eq!(
+                expected,
+                redundant_snapshots(&policy, &revisions, &lens),
+                "revisions {revisions:?} with {policy:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_latest_revision() {
+        let state = AppState {
+            snapshot_policy: policy(4, usize::MAX),
+            ..AppState::in_memory()
+        };
+        let (gym, object_id) = ("test", String::from("obj"));
+
+        for rev_id in 0..=10 {
+            let snapshot = Snapshot {
+                object_id: object_id.clone(),
+                revision_id: rev_id,
+                content: json!({ "x": rev_id }),
+            };
+            let patch = Patch::new_revision(
+                rev_id,
+                object_id.clone(),
+                String::new(),
+                otp::Operation::new_set("x", json!(rev_id)),
+            );
+            state.db.store_patch(gym, &patch).await.unwrap();
+            state.db.store_snapshot(gym, &snapshot).await.unwrap();
+        }
+
+        assert_eq!(8, compact_object(&state, gym, &object_id).await.unwrap());
+        assert_eq!(
+            vec![3, 7, 10],
+            state.db.snapshot_revisions(gym, &object_id).await.unwrap()
+        );
+
+        let latest =
+            Snapshot::lookup(&state, gym, &object_id, 9).await.unwrap();
+        assert_eq!(Some(&json!(9)), latest.content.get("x"));
+
+        // nothing left to compact
+        assert_eq!(0, compact_object(&state, gym, &object_id).await.unwrap());
+    }
+
+    #[test]
+    fn snapshots_due_by_revisions() {
+        let policy = policy(4, 1000);
+        assert!(due_by_revisions(&policy, &[3, 7, 10]));
+        assert!(due_by_revisions(&policy, &[3, 7, 7, 8]));
+        assert!(due_by_revisions(&policy, &[5]));
+        // the empty snapshot is never due
+        assert!(!due_by_revisions(&policy, &[-1, 10]));
+        // might be due by the size of the patches
+        assert!(!due_by_revisions(&policy, &[3, 5, 10]));
+    }
+}
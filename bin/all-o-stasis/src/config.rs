@@ -0,0 +1,393 @@
+//! Configuration of the server.
+//!
+//! Settings are read from the JSON file at `CONFIG_FILE` if it is set. Every
+//! setting can be overridden by its environment variable, e.g. `bindAddress`
+//! by `BIND_ADDRESS`. Settings of a gym are stored with the gym, see
+//! [`crate::types::GymSettings`].
+
+use std::{net::SocketAddr, num::NonZeroUsize, str::FromStr};
+
+use otp::RevId;
+use serde::Deserialize;
+
+use crate::types::SnapshotPolicy;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Environment {
+    Production,
+    Development,
+    /// running on a developer machine or self-hosted
+    Local,
+}
+
+impl FromStr for Environment {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "production" => Ok(Self::Production),
+            "development" => Ok(Self::Development),
+            "local" => Ok(Self::Local),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum StorageKind {
+    Firestore,
+    Sqlite,
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "firestore" => Ok(Self::Firestore),
+            "sqlite" => Ok(Self::Sqlite),
+            "memory" => Ok(Self::Memory),
+            _ => Err(()),
+        }
+    }
+}
+
+/// settings from one source, unset ones come from the next source
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct Settings {
+    environment: Option<Environment>,
+    bind_address: Option<SocketAddr>,
+    api_host: Option<String>,
+    frontend_url: Option<String>,
+    storage: Option<StorageKind>,
+    sqlite_path: Option<String>,
+    project_id: Option<String>,
+    firestore_database_id: Option<String>,
+    admin_token: Option<String>,
+    token_key: Option<String>,
+    snapshot_every_revisions: Option<RevId>,
+    snapshot_every_bytes: Option<usize>,
+    snapshot_cache_size: Option<NonZeroUsize>,
+    compaction_interval_secs: Option<u64>,
+    session_max_age_secs: Option<u64>,
+    session_idle_secs: Option<u64>,
+    passport_ttl_secs: Option<u64>,
+    passport_poll_secs: Option<u64>,
+    login_limit_per_ip: Option<u64>,
+    login_limit_per_email: Option<u64>,
+    login_limit_per_gym: Option<u64>,
+    login_limit_window_secs: Option<u64>,
+    trusted_proxy_hops: Option<usize>,
+}
+
+/// parse the variable if it is set
+fn var<T: FromStr>(
+    env: &dyn Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>, String> {
+    env(name)
+        .map(|value| {
+            value.parse().map_err(|_| format!("{name}: invalid value"))
+        })
+        .transpose()
+}
+
+impl Settings {
+    fn from_env(env: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        Ok(Self {
+            environment: var(env, "ENVIRONMENT")?,
+            bind_address: var(env, "BIND_ADDRESS")?,
+            api_host: var(env, "API_HOST")?,
+            frontend_url: var(env, "FRONTEND_URL")?,
+            storage: var(env, "STORAGE")?,
+            sqlite_path: var(env, "SQLITE_PATH")?,
+            project_id: var(env, "PROJECT_ID")?,
+            firestore_database_id: var(env, "FIRESTORE_DATABASE_ID")?,
+            admin_token: var(env, "ADMIN_TOKEN")?,
+            token_key: var(env, "TOKEN_KEY")?,
+            snapshot_every_revisions: var(env, "SNAPSHOT_EVERY_REVISIONS")?,
+            snapshot_every_bytes: var(env, "SNAPSHOT_EVERY_BYTES")?,
+            snapshot_cache_size: var(env, "SNAPSHOT_CACHE_SIZE")?,
+            compaction_interval_secs: var(env, "COMPACTION_INTERVAL_SECS")?,
+            session_max_age_secs: var(env, "SESSION_MAX_AGE_SECS")?,
+            session_idle_secs: var(env, "SESSION_IDLE_SECS")?,
+            passport_ttl_secs: var(env, "PASSPORT_TTL_SECS")?,
+            passport_poll_secs: var(env, "PASSPORT_POLL_SECS")?,
+            login_limit_per_ip: var(env, "LOGIN_LIMIT_PER_IP")?,
+            login_limit_per_email: var(env, "LOGIN_LIMIT_PER_EMAIL")?,
+            login_limit_per_gym: var(env, "LOGIN_LIMIT_PER_GYM")?,
+            login_limit_window_secs: var(env, "LOGIN_LIMIT_WINDOW_SECS")?,
+            trusted_proxy_hops: var(env, "TRUSTED_PROXY_HOPS")?,
+        })
+    }
+
+    /// the settings of self, the ones of other where self has none
+    fn or(self, other: Self) -> Self {
+        Self {
+            environment: self.environment.or(other.environment),
+            bind_address: self.bind_address.or(other.bind_address),
+            api_host: self.api_host.or(other.api_host),
+            frontend_url: self.frontend_url.or(other.frontend_url),
+            storage: self.storage.or(other.storage),
+            sqlite_path: self.sqlite_path.or(other.sqlite_path),
+            project_id: self.project_id.or(other.project_id),
+            firestore_database_id: self
+                .firestore_database_id
+                .or(other.firestore_database_id),
+            admin_token: self.admin_token.or(other.admin_token),
+            token_key: self.token_key.or(other.token_key),
+            snapshot_every_revisions: self
+                .snapshot_every_revisions
+                .or(other.snapshot_every_revisions),
+            snapshot_every_bytes: self
+                .snapshot_every_bytes
+                .or(other.snapshot_every_bytes),
+            snapshot_cache_size: self
+                .snapshot_cache_size
+                .or(other.snapshot_cache_size),
+            compaction_interval_secs: self
+                .compaction_interval_secs
+                .or(other.compaction_interval_secs),
+            session_max_age_secs: self
+                .session_max_age_secs
+                .or(other.session_max_age_secs),
+            session_idle_secs: self
+                .session_idle_secs
+                .or(other.session_idle_secs),
+            passport_ttl_secs: self
+                .passport_ttl_secs
+                .or(other.passport_ttl_secs),
+            passport_poll_secs: self
+                .passport_poll_secs
+                .or(other.passport_poll_secs),
+            login_limit_per_ip: self
+                .login_limit_per_ip
+                .or(other.login_limit_per_ip),
+            login_limit_per_email: self
+                .login_limit_per_email
+                .or(other.login_limit_per_email),
+            login_limit_per_gym: self
+                .login_limit_per_gym
+                .or(other.login_limit_per_gym),
+            login_limit_window_secs: self
+                .login_limit_window_secs
+                .or(other.login_limit_window_secs),
+            trusted_proxy_hops: self
+                .trusted_proxy_hops
+                .or(other.trusted_proxy_hops),
+        }
+    }
+}
+
+/// only for local servers, others must set `TOKEN_KEY`
+const LOCAL_TOKEN_KEY: &str = "all-o-stasis-local";
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub environment: Environment,
+    pub bind_address: SocketAddr,
+    /// public URL of this server, used in login confirmation links
+    pub api_host: String,
+    /// where users land after confirming a login unless their gym has its own
+    /// frontend URL, `{gym}` is replaced by the name of the gym
+    pub frontend_url: String,
+    pub storage: StorageKind,
+    pub sqlite_path: String,
+    pub project_id: Option<String>,
+    /// named Firestore database, the default database if unset
+    pub firestore_database_id: Option<String>,
+    /// bearer token for managing the gym registry, disabled if unset
+    pub admin_token: Option<String>,
+    /// key of the hashes of session ids and confirmation tokens, changing it
+    /// signs out everyone
+    pub token_key: String,
+    pub snapshot_policy: SnapshotPolicy,
+    pub snapshot_cache_size: NonZeroUsize,
+    /// seconds between compactions of snapshots by this instance, 0 (the
+    /// default) leaves them to admin requests
+    pub compaction_interval_secs: u64,
+    /// seconds after creation when sessions expire
+    pub session_max_age_secs: u64,
+    /// seconds without use after which sessions expire, 0 disables it
+    pub session_idle_secs: u64,
+    /// seconds after creation when unconfirmed passports expire
+    pub passport_ttl_secs: u64,
+    /// seconds a client waits for the confirmation of a passport per request
+    pub passport_poll_secs: u64,
+    /// logins per window from one IP, for one email and of one gym, 0 for
+    /// no limit
+    pub login_limit_per_ip: u64,
+    pub login_limit_per_email: u64,
+    pub login_limit_per_gym: u64,
+    /// seconds of the windows the login limits apply to
+    pub login_limit_window_secs: u64,
+    /// proxies in front of the server which append to `X-Forwarded-For`,
+    /// without any the header is set by clients and ignored
+    pub trusted_proxy_hops: usize,
+}
+
+impl From<Settings> for ServerConfig {
+    fn from(settings: Settings) -> Self {
+        let storage = settings.storage.unwrap_or(StorageKind::Firestore);
+        let environment = settings.environment.unwrap_or(match storage {
+            StorageKind::Firestore => Environment::Production,
+            StorageKind::Sqlite | StorageKind::Memory => Environment::Local,
+        });
+        let api_host = settings.api_host.unwrap_or_else(|| {
+            match environment {
+                Environment::Production => "https://apiv2.boulderhalle.app",
+                Environment::Development => {
+                    "https://apiv2-dev.boulderhalle.app"
+                }
+                Environment::Local => "http://localhost:8080",
+            }
+            .to_string()
+        });
+        let default_policy = SnapshotPolicy::default();
+
+        Self {
+            environment,
+            bind_address: settings
+                .bind_address
+                .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 8080))),
+            api_host,
+            frontend_url: settings.frontend_url.unwrap_or_else(|| {
+                String::from("https://{gym}.boulderhalle.app")
+            }),
+            storage,
+            sqlite_path: settings
+                .sqlite_path
+                .unwrap_or_else(|| String::from("all-o-stasis.sqlite")),
+            project_id: settings.project_id,
+            firestore_database_id: settings.firestore_database_id,
+            admin_token: settings.admin_token,
+            token_key: settings
+                .token_key
+                .unwrap_or_else(|| String::from(LOCAL_TOKEN_KEY)),
+            snapshot_policy: SnapshotPolicy {
+                revisions: settings
+                    .snapshot_every_revisions
+                    .unwrap_or(default_policy.revisions),
+                patch_bytes: settings
+                    .snapshot_every_bytes
+                    .unwrap_or(default_policy.patch_bytes),
+            },
+            snapshot_cache_size: settings.snapshot_cache_size.unwrap_or(
+                NonZeroUsize::new(10_000).unwrap_or(NonZeroUsize::MIN),
+            ),
+            compaction_interval_secs: settings
+                .compaction_interval_secs
+                .unwrap_or(0),
+            session_max_age_secs: settings
+                .session_max_age_secs
+                .unwrap_or(52 * 7 * 24 * 60 * 60),
+            session_idle_secs: settings
+                .session_idle_secs
+                .unwrap_or(90 * 24 * 60 * 60),
+            passport_ttl_secs: settings.passport_ttl_secs.unwrap_or(15 * 60),
+            passport_poll_secs: settings.passport_poll_secs.unwrap_or(25),
+            login_limit_per_ip: settings.login_limit_per_ip.unwrap_or(10),
+            login_limit_per_email: settings.login_limit_per_email.unwrap_or(5),
+            login_limit_per_gym: settings.login_limit_per_gym.unwrap_or(500),
+            login_limit_window_secs: settings
+                .login_limit_window_secs
+                .unwrap_or(60 * 60),
+            trusted_proxy_hops: settings.trusted_proxy_hops.unwrap_or(0),
+        }
+    }
+}
+
+/// a local server keeping everything in memory
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Settings {
+            storage: Some(StorageKind::Memory),
+            ..Settings::default()
+        }
+        .into()
+    }
+}
+
+impl ServerConfig {
+    /// the config from the contents of a config file and the environment
+    fn from_sources(
+        file: Option<&str>,
+        env: &dyn Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let file = match file {
+            Some(contents) => serde_json::from_str(contents)
+                .map_err(|e| format!("config file: {e}"))?,
+            None => Settings::default(),
+        };
+        let settings = Settings::from_env(env)?.or(file);
+        let has_token_key = settings.token_key.is_some();
+        let config = Self::from(settings);
+        if !has_token_key && config.environment != Environment::Local {
+            return Err(String::from("TOKEN_KEY: not set"));
+        }
+        Ok(config)
+    }
+
+    /// the config from `CONFIG_FILE` (if set) and the environment
+    pub fn load() -> Result<Self, String> {
+        let file = match std::env::var("CONFIG_FILE") {
+            Ok(path) => Some(
+                std::fs::read_to_string(&path)
+                    .map_err(|e| format!("{path}: {e}"))?,
+            ),
+            Err(_) => None,
+        };
+        Self::from_sources(file.as_deref(), &|name| std::env::var(name).ok())
+    }
+
+    /// the frontend URL of a gym without its own
+    pub fn frontend_url_of(&self, gym: &str) -> String {
+        self.frontend_url.replace("{gym}", gym)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_file() {
+        let file = r#"{
+            "environment": "development",
+            "bindAddress": "127.0.0.1:9000",
+            "snapshotEveryRevisions": 5
+        }"#;
+        let env = |name: &str| match name {
+            "BIND_ADDRESS" => Some(String::from("127.0.0.1:9001")),
+            "TOKEN_KEY" => Some(String::from("secret")),
+            _ => None,
+        };
+        let config = ServerConfig::from_sources(Some(file), &env).unwrap();
+        assert_eq!(Environment::Development, config.environment);
+        assert_eq!("127.0.0.1:9001", config.bind_address.to_string());
+        assert_eq!("https://apiv2-dev.boulderhalle.app", config.api_host);
+        assert_eq!(5, config.snapshot_policy.revisions);
+        assert_eq!(
+            "https://kletterhalle.boulderhalle.app",
+            config.frontend_url_of("kletterhalle")
+        );
+        // forwarded addresses are only trusted when configured
+        assert_eq!(0, config.trusted_proxy_hops);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let env =
+            |name: &str| (name == "STORAGE").then(|| String::from("postgres"));
+        assert!(ServerConfig::from_sources(None, &env).is_err());
+        let file = r#"{"unknown": 1}"#;
+        assert!(ServerConfig::from_sources(Some(file), &|_| None).is_err());
+        // deployments need their own key
+        let file = r#"{"environment": "production"}"#;
+        assert!(ServerConfig::from_sources(Some(file), &|_| None).is_err());
+    }
+}
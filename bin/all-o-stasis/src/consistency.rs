@@ -0,0 +1,342 @@
+//! Checking the stored history of a gym for inconsistencies.
+//!
+//! The patches of an object are its source of truth: their revision ids have
+//! to count up from [`ZERO_REV_ID`] without gaps or duplicates and replaying
+//! them has to reproduce every stored snapshot. Objects are created before
+//! their first patch is stored, so a failed create leaves an object without
+//! patches behind.
+//!
+//! Only repairs which lose no information are made: snapshots which differ
+//! from the replayed patches are deleted (they are only checkpoints) and
+//! objects without patches are marked as deleted. Everything else is reported
+//! for a human to look at.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use chrono::{TimeDelta, Utc};
+use otp::{ObjectId, RevId, ZERO_REV_ID};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    AppError, AppState,
+    types::{Object, Patch, Snapshot},
+};
+
+/// objects younger than this might still get their first patch
+const EMPTY_OBJECT_GRACE: TimeDelta = TimeDelta::minutes(10);
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Issue {
+    /// revisions below the latest one without a patch
+    RevisionGap { missing: Vec<RevId> },
+    /// several patches with the same revision
+    DuplicateRevision { revision_id: RevId, count: usize },
+    /// the patch can not be applied to the content of the revision before
+    UnreplayablePatch { revision_id: RevId, error: String },
+    /// the stored snapshot differs from replaying the patches up to it
+    SnapshotMismatch { revision_id: RevId },
+    /// patches or snapshots of an object which does not exist
+    MissingObject { patches: usize, snapshots: usize },
+    /// an object without any patch
+    EmptyObject,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Finding {
+    pub object_id: ObjectId,
+    #[serde(flatten)]
+    pub issue: Issue,
+    /// whether the repair mode fixes this
+    pub repairable: bool,
+    pub repaired: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsistencyReport {
+    pub repair: bool,
+    /// number of object ids with an object, patches or snapshots
+    pub checked: usize,
+    pub findings: Vec<Finding>,
+}
+
+/// Issues in the history of an object. `patches` must be ordered by revision,
+/// `snapshots` holds one stored snapshot per revision.
+fn check_history(patches: &[Patch], snapshots: &[Snapshot]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let mut counts: BTreeMap<RevId, usize> = BTreeMap::new();
+    for patch in patches {
+        *counts.entry(patch.revision_id).or_default() += 1;
+    }
+    if let Some(&latest) = counts.keys().next_back() {
+        let missing: Vec<RevId> = (ZERO_REV_ID..latest)
+            .filter(|rev_id| !counts.contains_key(rev_id))
+            .collect();
+        if !missing.is_empty() {
+            issues.push(Issue::RevisionGap { missing });
+        }
+    }
+    for (&revision_id, &count) in &counts {
+        if count > 1 {
+            issues.push(Issue::DuplicateRevision { revision_id, count });
+        }
+    }
+
+    // replay the first patch of every revision, the same as lookups do
+    let wanted: BTreeSet<RevId> =
+        snapshots.iter().map(|s| s.revision_id).collect();
+    let mut replayed = BTreeMap::new();
+    let mut content = json!({});
+    let mut previous = None;
+    for patch in patches {
+        if previous == Some(patch.revision_id) {
+            continue;
+        }
+        previous = Some(patch.revision_id);
+        match patch.operation.apply_to(content.clone()) {
+            Ok(next) => content = next,
+            Err(e) => {
+                issues.push(Issue::UnreplayablePatch {
+                    revision_id: patch.revision_id,
+                    error: e.to_string(),
+                });
+                break;
+            }
+        }
+        if wanted.contains(&patch.revision_id) {
+            replayed.insert(patch.revision_id, content.clone());
+        }
+    }
+
+    for snapshot in snapshots {
+        if replayed.get(&snapshot.revision_id) != Some(&snapshot.content) {
+            issues.push(Issue::SnapshotMismatch {
+                revision_id: snapshot.revision_id,
+            });
+        }
+    }
+
+    issues
+}
+
+/// Check all objects, patches and snapshots of a gym and fix the safe cases
+/// if `repair` is set.
+pub async fn check_gym(
+    state: &AppState,
+    gym: &str,
+    repair: bool,
+) -> Result<ConsistencyReport, AppError> {
+    let mut report = ConsistencyReport {
+        repair,
+        checked: 0,
+        findings: Vec::new(),
+    };
+
+    let objects: BTreeSet<ObjectId> =
+        state.db.object_ids(gym).await?.into_iter().collect();
+    let history = state.db.history_object_ids(gym).await?;
+
+    for object_id in objects.union(&history) {
+        report.checked += 1;
+        // snapshots first, a patch stored in between only adds revisions
+        let snapshots = Snapshot::lookup_stored(state, gym, object_id).await?;
+        let patches =
+            Patch::after_revision(state, gym, object_id, ZERO_REV_ID - 1)
+                .await?;
+        let mut finding = |issue, repairable| {
+            report.findings.push(Finding {
+                object_id: object_id.clone(),
+                issue,
+                repairable,
+                repaired: repair && repairable,
+            });
+        };
+
+        if !objects.contains(object_id) {
+            let issue = Issue::MissingObject {
+                patches: patches.len(),
+                snapshots: snapshots.len(),
+            };
+            finding(issue, false);
+            continue;
+        }
+
+        if patches.is_empty() {
+            let object =
+                Object::lookup_including_deleted(state, gym, object_id).await?;
+            if !object.deleted {
+                let repairable =
+                    Utc::now() - object.created_at > EMPTY_OBJECT_GRACE;
+                if repair && repairable {
+                    object.delete(state, gym).await?;
+                }
+                finding(Issue::EmptyObject, repairable);
+            }
+        }
+
+        let issues = check_history(&patches, &snapshots);
+        // with a broken history the snapshots might be the better record
+        let history_intact = issues
+            .iter()
+            .all(|issue| matches!(issue, Issue::SnapshotMismatch { .. }));
+        let mismatched: Vec<RevId> = issues
+            .iter()
+            .filter_map(|issue| match issue {
+                Issue::SnapshotMismatch { revision_id } => Some(*revision_id),
+                _ => None,
+            })
+            .collect();
+        if repair && history_intact && !mismatched.is_empty() {
+            state
+                .db
+                .delete_snapshots(gym, object_id, &mismatched)
+                .await?;
+            state.snapshots.evict(gym, object_id);
+        }
+        for issue in issues {
+            finding(issue, history_intact);
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use otp::Operation;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::types::ObjectType;
+
+    /// name, patches, snapshots and the expected issues
+    type Case = (&'static str, Vec<Patch>, Vec<Snapshot>, Vec<Issue>);
+
+    fn patch(rev_id: RevId, value: i64) -> Patch {
+        Patch::new_revision(
+            rev_id,
+            String::from("obj"),
+            String::new(),
+            Operation::new_set("x", json!(value)),
+        )
+    }
+
+    fn snapshot(rev_id: RevId, content: Value) -> Snapshot {
+        Snapshot {
+            object_id: String::from("obj"),
+            revision_id: rev_id,
+            content,
+        }
+    }
+
+    #[test]
+    fn history_issues() {
+        let root = Patch::new(String::from("obj"), String::new(), &json!({}));
+        let cases: Vec<Case> = vec![
+            (
+                "intact",
+                vec![root.clone(), patch(1, 1), patch(2, 2)],
+                vec![snapshot(1, json!({"x": 1}))],
+                vec![],
+            ),
+            (
+                "gap",
+                vec![root.clone(), patch(3, 3)],
+                vec![],
+                vec![Issue::RevisionGap {
+                    missing: vec![1, 2],
+                }],
+            ),
+            (
+                "duplicate",
+                vec![root.clone(), patch(1, 1), patch(1, 2)],
+                vec![snapshot(1, json!({"x": 1}))],
+                vec![Issue::DuplicateRevision {
+                    revision_id: 1,
+                    count: 2,
+                }],
+            ),
+            (
+                "mismatch",
+                vec![root.clone(), patch(1, 1)],
+                vec![snapshot(1, json!({"x": 2})), snapshot(4, json!({}))],
+                vec![
+                    Issue::SnapshotMismatch { revision_id: 1 },
+                    Issue::SnapshotMismatch { revision_id: 4 },
+                ],
+            ),
+        ];
+
+        for (name, patches, snapshots, expected) in cases {
+            assert_eq!(expected, check_history(&patches, &snapshots), "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn repair_safe_cases() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let obj = Object::from_value(
+            &state,
+            gym,
+            String::from("author"),
+            ObjectType::Passport,
+            &json!({"x": 0}),
+        )
+        .await
+        .unwrap();
+        let bad = Snapshot {
+            object_id: obj.id.clone(),
+            revision_id: 0,
+            content: json!({"x": 1}),
+        };
+        state.db.store_snapshot(gym, &bad).await.unwrap();
+        // patches of an object which was never created
+        let mut orphan = patch(0, 0);
+        orphan.object_id = String::from("orphan");
+        state.db.store_patch(gym, &orphan).await.unwrap();
+
+        let kinds = |report: &ConsistencyReport| -> Vec<(Issue, bool)> {
+            report
+                .findings
+                .iter()
+                .map(|f| (f.issue.clone(), f.repairable))
+                .collect()
+        };
+        let mut expected = vec![
+            (Issue::SnapshotMismatch { revision_id: 0 }, true),
+            (
+                Issue::MissingObject {
+                    patches: 1,
+                    snapshots: 0,
+                },
+                false,
+            ),
+        ];
+        if obj.id > orphan.object_id {
+            expected.reverse();
+        }
+
+        let report = check_gym(&state, gym, false).await.unwrap();
+        assert_eq!(2, report.checked);
+        assert_eq!(expected, kinds(&report));
+
+        let report = check_gym(&state, gym, true).await.unwrap();
+        assert_eq!(expected, kinds(&report));
+        let snapshot =
+            Snapshot::lookup_latest(&state, gym, &obj.id).await.unwrap();
+        assert_eq!(json!({"x": 0}), snapshot.content);
+
+        // only the missing object is left
+        let report = check_gym(&state, gym, true).await.unwrap();
+        assert_eq!(1, report.findings.len());
+    }
+}
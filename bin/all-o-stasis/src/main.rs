@@ -1,4 +1,10 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     http::StatusCode,
@@ -8,24 +14,79 @@ use firestore::{FirestoreDb, FirestoreDbOptions, errors::FirestoreError};
 use serde_json::json;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::routes::app;
+use crate::{
+    backend::{FirestoreStorage, MemoryStorage, SqliteStorage, Storage},
+    cache::SnapshotCache,
+    config::{ServerConfig, StorageKind},
+    passport::Session,
+    rate_limit::RateLimiter,
+    routes::app,
+    types::{Gym, GymRegistry, SnapshotPolicy},
+};
 
+mod archive;
+mod backend;
+mod cache;
+mod compaction;
+mod config;
+mod consistency;
 mod passport;
+mod policy;
+mod rate_limit;
+mod rebuild;
 mod routes;
 mod storage;
+mod tokens;
 mod types;
 mod word_list;
 mod ws;
-use otp::OtError;
-
-pub fn config_env_var(name: &str) -> Result<String, String> {
-    std::env::var(name).map_err(|e| format!("{name}: {e}"))
-}
+use otp::{ObjectId, OtError, RevId};
 
 #[derive(Clone)]
 struct AppState {
-    pub db: Arc<FirestoreDb>,
-    pub api_host: String,
+    pub db: Arc<dyn Storage>,
+    pub config: Arc<ServerConfig>,
+    pub snapshot_policy: SnapshotPolicy,
+    pub snapshots: Arc<SnapshotCache>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub gyms: Arc<GymRegistry>,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// the state of a local server around db, fields can be overridden
+    pub fn for_tests(db: Arc<dyn Storage>) -> Self {
+        Self {
+            db,
+            config: Arc::new(ServerConfig::default()),
+            snapshot_policy: SnapshotPolicy::default(),
+            snapshots: Arc::new(SnapshotCache::new(
+                std::num::NonZeroUsize::new(100).unwrap(),
+            )),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            gyms: Arc::new(GymRegistry::default()),
+        }
+    }
+
+    /// [`AppState::for_tests`] around a new in-memory storage
+    pub fn in_memory() -> Self {
+        Self::for_tests(Arc::new(backend::MemoryStorage::new()))
+    }
+}
+
+/// the content of a boulder which is valid in every view
+#[cfg(test)]
+pub fn test_boulder() -> serde_json::Value {
+    json!({
+        "setter": ["setter"],
+        "sector": "kurswand",
+        "grade": "blue",
+        "gradeNr": 42,
+        "setDate": 0,
+        "removed": 0,
+        "isDraft": 0,
+        "name": "",
+    })
 }
 
 // The kinds of errors we can hit in our application.
@@ -35,6 +96,16 @@ pub enum AppError {
     Ot(OtError),
     // firestore db errors
     Firestore(FirestoreError),
+    // sqlite db errors
+    Sqlite(rusqlite::Error),
+    // another patch with this revision was stored concurrently
+    RevisionConflict(ObjectId, RevId),
+    // the object was deleted
+    Gone(ObjectId),
+    // the gym is not in the registry
+    UnknownGym(String),
+    // the gym is in the registry but suspended
+    GymSuspended(String),
     // query error
     Query(String), // TODO split and more meaningful name
     // unable to parse json content into type
@@ -45,6 +116,8 @@ pub enum AppError {
     NoSession(),
     NotAuthorized(),
     Passport(String),
+    // too many requests of a client, see [`rate_limit`]
+    RateLimited(),
 }
 
 impl From<FirestoreError> for AppError {
@@ -53,15 +126,22 @@ impl From<FirestoreError> for AppError {
     }
 }
 
+impl From<rusqlite::Error> for AppError {
+    fn from(inner: rusqlite::Error) -> Self {
+        AppError::Sqlite(inner)
+    }
+}
+
 impl From<OtError> for AppError {
     fn from(inner: OtError) -> Self {
         AppError::Ot(inner)
     }
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+impl AppError {
+    /// the status code and message clients get for this error
+    pub fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
             AppError::Firestore(FirestoreError::SystemError(_)) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "firestore system error".to_string(),
@@ -100,6 +180,23 @@ impl IntoResponse for AppError {
             AppError::Firestore(FirestoreError::CacheError(_)) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "cache error".to_string())
             }
+            AppError::Sqlite(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("database error: {e}"),
+            ),
+            AppError::RevisionConflict(object_id, rev_id) => (
+                StatusCode::CONFLICT,
+                format!("revision {rev_id} of {object_id} already exists"),
+            ),
+            AppError::Gone(object_id) => {
+                (StatusCode::GONE, format!("object {object_id} was deleted"))
+            }
+            AppError::UnknownGym(gym) => {
+                (StatusCode::NOT_FOUND, format!("gym {gym} not found"))
+            }
+            AppError::GymSuspended(gym) => {
+                (StatusCode::FORBIDDEN, format!("gym {gym} is suspended"))
+            }
             AppError::Ot(e) => {
                 (StatusCode::NOT_FOUND, format!("OT failure: {e}"))
             }
@@ -123,8 +220,17 @@ impl IntoResponse for AppError {
             AppError::Passport(e) => {
                 (StatusCode::BAD_REQUEST, format!("passport failure: {e}"))
             }
-        };
+            AppError::RateLimited() => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "too many requests, try again later".to_string(),
+            ),
+        }
+    }
+}
 
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let (status, error_message) = self.status_and_message();
         let body = Json(json!({
             "error": error_message,
         }));
@@ -133,6 +239,9 @@ impl IntoResponse for AppError {
     }
 }
 
+const USAGE: &str = "usage: all-o-stasis [export <gym> <file> | import <file> \
+     [gym] | migrate-sessions]";
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     tracing_subscriber::registry()
@@ -150,29 +259,112 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let gcp_project_id = config_env_var("PROJECT_ID")?;
-    // TODO prod should also be a named database
-    // TODO better configuration
-    let (options, api_host) = match config_env_var("FIRESTORE_DATABASE_ID") {
-        Ok(name) => (
-            FirestoreDbOptions::new(gcp_project_id.to_string())
-                .with_database_id(name.to_string())
-                .with_max_retries(5),
-            "https://apiv2-dev.boulderhalle.app",
-        ),
-        Err(_) => (
-            FirestoreDbOptions::new(gcp_project_id.to_string()),
-            "https://apiv2.boulderhalle.app",
-        ),
-    };
+    let config = ServerConfig::load()?;
+    tracing::info!("running in {:?} environment", config.environment);
+
+    let db: Arc<dyn Storage> = match config.storage {
+        StorageKind::Memory => {
+            // keep everything in memory for local development
+            tracing::info!("using in-memory storage, nothing is persisted");
+            Arc::new(MemoryStorage::new())
+        }
+        StorageKind::Sqlite => {
+            let path = &config.sqlite_path;
+            tracing::info!("using sqlite storage at {path}");
+            Arc::new(SqliteStorage::open(path).map_err(|e| format!("{e:?}"))?)
+        }
+        StorageKind::Firestore => {
+            let project_id =
+                config.project_id.clone().ok_or("PROJECT_ID: not set")?;
+            // TODO prod should also be a named database
+            let options = match &config.firestore_database_id {
+                Some(name) => FirestoreDbOptions::new(project_id)
+                    .with_database_id(name.clone())
+                    .with_max_retries(5),
+                None => FirestoreDbOptions::new(project_id),
+            };
 
+            let db = FirestoreDb::with_options(options).await?;
+            tracing::debug!("connected to firestore");
+            Arc::new(FirestoreStorage::new(db))
+        }
+    };
     let state = AppState {
-        db: Arc::new(FirestoreDb::with_options(options).await?),
-        api_host: api_host.to_string(),
+        db,
+        snapshot_policy: config.snapshot_policy.clone(),
+        snapshots: Arc::new(SnapshotCache::new(config.snapshot_cache_size)),
+        rate_limiter: Arc::new(RateLimiter::default()),
+        gyms: Arc::new(GymRegistry::default()),
+        config: Arc::new(config),
     };
-    tracing::debug!("connected to firestore");
+    state
+        .gyms
+        .reload(state.db.as_ref())
+        .await
+        .map_err(|e| e.status_and_message().1)?;
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => {}
+        ["export", gym, path] => {
+            let out = BufWriter::new(File::create(path)?);
+            let counts = archive::export_gym(&state, gym, out)
+                .await
+                .map_err(|e| e.status_and_message().1)?;
+            tracing::info!("exported {gym} to {path}: {counts}");
+            return Ok(());
+        }
+        ["import", path, gym @ ..] if gym.len() <= 1 => {
+            let input = BufReader::new(File::open(path)?);
+            let counts =
+                archive::import_gym(&state, gym.first().copied(), input)
+                    .await
+                    .map_err(|e| e.status_and_message().1)?;
+            tracing::info!("imported {path}: {counts}");
+            return Ok(());
+        }
+        // sessions from before session ids were hashed, once per deployment
+        ["migrate-sessions"] => {
+            let migrated = Session::migrate(&state)
+                .await
+                .map_err(|e| e.status_and_message().1)?;
+            tracing::info!("hashed the ids of {migrated} sessions");
+            return Ok(());
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    // serve the gyms of deployments from before the registry
+    Gym::seed_registry(&state)
+        .await
+        .map_err(|e| e.status_and_message().1)?;
+
+    let migrated = Gym::migrate_frontend_urls(&state)
+        .await
+        .map_err(|e| e.status_and_message().1)?;
+    if migrated > 0 {
+        tracing::info!("set the frontend URL of {migrated} gyms");
+    }
+
+    // see gyms registered by other instances
+    tokio::spawn(GymRegistry::follow(state.clone()));
+
+    // prune snapshots which are no longer needed with the current policy
+    let compaction_interval = state.config.compaction_interval_secs;
+    if compaction_interval > 0 {
+        tokio::spawn(compaction::run(
+            state.clone(),
+            Duration::from_secs(compaction_interval),
+        ));
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await?;
+    let bind_address = state.config.bind_address;
+    let listener = tokio::net::TcpListener::bind(bind_address).await?;
     axum::serve(
         listener,
         app(state).into_make_service_with_connect_info::<SocketAddr>(),
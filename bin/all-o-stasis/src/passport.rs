@@ -1,24 +1,31 @@
-use std::fmt;
+use std::{fmt, net::SocketAddr};
 
 use axum::{
     Json, Router,
-    extract::{Path, Query, State},
-    response::{IntoResponse, Redirect},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
     routing::{get, post},
 };
-use axum_extra::extract::CookieJar;
-use chrono::{DateTime, Utc};
+use axum_extra::{TypedHeader, extract::CookieJar, headers::UserAgent};
+use chrono::{DateTime, TimeDelta, Utc};
 use cookie::{Cookie, SameSite, time::Duration};
-use firestore::{FirestoreResult, path_camel_case};
-use futures::{TryStreamExt, stream::BoxStream};
-use otp::{ObjectId, Operation};
+use otp::{ObjectId, Operation, RevId};
 use rand::RngExt;
 use serde::{Deserialize, Serialize};
+use tokio::{sync::broadcast::error::RecvError, time::Instant};
 
 use crate::{
     AppError, AppState,
+    config::ServerConfig,
+    rate_limit::Limit,
+    routes::KnownGym,
     storage::apply_object_updates,
-    types::{Account, AccountRole, AccountsView, Object, ObjectType, Snapshot},
+    tokens::{keyed_hash, verify},
+    types::{
+        Account, AccountRole, AccountsView, Gym, Locale, Object, ObjectType,
+        Patch, Snapshot,
+    },
     word_list::make_security_code,
 };
 
@@ -66,7 +73,12 @@ mod maileroo {
     }
 
     impl Email {
-        pub fn new(to: String, subject: String, body: String) -> Self {
+        pub fn new(
+            to: String,
+            sender_name: String,
+            subject: String,
+            body: String,
+        ) -> Self {
             Self {
                 data: EmailData {
                     to: vec![EmailAddress {
@@ -75,7 +87,7 @@ mod maileroo {
                     }],
                     from: EmailAddress {
                         address: String::from("auth@boulderhalle.app"),
-                        display_name: None,
+                        display_name: Some(sender_name),
                     },
                     subject,
                     html: body,
@@ -129,25 +141,58 @@ mod maileroo {
 
 pub type SessionId = String;
 
-pub(crate) async fn author_from_session(
-    state: &AppState,
-    gym: &String,
-    session_id: &Cookie<'static>,
-) -> Result<String, AppError> {
-    let session_id = session_id.value().to_owned();
-    let session = Session::lookup(state, gym, session_id).await?;
-    Ok(session.obj_id)
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct Session {
+    /// the keyed hash of the session id in the cookie, see [`tokens`]
     #[serde(alias = "_firestore_id")]
     pub id: Option<SessionId>,
     pub obj_id: ObjectId,
-    #[serde(alias = "_firestore_created")]
     pub created_at: Option<DateTime<Utc>>,
     pub last_accessed_at: DateTime<Utc>,
+    /// of the browser the session was created in
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    /// whether `id` is hashed, see [`Session::migrate`] for sessions from before
+    #[serde(default)]
+    pub hashed: bool,
+}
+
+/// `last_accessed_at` is stored at most this often, not on every request
+const SESSION_TOUCH_INTERVAL: TimeDelta = TimeDelta::minutes(1);
+
+/// a duration in seconds from the config, saturating
+fn secs(s: u64) -> TimeDelta {
+    i64::try_from(s)
+        .ok()
+        .and_then(TimeDelta::try_seconds)
+        .unwrap_or(TimeDelta::MAX)
+}
+
+/// how long browsers keep the session cookie
+pub(crate) fn session_cookie_max_age(config: &ServerConfig) -> Duration {
+    Duration::seconds(
+        i64::try_from(config.session_max_age_secs).unwrap_or(i64::MAX),
+    )
+}
+
+/// The address of the client. Behind proxies it is the forwarded address
+/// which the outermost of the trusted `hops` appended, earlier ones are set
+/// by the client. Requests which did not pass all of them use their peer.
+pub(crate) fn client_ip(
+    hops: usize,
+    headers: &HeaderMap,
+    addr: SocketAddr,
+) -> String {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').nth(hops.checked_sub(1)?))
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .unwrap_or_else(|| addr.ip().to_string())
 }
 
 impl fmt::Display for Session {
@@ -162,87 +207,154 @@ impl fmt::Display for Session {
 }
 
 impl Session {
-    const COLLECTION: &str = "sessions";
+    pub fn new(
+        obj_id: ObjectId,
+        user_agent: Option<String>,
+        ip: Option<String>,
+    ) -> Self {
+        Self {
+            id: None,
+            obj_id,
+            created_at: None,
+            last_accessed_at: Utc::now(),
+            user_agent,
+            ip,
+            hashed: false,
+        }
+    }
 
-    pub async fn lookup(
+    /// whether the session is too old or was not used for too long at `now`
+    pub fn is_expired(
+        &self,
+        config: &ServerConfig,
+        now: DateTime<Utc>,
+    ) -> bool {
+        let created_at = self.created_at.unwrap_or(self.last_accessed_at);
+        let too_old = now - created_at > secs(config.session_max_age_secs);
+        let idle = config.session_idle_secs > 0
+            && now - self.last_accessed_at > secs(config.session_idle_secs);
+        too_old || idle
+    }
+
+    /// Refers to the session in listings, the hash does not allow to use
+    /// the session.
+    pub fn handle(&self) -> Option<String> {
+        self.id.clone().filter(|_| self.hashed)
+    }
+
+    /// store a new session, returns the session id for the cookie
+    pub async fn create(
+        self,
         state: &AppState,
-        gym: &String,
-        object_id: ObjectId,
-    ) -> Result<Self, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        state
-            .db
-            .fluent()
-            .select()
-            .by_id_in(Self::COLLECTION)
-            .parent(&parent_path)
-            .obj()
-            .one(&object_id)
-            .await?
-            .ok_or(AppError::NoSession())
+        gym: &str,
+    ) -> Result<(SessionId, Self), AppError> {
+        let session_id = new_id(80);
+        let hash = keyed_hash(&state.config.token_key, &session_id);
+        let session = Self {
+            hashed: true,
+            ..self
+        };
+        let session = state.db.store_session(gym, &hash, &session).await?;
+        Ok((session_id, session))
     }
 
-    pub async fn store(
-        &self,
+    /// The session with the id of a cookie if it has not expired, expired
+    /// sessions are deleted. Using a session extends its idle expiry.
+    pub async fn lookup_active(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         session_id: &str,
-    ) -> Result<Session, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let p: Option<Session> = state
+    ) -> Result<Self, AppError> {
+        let hash = keyed_hash(&state.config.token_key, session_id);
+        let session = state
             .db
-            .fluent()
-            .update()
-            .in_col(Session::COLLECTION)
-            .document_id(session_id)
-            .parent(&parent_path)
-            .object(self)
-            .execute()
-            .await?;
+            .lookup_session(gym, &hash)
+            .await?
+            .ok_or(AppError::NoSession())?;
+        let now = Utc::now();
+        if session.is_expired(&state.config, now) {
+            state.db.delete_session(gym, &hash).await?;
+            return Err(AppError::NoSession());
+        }
 
-        match p {
-            Some(p) => {
-                tracing::debug!("storing session: {p}");
-                Ok(p)
-            }
-            None => {
-                tracing::warn!(
-                    "failed to update session: {self} (no such object exists"
-                );
-                Err(AppError::NoSession())
-            }
+        if now - session.last_accessed_at < SESSION_TOUCH_INTERVAL {
+            return Ok(session);
         }
+        let session = Self {
+            last_accessed_at: now,
+            ..session
+        };
+        state.db.store_session(gym, &hash, &session).await
     }
 
-    pub async fn delete(
+    /// the sessions of an account which have not expired
+    pub async fn of_account(
         state: &AppState,
-        gym: &String,
-        session_id: &str,
-    ) -> Result<(), AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        state
+        gym: &str,
+        account_id: &ObjectId,
+    ) -> Result<Vec<Self>, AppError> {
+        let now = Utc::now();
+        Ok(state
             .db
-            .fluent()
-            .delete()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .document_id(session_id)
-            .execute()
-            .await?;
-        Ok(())
+            .account_sessions(gym, account_id)
+            .await?
+            .into_iter()
+            .filter(|session| !session.is_expired(&state.config, now))
+            .collect())
+    }
+
+    pub async fn revoke(
+        &self,
+        state: &AppState,
+        gym: &str,
+    ) -> Result<(), AppError> {
+        let id = self
+            .id
+            .as_ref()
+            .ok_or(AppError::Internal("session missing id".to_string()))?;
+        state.db.delete_session(gym, id).await
+    }
+
+    /// Store the sessions of registered gyms which still use the session id
+    /// as their id under its hash. Returns the number of migrated sessions.
+    pub async fn migrate(state: &AppState) -> Result<usize, AppError> {
+        let mut migrated = 0;
+        for gym in state.db.registered_gyms().await? {
+            for session in state.db.sessions(&gym.name).await? {
+                let Some(session_id) = session.id.clone() else {
+                    continue;
+                };
+                if session.hashed {
+                    continue;
+                }
+                let hash = keyed_hash(&state.config.token_key, &session_id);
+                let hashed = Self {
+                    hashed: true,
+                    ..session
+                };
+                state.db.store_session(&gym.name, &hash, &hashed).await?;
+                state.db.delete_session(&gym.name, &session_id).await?;
+                migrated += 1;
+            }
+        }
+        Ok(migrated)
     }
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Passport {
-    account_id: ObjectId,
+    /// the account of the email, set on confirmation if it had none yet
+    account_id: Option<ObjectId>,
+    /// empty for passports from before accounts were created on confirmation
+    #[serde(default)]
+    email: String,
     security_code: String,
     confirmation_token: String,
     validity: PassportValidity,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 enum PassportValidity {
     Unconfirmed,
@@ -250,6 +362,231 @@ enum PassportValidity {
     Expired,
 }
 
+impl Passport {
+    /// The passport with its revision and expiry. Unconfirmed passports are
+    /// marked as expired once `PASSPORT_TTL_SECS` passed since their creation.
+    async fn lookup(
+        state: &AppState,
+        gym: &str,
+        passport_id: &ObjectId,
+    ) -> Result<(Self, RevId, DateTime<Utc>), AppError> {
+        let object = Object::lookup(state, gym, passport_id).await?;
+        let snapshot = Snapshot::lookup_latest(state, gym, passport_id).await?;
+        let mut passport: Self = serde_json::from_value(snapshot.content).or(
+            Err(AppError::ParseError(
+                "failed to parse object into Passport".to_string(),
+            )),
+        )?;
+
+        let expires_at =
+            object.created_at + secs(state.config.passport_ttl_secs);
+        if passport.validity == PassportValidity::Unconfirmed
+            && Utc::now() >= expires_at
+        {
+            set_validity(
+                state,
+                gym,
+                passport_id,
+                snapshot.revision_id,
+                PassportValidity::Expired,
+            )
+            .await?;
+            passport.validity = PassportValidity::Expired;
+        }
+        Ok((passport, snapshot.revision_id, expires_at))
+    }
+}
+
+async fn set_validity(
+    state: &AppState,
+    gym: &str,
+    passport_id: &ObjectId,
+    revision_id: RevId,
+    validity: PassportValidity,
+) -> Result<(), AppError> {
+    let op = set_op("validity", validity)?;
+    update_passport(state, gym, passport_id, revision_id, vec![op]).await
+}
+
+fn set_op(path: &str, value: impl Serialize) -> Result<Operation, AppError> {
+    let value = serde_json::to_value(value)
+        .map_err(|e| AppError::Internal(format!("serialising {path}: {e}")))?;
+    Ok(Operation::try_new_set(path, Some(value))?)
+}
+
+async fn update_passport(
+    state: &AppState,
+    gym: &str,
+    passport_id: &ObjectId,
+    revision_id: RevId,
+    operations: Vec<Operation>,
+) -> Result<(), AppError> {
+    let _ = apply_object_updates(
+        state,
+        gym,
+        passport_id.clone(),
+        revision_id,
+        String::from(""), // TODO fine?
+        operations,
+    )
+    .await?;
+    Ok(())
+}
+
+/// The account with the email, created if there is none. Accounts are only
+/// created once a login is confirmed, so unconfirmed logins leave no trace.
+async fn account_with_email(
+    state: &AppState,
+    gym: &str,
+    email: &str,
+) -> Result<ObjectId, AppError> {
+    if let Some(account) =
+        AccountsView::with_email(state, gym.to_string(), email.to_string())
+            .await?
+    {
+        return account.id.ok_or(AppError::Internal(
+            "existing account has no id".to_string(),
+        ));
+    }
+
+    let account = Account {
+        id: None,
+        email: email.to_string(),
+        role: AccountRole::User,
+        login: "to be removed".to_string(),
+        name: None,
+    };
+    let value = serde_json::to_value(account)
+        .map_err(|e| AppError::Internal(format!("serialising account: {e}")))?;
+    // TODO: author? root?
+    let obj = Object::from_value(
+        state,
+        gym,
+        String::from(""),
+        ObjectType::Account,
+        &value,
+    )
+    .await?;
+    Ok(obj.id)
+}
+
+/// Mark the passport as valid in the revision after revision_id. Unlike other
+/// updates this is not rebased: if another request stored that revision
+/// first, the passport was confirmed (or expired) already.
+async fn claim_passport(
+    state: &AppState,
+    gym: &str,
+    passport_id: &ObjectId,
+    revision_id: RevId,
+) -> Result<(), AppError> {
+    let patch = Patch::new_revision(
+        revision_id + 1,
+        passport_id.clone(),
+        String::new(),
+        set_op("validity", PassportValidity::Valid)?,
+    );
+    match patch.store(state, gym).await {
+        Ok(_) => Ok(()),
+        Err(AppError::RevisionConflict(..)) => Err(AppError::NotAuthorized()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Confirm the passport if the token is the one mailed for it, returns the
+/// account it confirms, which is created if needed. Confirmation links can
+/// only be used once and until the passport expires.
+async fn confirm(
+    state: &AppState,
+    gym: &str,
+    passport_id: &ObjectId,
+    confirmation_token: &str,
+) -> Result<ObjectId, AppError> {
+    let (passport, revision_id, _) =
+        Passport::lookup(state, gym, passport_id).await?;
+    if passport.validity != PassportValidity::Unconfirmed
+        || !verify(
+            &state.config.token_key,
+            confirmation_token,
+            &passport.confirmation_token,
+        )
+    {
+        return Err(AppError::NotAuthorized());
+    }
+
+    // only the first of concurrent confirmations, e.g. by a mail scanner
+    // opening the link as well, may create an account
+    claim_passport(state, gym, passport_id, revision_id).await?;
+    match passport.account_id {
+        Some(account_id) => Ok(account_id),
+        None => {
+            let account_id =
+                account_with_email(state, gym, &passport.email).await?;
+            let operations = vec![set_op("accountId", &account_id)?];
+            update_passport(
+                state,
+                gym,
+                passport_id,
+                revision_id + 1,
+                operations,
+            )
+            .await?;
+            Ok(account_id)
+        }
+    }
+}
+
+/// Wait until the passport is confirmed, returns its account and revision.
+/// Patches of the passport wake the waiter, `None` if it is still
+/// unconfirmed at `until`.
+async fn await_confirmation(
+    state: &AppState,
+    gym: &str,
+    passport_id: &ObjectId,
+    until: Instant,
+) -> Result<Option<(ObjectId, RevId)>, AppError> {
+    loop {
+        // subscribe first to not miss a confirmation right after the lookup
+        let mut patched = state.snapshots.subscribe(state, gym).await?;
+        let (passport, revision_id, expires_at) =
+            Passport::lookup(state, gym, passport_id).await?;
+        let expiry = match (passport.validity, passport.account_id) {
+            (PassportValidity::Valid, Some(account_id)) => {
+                return Ok(Some((account_id, revision_id)));
+            }
+            (PassportValidity::Expired, _) => {
+                return Err(AppError::NotAuthorized());
+            }
+            // confirmed, the account is set next
+            (PassportValidity::Valid, None) => until,
+            // look again once the passport expires
+            (PassportValidity::Unconfirmed, _) => {
+                Instant::now()
+                    + (expires_at - Utc::now()).to_std().unwrap_or_default()
+            }
+        };
+        let wake = async {
+            loop {
+                match patched.recv().await {
+                    Ok((g, object_id)) => {
+                        if g == gym && object_id == *passport_id {
+                            return;
+                        }
+                    }
+                    // the confirmation might be among the missed patches
+                    Err(RecvError::Lagged(_)) => return,
+                    // the cache keeps its sender, wait for the timeout
+                    Err(RecvError::Closed) => std::future::pending().await,
+                }
+            }
+        };
+        match tokio::time::timeout_at(expiry.min(until), wake).await {
+            Ok(()) => {}
+            Err(_) if Instant::now() >= until => return Ok(None),
+            Err(_) => {}
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct CreatePassportBody {
@@ -276,6 +613,13 @@ struct AwaitPassportConfirmation {
     passport_id: String,
 }
 
+/// the response while the passport is not confirmed yet
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AwaitPassportResponse {
+    validity: PassportValidity,
+}
+
 pub(crate) fn routes() -> Router<AppState> {
     Router::new()
         .route("/{gym}/login", post(create_passport))
@@ -283,7 +627,7 @@ pub(crate) fn routes() -> Router<AppState> {
         .route("/{gym}/login/verify", get(await_passport_confirmation))
 }
 
-fn new_id(len: usize) -> String {
+pub(crate) fn new_id(len: usize) -> String {
     const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
                              abcdefghijklmnopqrstuvwxyz\
                              0123456789";
@@ -301,73 +645,122 @@ fn new_id(len: usize) -> String {
 
 async fn send_email(
     email: String,
-    api_host: String,
-    api_domain: String,
+    api_host: &str,
+    gym: &Gym,
     passport_id: String,
     security_code: String,
     confirmation_token: String,
 ) -> Result<(), AppError> {
+    let api_domain = &gym.name;
+    let display_name = &gym.display_name;
     let confirmation_url = format!(
         "{api_host}/{api_domain}/login/confirm?passportId={passport_id}&confirmationToken={confirmation_token}",
     );
-    let subject =
-        format!("{api_domain} Login Verification (code: \"{security_code}\")",);
+    let (subject, lines) = match gym.settings.locale {
+        Locale::En => (
+            format!(
+                "{display_name} Login Verification (code: \"{security_code}\")"
+            ),
+            [
+                format!("Verify your email to log on to the {display_name}"),
+                "We have received a login attempt with the following code:"
+                    .to_string(),
+                "complete the login process, please click the URL below:"
+                    .to_string(),
+                "copy and paste this URL into your browser.".to_string(),
+            ],
+        ),
+        Locale::De => (
+            format!(
+                "{display_name} Anmeldung bestätigen (Code: \"{security_code}\")"
+            ),
+            [
+                format!("Bestätige deine E-Mail für die Anmeldung bei {display_name}"),
+                "Wir haben einen Anmeldeversuch mit folgendem Code erhalten:"
+                    .to_string(),
+                "um die Anmeldung abzuschliessen, klicke auf den folgenden Link:"
+                    .to_string(),
+                "oder kopiere ihn in deinen Browser.".to_string(),
+            ],
+        ),
+    };
+    let [title, attempt, instructions, copy] = lines;
     let body = [
-        format!("Verify your email to log on to the {api_domain}"),
+        title,
         "<br/>".to_string(),
-        "We have received a login attempt with the following code:".to_string(),
+        attempt,
         "<br/>".to_string(),
         security_code,
         "<br/>".to_string(),
-        "complete the login process, please click the URL below:".to_string(),
+        instructions,
         "<br/>".to_string(),
         format!("<a href={confirmation_url}>{confirmation_url}</a>"),
         "<br/>".to_string(),
-        "copy and paste this URL into your browser.".to_string(),
+        copy,
     ];
 
-    maileroo::Email::new(email.clone(), subject, body.join("\n")).send()
+    maileroo::Email::new(
+        email.clone(),
+        gym.email_sender_name().to_string(),
+        subject,
+        body.join("\n"),
+    )
+    .send()
 }
 
+/// The limits of logins from the IP for the email of the gym, the ones a
+/// client controls least first. Emails are counted per gym as they have an
+/// account in each.
+fn login_limits(
+    config: &ServerConfig,
+    ip: &str,
+    gym: &str,
+    email: &str,
+) -> [Limit; 3] {
+    let email = email.trim().to_lowercase();
+    [
+        Limit {
+            key: format!("login-ip:{ip}"),
+            max_hits: config.login_limit_per_ip,
+        },
+        Limit {
+            key: format!("login-email:{gym}:{email}"),
+            max_hits: config.login_limit_per_email,
+        },
+        Limit {
+            key: format!("login-gym:{gym}"),
+            max_hits: config.login_limit_per_gym,
+        },
+    ]
+}
+
+/// Mails a confirmation link for a new passport. The response is the same
+/// whether the email has an account or not.
 async fn create_passport(
     State(state): State<AppState>,
-    Path(gym): Path<String>,
+    // the route layer checks the gym as well, but never send mails for
+    // unknown gyms even if the routes change
+    KnownGym(known_gym): KnownGym,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): axum::extract::Json<CreatePassportBody>,
 ) -> Result<Json<CreatePassportResponse>, AppError> {
-    // 1. Lookup account by email. If no such account exists, create a new one
+    let gym = known_gym.name.clone();
+    let ip = client_ip(state.config.trusted_proxy_hops, &headers, addr);
+    let limits = login_limits(&state.config, &ip, &gym, &payload.email);
+    let window = secs(state.config.login_limit_window_secs);
+    state.rate_limiter.check(&limits, window).await?;
+
+    // 1. Lookup account by email, new accounts are created on confirmation
     let account =
         AccountsView::with_email(&state, gym.clone(), payload.email.clone())
             .await?;
-    let maybe_account_id: Result<ObjectId, AppError> = match account {
-        Some(account) => account.id.clone().ok_or(AppError::Internal(
+    let account_id = match account {
+        Some(account) => Some(account.id.ok_or(AppError::Internal(
             "existing account has no id".to_string(),
-        )),
-        None => {
-            let account = Account {
-                id: None,
-                email: payload.email.clone(),
-                role: AccountRole::User,
-                login: "to be removed".to_string(),
-                name: None,
-            };
-            let value = serde_json::to_value(account).map_err(|e| {
-                AppError::Internal(format!("serialising account: {e}"))
-            })?;
-            // TODO: author? root?
-            let obj = Object::from_value(
-                &state,
-                &gym,
-                String::from(""),
-                ObjectType::Account,
-                &value,
-            )
-            .await?;
-            Ok(obj.id.clone())
-        }
+        ))?),
+        None => None,
     };
-    let account_id = maybe_account_id.or(Err(AppError::Query(
-        "create_passport: failed to create/get account id".to_string(),
-    )))?;
 
     // 2. Create a new Passport object.
     let security_code = make_security_code().ok_or(AppError::Internal(
@@ -377,17 +770,22 @@ async fn create_passport(
 
     let passport = Passport {
         account_id: account_id.clone(),
+        email: payload.email.clone(),
         security_code: security_code.clone(),
-        confirmation_token: confirmation_token.clone(),
+        confirmation_token: keyed_hash(
+            &state.config.token_key,
+            &confirmation_token,
+        ),
         validity: PassportValidity::Unconfirmed,
     };
     let value = serde_json::to_value(passport).map_err(|e| {
         AppError::Internal(format!("serialising passport: {e}"))
     })?;
+    // logins for new accounts are made by the server
     let obj = Object::from_value(
         &state,
         &gym,
-        account_id,
+        account_id.unwrap_or_default(),
         ObjectType::Passport,
         &value,
     )
@@ -398,8 +796,8 @@ async fn create_passport(
     // 3. Send email
     send_email(
         payload.email,
-        state.api_host,
-        gym.clone(),
+        &state.config.api_host,
+        &known_gym,
         passport_id.clone(),
         security_code.clone(),
         confirmation_token.clone(),
@@ -415,155 +813,257 @@ async fn create_passport(
 
 async fn confirm_passport(
     State(state): State<AppState>,
-    Path(gym): Path<String>,
+    KnownGym(known_gym): KnownGym,
     Query(pport): Query<ConfirmPassport>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     jar: CookieJar,
 ) -> Result<impl IntoResponse, AppError> {
-    let snapshot =
-        Snapshot::lookup_latest(&state, &gym, &pport.passport_id.clone())
+    let gym = known_gym.name.clone();
+    let account_id =
+        confirm(&state, &gym, &pport.passport_id, &pport.confirmation_token)
             .await?;
-    let passport: Passport = serde_json::from_value(snapshot.content).or(
-        Err(AppError::ParseError(
-            "failed to parse object into Passport".to_string(),
-        )),
-    )?;
-
-    if pport.confirmation_token != passport.confirmation_token {
-        Err(AppError::NotAuthorized())
-    } else {
-        // create a new session for the account in the Passport object
-        let session = Session {
-            id: None,
-            obj_id: passport.account_id,
-            created_at: None,
-            last_accessed_at: chrono::offset::Utc::now(),
-        }
-        .store(&state, &gym, &new_id(80))
-        .await?;
 
-        // mark as valid
-        let validity =
-            serde_json::to_value(&PassportValidity::Valid).map_err(|e| {
-                AppError::Internal(format!("serialising validity: {e}"))
-            })?;
-        let op = Operation::try_new_set("validity", Some(validity))?;
-        let _ = apply_object_updates(
-            &state,
-            &gym,
-            pport.passport_id,
-            snapshot.revision_id,
-            String::from(""), // TODO fine?
-            [op].to_vec(),
-        )
-        .await?;
-
-        let session_id = session.id.ok_or(AppError::Internal(
-            "session missing id after store".to_string(),
-        ))?;
-        let cookie = Cookie::build(("session", session_id))
-            .path("/")
-            .max_age(Duration::weeks(52))
-            .secure(true) // TODO not sure about this
-            .same_site(SameSite::None)
-            .http_only(true);
-
-        let redirect_host = if state.api_host.contains("dev") {
-            String::from("https://dev.boulderhalle.app")
-        } else {
-            // NOTE for now just map gym => https://gym.boulderhalle.app/email-confirmed
-            if gym == "leutsch" {
-                format!("https://minimum-{gym}.boulderhalle.app")
-            } else {
-                format!("https://{gym}.boulderhalle.app")
-            }
-        };
-        Ok((
-            jar.add(cookie),
-            Redirect::permanent(
-                format!("{redirect_host}/email-confirmed").as_str(),
-            ),
-        ))
-    }
+    // create a new session for the account in the Passport object
+    let (session_id, _) = Session::new(
+        account_id,
+        user_agent.map(|TypedHeader(user_agent)| user_agent.to_string()),
+        Some(client_ip(state.config.trusted_proxy_hops, &headers, addr)),
+    )
+    .create(&state, &gym)
+    .await?;
+
+    let cookie = Cookie::build(("session", session_id))
+        .path("/")
+        .max_age(session_cookie_max_age(&state.config))
+        .secure(true) // TODO not sure about this
+        .same_site(SameSite::None)
+        .http_only(true);
+
+    let redirect_host = known_gym.frontend_url(&state.config);
+    Ok((
+        jar.add(cookie),
+        Redirect::permanent(
+            format!("{redirect_host}/email-confirmed").as_str(),
+        ),
+    ))
 }
 
+/// Long-polls for the confirmation of the passport for `PASSPORT_POLL_SECS`,
+/// responds with `202 Accepted` if it is still unconfirmed.
 async fn await_passport_confirmation(
     State(state): State<AppState>,
     Path(gym): Path<String>,
     Query(pport): Query<AwaitPassportConfirmation>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     jar: CookieJar,
-) -> Result<impl IntoResponse, AppError> {
-    let (account_id, revision_id) = loop {
-        let snapshot =
-            Snapshot::lookup_latest(&state, &gym, &pport.passport_id.clone())
-                .await?;
-        let passport: Passport = serde_json::from_value(snapshot.content).or(
-            Err(AppError::ParseError(
-                "failed to parse object into Passport".to_string(),
-            )),
-        )?;
-
-        match passport.validity {
-            PassportValidity::Valid => {
-                break (passport.account_id, snapshot.revision_id);
-            }
-            PassportValidity::Unconfirmed => {
-                // TODO should we stop trying after some time?
-                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-            }
-            PassportValidity::Expired => {
-                return Err(AppError::NotAuthorized());
-            }
-        }
+) -> Result<Response, AppError> {
+    let until = Instant::now()
+        + std::time::Duration::from_secs(state.config.passport_poll_secs);
+    let Some((account_id, revision_id)) =
+        await_confirmation(&state, &gym, &pport.passport_id, until).await?
+    else {
+        let pending = AwaitPassportResponse {
+            validity: PassportValidity::Unconfirmed,
+        };
+        return Ok((StatusCode::ACCEPTED, Json(pending)).into_response());
     };
 
-    let validity =
-        serde_json::to_value(&PassportValidity::Expired).map_err(|e| {
-            AppError::Internal(format!("serialising validity: {e}"))
-        })?;
-    let op = Operation::try_new_set("validity", Some(validity))?;
-    let _ = apply_object_updates(
+    set_validity(
         &state,
         &gym,
-        pport.passport_id,
+        &pport.passport_id,
         revision_id,
-        String::from(""), // TODO fine?
-        [op].to_vec(),
+        PassportValidity::Expired,
     )
     .await?;
 
-    // find session created in confirmation
-    let parent_path = state.db.parent_path("gyms", gym)?;
-    let sessions_stream: BoxStream<FirestoreResult<Session>> = state
-        .db
-        .fluent()
-        .select()
-        .from(Session::COLLECTION)
-        .parent(&parent_path)
-        .filter(|q| {
-            q.for_all([q
-                .field(path_camel_case!(Session::obj_id))
-                .eq(account_id.clone())])
-        })
-        .limit(1)
-        .obj()
-        .stream_query_with_errors()
-        .await?;
-
-    let sessions: Vec<Session> = sessions_stream.try_collect().await?;
-    let session_id = match sessions.first() {
-        Some(session) => session
-            .id
-            .clone()
-            .ok_or(AppError::Internal("session missing id".to_string())),
-        None => Err(AppError::NotAuthorized()),
-    }?;
+    // the session of the confirming browser is stored hashed, the waiting
+    // one gets its own
+    let (session_id, _) = Session::new(
+        account_id,
+        user_agent.map(|TypedHeader(user_agent)| user_agent.to_string()),
+        Some(client_ip(state.config.trusted_proxy_hops, &headers, addr)),
+    )
+    .create(&state, &gym)
+    .await?;
 
     // respond with the session cookie and status=200
     let cookie = Cookie::build(("session", session_id))
         .path("/")
-        .max_age(Duration::weeks(52))
+        .max_age(session_cookie_max_age(&state.config))
         .secure(true)
         .same_site(SameSite::None)
         .http_only(true);
-    Ok(jar.add(cookie))
+    Ok(jar.add(cookie).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+    use crate::{
+        backend::SqliteStorage,
+        types::{Filter, ViewQuery, view},
+    };
+
+    fn state(config: ServerConfig) -> AppState {
+        AppState {
+            config: Arc::new(config),
+            ..AppState::in_memory()
+        }
+    }
+
+    async fn passport(
+        state: &AppState,
+        gym: &str,
+        account_id: Option<&str>,
+    ) -> ObjectId {
+        let passport = Passport {
+            account_id: account_id.map(String::from),
+            email: String::from("new@example.com"),
+            security_code: String::from("code"),
+            confirmation_token: keyed_hash(&state.config.token_key, "token"),
+            validity: PassportValidity::Unconfirmed,
+        };
+        let value = serde_json::to_value(passport).unwrap();
+        Object::from_value(
+            state,
+            gym,
+            account_id.unwrap_or_default().to_string(),
+            ObjectType::Passport,
+            &value,
+        )
+        .await
+        .unwrap()
+        .id
+    }
+
+    #[test]
+    fn client_ip_is_appended_by_trusted_proxies() {
+        let addr = SocketAddr::from(([10, 0, 0, 1], 443));
+        let mut headers = HeaderMap::new();
+        assert_eq!("10.0.0.1", client_ip(1, &headers, addr));
+
+        headers.insert(
+            "x-forwarded-for",
+            "6.6.6.6, 1.2.3.4, 5.6.7.8".parse().unwrap(),
+        );
+        assert_eq!("5.6.7.8", client_ip(1, &headers, addr));
+        assert_eq!("1.2.3.4", client_ip(2, &headers, addr));
+        // not forwarded by all proxies or not behind any
+        assert_eq!("10.0.0.1", client_ip(4, &headers, addr));
+        assert_eq!("10.0.0.1", client_ip(0, &headers, addr));
+    }
+
+    #[tokio::test]
+    async fn confirmation_wakes_waiter() {
+        let state = state(ServerConfig::default());
+        let gym = "test";
+        let passport_id = passport(&state, gym, Some("account")).await;
+
+        let soon = Instant::now() + Duration::from_millis(20);
+        let pending = await_confirmation(&state, gym, &passport_id, soon);
+        assert_eq!(None, pending.await.unwrap());
+
+        let until = Instant::now() + Duration::from_secs(60);
+        let waiter = tokio::spawn({
+            let (state, passport_id) = (state.clone(), passport_id.clone());
+            async move { await_confirmation(&state, gym, &passport_id, until).await }
+        });
+        tokio::task::yield_now().await;
+        set_validity(&state, gym, &passport_id, 0, PassportValidity::Valid)
+            .await
+            .unwrap();
+        let confirmed = waiter.await.unwrap().unwrap();
+        assert_eq!(Some((String::from("account"), 1)), confirmed);
+        assert!(Instant::now() < until);
+    }
+
+    #[tokio::test]
+    async fn unconfirmed_passports_expire() {
+        let state = state(ServerConfig {
+            passport_ttl_secs: 0,
+            ..ServerConfig::default()
+        });
+        let gym = "test";
+        let passport_id = passport(&state, gym, Some("account")).await;
+
+        let until = Instant::now() + Duration::from_secs(60);
+        let waited = await_confirmation(&state, gym, &passport_id, until);
+        assert!(matches!(waited.await, Err(AppError::NotAuthorized())));
+        let (passport, revision_id, _) =
+            Passport::lookup(&state, gym, &passport_id).await.unwrap();
+        assert_eq!(PassportValidity::Expired, passport.validity);
+        assert_eq!(1, revision_id);
+    }
+
+    #[tokio::test]
+    async fn accounts_are_created_on_confirmation() {
+        let state = state(ServerConfig::default());
+        let gym = "test";
+        let passport_id = passport(&state, gym, None).await;
+        let email = || String::from("new@example.com");
+        let account =
+            AccountsView::with_email(&state, gym.to_string(), email());
+        assert!(account.await.unwrap().is_none());
+
+        let wrong = confirm(&state, gym, &passport_id, "wrong").await;
+        assert!(matches!(wrong, Err(AppError::NotAuthorized())));
+        let account_id =
+            confirm(&state, gym, &passport_id, "token").await.unwrap();
+        let account =
+            AccountsView::with_email(&state, gym.to_string(), email());
+        assert_eq!(
+            Some(account_id.clone()),
+            account.await.unwrap().unwrap().id
+        );
+
+        // the link can only be used once
+        let again = confirm(&state, gym, &passport_id, "token").await;
+        assert!(matches!(again, Err(AppError::NotAuthorized())));
+        let until = Instant::now() + Duration::from_secs(60);
+        let confirmed = await_confirmation(&state, gym, &passport_id, until);
+        assert_eq!(Some((account_id, 2)), confirmed.await.unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_confirmations_create_one_account() {
+        // sqlite runs its queries on blocking threads, so requests interleave
+        let db = SqliteStorage::open_in_memory().unwrap();
+        let state = AppState::for_tests(Arc::new(db));
+        let gym = "test";
+        let passport_id = passport(&state, gym, None).await;
+
+        // e.g. a mail scanner opening the link while the user clicks it
+        let confirmations: Vec<_> = (0..8)
+            .map(|_| {
+                let (state, passport_id) = (state.clone(), passport_id.clone());
+                tokio::spawn(async move {
+                    confirm(&state, gym, &passport_id, "token").await
+                })
+            })
+            .collect();
+        let mut confirmed = Vec::new();
+        for confirmation in confirmations {
+            match confirmation.await.unwrap() {
+                Ok(account_id) => confirmed.push(account_id),
+                Err(e) => assert!(matches!(e, AppError::NotAuthorized())),
+            }
+        }
+        assert_eq!(1, confirmed.len());
+
+        let query = ViewQuery::default()
+            .filter(Filter::Eq("email", serde_json::json!("new@example.com")));
+        let accounts: Vec<Account> =
+            view::query(&state, gym, &AccountsView, &query)
+                .await
+                .unwrap();
+        let ids: Vec<_> = accounts.into_iter().filter_map(|a| a.id).collect();
+        assert_eq!(confirmed, ids);
+    }
 }
@@ -0,0 +1,463 @@
+//! Field-level authorization of objects.
+//!
+//! Every [`ObjectType`] declares a table of [`Rule`]s. A principal may apply
+//! an [`Action`] to a path if an allowing rule covers the path and no denying
+//! rule overlaps it. Paths are compared as parsed op paths, so a rule for
+//! `role` covers `role` and everything below it, and denying `role` also
+//! denies a `Set` of the root, which replaces the role as well. Reading is
+//! different: the object can be read with the denied paths [`redact`]ed.
+
+use otp::{ObjectId, ROOT_PATH, is_subpath};
+use serde_json::Value;
+
+use crate::{
+    AppError, AppState,
+    routes::Principal,
+    types::{AccountRole, Boulder, Object, ObjectType},
+};
+use Action::{Create, Patch, Read};
+use Condition::{Always, Draft, Owner, Setter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Read,
+    Create,
+    Patch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// who a rule applies to
+#[derive(Debug)]
+pub enum Who {
+    /// anyone, also without session
+    Anyone,
+    Roles(&'static [AccountRole]),
+}
+
+/// what has to hold in addition to the role
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Always,
+    /// the principal is the account itself, or the creator of other objects
+    Owner,
+    /// the principal is listed in the setters of the boulder
+    Setter,
+    /// the boulder is a draft
+    Draft,
+}
+
+#[derive(Debug)]
+pub struct Rule {
+    pub effect: Effect,
+    pub action: Action,
+    pub who: Who,
+    pub condition: Condition,
+    /// the path and everything below it
+    pub path: &'static str,
+}
+
+const fn allow(
+    action: Action,
+    who: Who,
+    condition: Condition,
+    path: &'static str,
+) -> Rule {
+    Rule {
+        effect: Effect::Allow,
+        action,
+        who,
+        condition,
+        path,
+    }
+}
+
+const fn deny(
+    action: Action,
+    who: Who,
+    condition: Condition,
+    path: &'static str,
+) -> Rule {
+    Rule {
+        effect: Effect::Deny,
+        action,
+        who,
+        condition,
+        path,
+    }
+}
+
+const ANY_ROLE: &[AccountRole] =
+    &[AccountRole::User, AccountRole::Setter, AccountRole::Admin];
+const STAFF: &[AccountRole] = &[AccountRole::Setter, AccountRole::Admin];
+const ADMIN: &[AccountRole] = &[AccountRole::Admin];
+const SETTER: &[AccountRole] = &[AccountRole::Setter];
+
+const ACCOUNT_RULES: &[Rule] = &[
+    allow(Read, Who::Roles(ANY_ROLE), Owner, ROOT_PATH),
+    allow(Read, Who::Roles(STAFF), Always, ROOT_PATH),
+    allow(Create, Who::Roles(STAFF), Always, ROOT_PATH),
+    allow(Patch, Who::Roles(ADMIN), Always, ROOT_PATH),
+    // setters can change their own account, but only admins the role
+    allow(Patch, Who::Roles(SETTER), Owner, ROOT_PATH),
+    deny(Patch, Who::Roles(SETTER), Always, "role"),
+];
+
+const BOULDER_RULES: &[Rule] = &[
+    allow(Read, Who::Anyone, Always, ROOT_PATH),
+    allow(Create, Who::Roles(STAFF), Always, ROOT_PATH),
+    allow(Patch, Who::Roles(ADMIN), Always, ROOT_PATH),
+    // drafts can be edited by any setter, set boulders by their setters
+    allow(Patch, Who::Roles(SETTER), Draft, ROOT_PATH),
+    allow(Patch, Who::Roles(SETTER), Owner, ROOT_PATH),
+    allow(Patch, Who::Roles(SETTER), Setter, ROOT_PATH),
+];
+
+// passports are created and confirmed by the login routes only
+const PASSPORT_RULES: &[Rule] = &[
+    allow(Read, Who::Roles(ANY_ROLE), Owner, ROOT_PATH),
+    allow(Read, Who::Roles(STAFF), Always, ROOT_PATH),
+    deny(Read, Who::Anyone, Always, "confirmationToken"),
+];
+
+impl ObjectType {
+    pub fn rules(&self) -> &'static [Rule] {
+        match self {
+            ObjectType::Account => ACCOUNT_RULES,
+            ObjectType::Boulder => BOULDER_RULES,
+            ObjectType::Passport => PASSPORT_RULES,
+        }
+    }
+}
+
+/// What rules are evaluated against besides the principal
+pub struct ObjectFacts {
+    pub object_type: ObjectType,
+    /// the account itself for accounts, the creator for other objects
+    pub owner: ObjectId,
+    pub setters: Vec<ObjectId>,
+    pub is_draft: bool,
+}
+
+impl ObjectFacts {
+    /// Facts without the content of the object, `Setter` and `Draft` never
+    /// hold. Enough for all rules which only depend on the owner.
+    pub fn new(
+        object_type: &ObjectType,
+        object_id: &ObjectId,
+        created_by: &ObjectId,
+    ) -> Self {
+        let owner = match object_type {
+            ObjectType::Account => object_id,
+            _ => created_by,
+        };
+        Self {
+            object_type: object_type.clone(),
+            owner: owner.clone(),
+            setters: Vec::new(),
+            is_draft: false,
+        }
+    }
+
+    pub fn of(object: &Object) -> Self {
+        Self::new(&object.object_type, &object.id, &object.created_by)
+    }
+
+    /// all facts of the object, including the setters of boulders
+    pub async fn lookup(
+        state: &AppState,
+        gym: &str,
+        object: &Object,
+    ) -> Result<Self, AppError> {
+        let facts = Self::of(object);
+        if object.object_type != ObjectType::Boulder {
+            return Ok(facts);
+        }
+        let boulder = Boulder::lookup(state, gym, &object.id).await?;
+        Ok(Self {
+            setters: boulder.setter.clone(),
+            is_draft: boulder.is_draft > 0,
+            ..facts
+        })
+    }
+}
+
+impl Rule {
+    fn applies_to(
+        &self,
+        principal: Option<&Principal>,
+        facts: &ObjectFacts,
+    ) -> bool {
+        let principal = match (&self.who, principal) {
+            (Who::Anyone, principal) => principal,
+            (Who::Roles(roles), Some(p)) if roles.contains(&p.role) => Some(p),
+            (Who::Roles(_), _) => return false,
+        };
+        let is = |id: &ObjectId| principal.is_some_and(|p| p.account_id == *id);
+        match self.condition {
+            Condition::Always => true,
+            Condition::Owner => is(&facts.owner),
+            Condition::Setter => facts.setters.iter().any(is),
+            Condition::Draft => facts.is_draft,
+        }
+    }
+}
+
+fn rules_for<'a>(
+    action: Action,
+    principal: Option<&'a Principal>,
+    facts: &'a ObjectFacts,
+) -> impl Iterator<Item = &'static Rule> + 'a {
+    facts.object_type.rules().iter().filter(move |rule| {
+        rule.action == action && rule.applies_to(principal, facts)
+    })
+}
+
+/// whether the principal may apply the action to path
+pub fn allows(
+    action: Action,
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+    path: &str,
+) -> bool {
+    let mut allowed = false;
+    for rule in rules_for(action, principal, facts) {
+        match rule.effect {
+            Effect::Allow => allowed |= is_subpath(path, rule.path),
+            Effect::Deny => {
+                let overlaps = is_subpath(path, rule.path)
+                    || (action != Action::Read && is_subpath(rule.path, path));
+                if overlaps {
+                    return false;
+                }
+            }
+        }
+    }
+    allowed
+}
+
+/// Fails if any of the paths is not allowed, with `NoSession` if a session
+/// could make a difference.
+pub fn authorize<'a>(
+    action: Action,
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+    paths: impl IntoIterator<Item = &'a str>,
+) -> Result<(), AppError> {
+    for path in paths {
+        if !allows(action, principal, facts, path) {
+            return Err(match principal {
+                None => AppError::NoSession(),
+                Some(_) => AppError::NotAuthorized(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// whether the principal may read the object at all
+pub fn authorize_read(
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+) -> Result<(), AppError> {
+    authorize(Action::Read, principal, facts, [ROOT_PATH])
+}
+
+/// The paths below the root which the principal may not read. Histories of
+/// objects with hidden paths can not be read, patches contain every field.
+pub fn hidden_paths(
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+) -> Vec<&'static str> {
+    rules_for(Action::Read, principal, facts)
+        .filter(|rule| rule.effect == Effect::Deny)
+        .map(|rule| rule.path)
+        .collect()
+}
+
+/// [`authorize_read`] of every path, e.g. for patches, blame and the feed
+pub fn authorize_read_history(
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+) -> Result<(), AppError> {
+    authorize_read(principal, facts)?;
+    if hidden_paths(principal, facts).is_empty() {
+        Ok(())
+    } else {
+        Err(AppError::NotAuthorized())
+    }
+}
+
+/// remove the fields the principal may not read from the content, hidden
+/// paths only follow object keys
+pub fn redact(
+    principal: Option<&Principal>,
+    facts: &ObjectFacts,
+    content: &mut Value,
+) {
+    for path in hidden_paths(principal, facts) {
+        let (parent, key) = match path.rsplit_once('.') {
+            Some((parent, key)) => (parent, key),
+            None => (ROOT_PATH, path),
+        };
+        let parent = if parent.is_empty() {
+            Some(&mut *content)
+        } else {
+            content.pointer_mut(&format!("/{}", parent.replace('.', "/")))
+        };
+        if let Some(Value::Object(o)) = parent {
+            o.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::passport::Session;
+    use AccountRole::{Admin as A, Setter as S, User as U};
+    use ObjectType::{Account as Acc, Boulder as Bou, Passport as Pas};
+
+    const OWNER: &str = "owner";
+    const LISTED: &str = "listed";
+    const OTHER: &str = "other";
+
+    fn principal(account_id: &str, role: AccountRole) -> Principal {
+        Principal {
+            account_id: account_id.to_string(),
+            role,
+            session: Session::new(account_id.to_string(), None, None),
+        }
+    }
+
+    fn facts(object_type: ObjectType, is_draft: bool) -> ObjectFacts {
+        ObjectFacts {
+            object_type,
+            owner: OWNER.to_string(),
+            setters: vec![LISTED.to_string()],
+            is_draft,
+        }
+    }
+
+    // (action, object, draft, principal, path, allowed)
+    type Case = (
+        Action,
+        ObjectType,
+        bool,
+        Option<(&'static str, AccountRole)>,
+        &'static str,
+        bool,
+    );
+
+    #[test]
+    fn rules() {
+        let cases: Vec<Case> = vec![
+            // anyone can read boulders
+            (Read, Bou, false, None, "", true),
+            (Read, Bou, false, Some((OTHER, U)), "", true),
+            // accounts only by themselves and staff
+            (Read, Acc, false, None, "", false),
+            (Read, Acc, false, Some((OTHER, U)), "", false),
+            (Read, Acc, false, Some((OWNER, U)), "", true),
+            (Read, Acc, false, Some((OTHER, S)), "", true),
+            // nobody reads confirmation tokens
+            (Read, Pas, false, Some((OWNER, U)), "", true),
+            (
+                Read,
+                Pas,
+                false,
+                Some((OWNER, U)),
+                "confirmationToken",
+                false,
+            ),
+            (
+                Read,
+                Pas,
+                false,
+                Some((OTHER, A)),
+                "confirmationToken",
+                false,
+            ),
+            (Read, Pas, false, Some((OTHER, U)), "", false),
+            // only staff creates, passports only by the login routes
+            (Create, Bou, false, Some((OTHER, U)), "", false),
+            (Create, Bou, false, Some((OTHER, S)), "", true),
+            (Create, Acc, false, Some((OTHER, A)), "", true),
+            (Create, Pas, false, Some((OTHER, A)), "", false),
+            // users can not patch
+            (Patch, Acc, false, Some((OWNER, U)), "name", false),
+            (Patch, Bou, true, Some((OWNER, U)), "name", false),
+            // setters change their own account except the role
+            (Patch, Acc, false, Some((OWNER, S)), "name", true),
+            (Patch, Acc, false, Some((OWNER, S)), "role", false),
+            (Patch, Acc, false, Some((OWNER, S)), "role.x", false),
+            (Patch, Acc, false, Some((OWNER, S)), "", false),
+            (Patch, Acc, false, Some((OWNER, S)), "roleName", true),
+            (Patch, Acc, false, Some((OTHER, S)), "name", false),
+            (Patch, Acc, false, Some((OTHER, A)), "role", true),
+            (Patch, Acc, false, Some((OTHER, A)), "", true),
+            // setters patch drafts and boulders they set or created
+            (Patch, Bou, true, Some((OTHER, S)), "grade", true),
+            (Patch, Bou, false, Some((OTHER, S)), "grade", false),
+            (Patch, Bou, false, Some((LISTED, S)), "grade", true),
+            (Patch, Bou, false, Some((OWNER, S)), "grade", true),
+            (Patch, Bou, false, Some((OTHER, A)), "grade", true),
+            // passports are confirmed by the login routes only
+            (Patch, Pas, false, Some((OWNER, U)), "validity", false),
+            (Patch, Pas, false, Some((OTHER, A)), "validity", false),
+        ];
+
+        for (action, object_type, is_draft, who, path, expected) in cases {
+            let principal = who.clone().map(|(id, role)| principal(id, role));
+            let facts = facts(object_type.clone(), is_draft);
+            assert_eq!(
+                expected,
+                allows(action, principal.as_ref(), &facts, path),
+                "{action:?} {object_type} draft={is_draft} by {who:?} at {path:?}",
+            );
+        }
+    }
+
+    #[test]
+    fn authorize_fails_with_no_session_without_principal() {
+        let facts = facts(Acc, false);
+        assert!(matches!(
+            authorize_read(None, &facts),
+            Err(AppError::NoSession())
+        ));
+        let other = principal(OTHER, U);
+        assert!(matches!(
+            authorize_read(Some(&other), &facts),
+            Err(AppError::NotAuthorized())
+        ));
+    }
+
+    #[test]
+    fn redact_hidden_paths() {
+        let owner = principal(OWNER, U);
+        let facts = facts(Pas, false);
+        let mut content = json!({
+            "accountId": OWNER,
+            "confirmationToken": "secret",
+            "validity": "unconfirmed",
+        });
+        redact(Some(&owner), &facts, &mut content);
+        assert_eq!(
+            json!({"accountId": OWNER, "validity": "unconfirmed"}),
+            content
+        );
+        assert!(authorize_read_history(Some(&owner), &facts).is_err());
+
+        let facts = ObjectFacts {
+            object_type: Bou,
+            ..facts
+        };
+        assert!(authorize_read_history(None, &facts).is_ok());
+    }
+}
@@ -0,0 +1,172 @@
+//! Rate limits of requests which are expensive or can be abused.
+//!
+//! Hits are counted per key in fixed windows. The counts are kept by a
+//! [`RateLimitStore`], in memory of this instance by default. Instances
+//! behind a load balancer each count on their own unless they share a store.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::AppError;
+
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Count a hit of key at `now`, returns the hits of key in its current
+    /// window including this one. Windows start with the first hit.
+    async fn hit(
+        &self,
+        key: &str,
+        window: TimeDelta,
+        now: DateTime<Utc>,
+    ) -> Result<u64, AppError>;
+}
+
+/// counted keys beyond which expired windows are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    /// start of the current window and its hits by key
+    windows: Mutex<HashMap<String, (DateTime<Utc>, u64)>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryRateLimitStore {
+    async fn hit(
+        &self,
+        key: &str,
+        window: TimeDelta,
+        now: DateTime<Utc>,
+    ) -> Result<u64, AppError> {
+        let mut windows =
+            self.windows.lock().unwrap_or_else(PoisonError::into_inner);
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, (start, _)| now - *start < window);
+        }
+
+        let (start, hits) = windows.entry(key.to_string()).or_insert((now, 0));
+        if now - *start >= window {
+            *start = now;
+            *hits = 0;
+        }
+        *hits += 1;
+        Ok(*hits)
+    }
+}
+
+/// a key and the hits it may have per window, 0 for no limit
+pub struct Limit {
+    pub key: String,
+    pub max_hits: u64,
+}
+
+pub struct RateLimiter {
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryRateLimitStore::default()))
+    }
+}
+
+impl RateLimiter {
+    pub fn new(store: Arc<dyn RateLimitStore>) -> Self {
+        Self { store }
+    }
+
+    /// Count a hit of the limits in order, fails at the first exceeded one.
+    /// The limits after it are not counted, so a client exceeding its own
+    /// limit does not use up the windows it shares with others.
+    pub async fn check(
+        &self,
+        limits: &[Limit],
+        window: TimeDelta,
+    ) -> Result<(), AppError> {
+        let now = Utc::now();
+        for limit in limits.iter().filter(|limit| limit.max_hits > 0) {
+            let hits = self.store.hit(&limit.key, window, now).await?;
+            if hits > limit.max_hits {
+                tracing::warn!("rate limit of {} exceeded", limit.key);
+                return Err(AppError::RateLimited());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn windows_restart_after_they_end() {
+        let store = MemoryRateLimitStore::default();
+        let window = TimeDelta::minutes(1);
+        let now = Utc::now();
+        assert_eq!(1, store.hit("a", window, now).await.unwrap());
+        assert_eq!(2, store.hit("a", window, now).await.unwrap());
+        assert_eq!(1, store.hit("b", window, now).await.unwrap());
+        let later = now + window;
+        assert_eq!(1, store.hit("a", window, later).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn any_exceeded_limit_rejects() {
+        let limiter = RateLimiter::default();
+        let window = TimeDelta::hours(1);
+        let limits = |email: &str| {
+            [
+                Limit {
+                    key: String::from("ip:1.2.3.4"),
+                    max_hits: 3,
+                },
+                Limit {
+                    key: format!("email:{email}"),
+                    max_hits: 1,
+                },
+                Limit {
+                    key: String::from("unlimited"),
+                    max_hits: 0,
+                },
+            ]
+        };
+        assert!(limiter.check(&limits("a"), window).await.is_ok());
+        assert!(matches!(
+            limiter.check(&limits("a"), window).await,
+            Err(AppError::RateLimited())
+        ));
+        assert!(limiter.check(&limits("b"), window).await.is_ok());
+        assert!(limiter.check(&limits("c"), window).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejected_hits_count_only_up_to_the_exceeded_limit() {
+        let limiter = RateLimiter::default();
+        let window = TimeDelta::hours(1);
+        let limits = |ip: &str| {
+            [
+                Limit {
+                    key: format!("ip:{ip}"),
+                    max_hits: 1,
+                },
+                Limit {
+                    key: String::from("gym"),
+                    max_hits: 2,
+                },
+            ]
+        };
+        assert!(limiter.check(&limits("1.2.3.4"), window).await.is_ok());
+        for _ in 0..3 {
+            assert!(limiter.check(&limits("1.2.3.4"), window).await.is_err());
+        }
+        // the rejected hits did not use up the limit of the gym
+        assert!(limiter.check(&limits("5.6.7.8"), window).await.is_ok());
+        assert!(limiter.check(&limits("9.9.9.9"), window).await.is_err());
+    }
+}
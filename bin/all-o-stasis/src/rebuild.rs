@@ -0,0 +1,249 @@
+//! Recomputing materialized views from snapshots.
+//!
+//! View rows are written after the patches of a change. If that write fails
+//! (or an older version projected the content differently) the view diverges
+//! from its object. A rebuild projects the latest snapshot of every object of
+//! a gym again, stores rows which are missing or differ and removes rows of
+//! objects which no longer exist or were deleted.
+
+use std::collections::{HashMap, HashSet};
+
+use otp::ObjectId;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    AppError, AppState,
+    types::{
+        Object, Snapshot, ViewQuery,
+        view::{View, view_of, views},
+    },
+};
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum DifferenceKind {
+    /// the object has no row
+    Missing,
+    /// the row differs from the projection of the latest snapshot
+    Changed,
+    /// the row belongs to a missing or deleted object
+    Stale,
+}
+
+/// a view row which does not match its object
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Difference {
+    pub collection: &'static str,
+    pub object_id: ObjectId,
+    pub kind: DifferenceKind,
+    pub expected: Option<Value>,
+    pub actual: Option<Value>,
+}
+
+/// an object whose row could not be computed, it is left as it is
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RebuildError {
+    pub object_id: ObjectId,
+    pub error: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RebuildReport {
+    pub dry_run: bool,
+    /// number of objects with a view
+    pub checked: usize,
+    pub differences: Vec<Difference>,
+    pub errors: Vec<RebuildError>,
+}
+
+/// the expected row of an object, `None` if it should not have one
+async fn expected_row(
+    state: &AppState,
+    gym: &str,
+    view: &dyn View,
+    obj: &Object,
+) -> Result<Option<Value>, AppError> {
+    if obj.deleted {
+        return Ok(None);
+    }
+    let snapshot = Snapshot::lookup_latest(state, gym, &obj.id).await?;
+    view.project(&obj.id, &snapshot.content).map(Some)
+}
+
+/// Compare all view rows of a gym with the latest snapshots of their objects
+/// and fix the differences unless `dry_run` is set.
+pub async fn rebuild_views(
+    state: &AppState,
+    gym: &str,
+    dry_run: bool,
+) -> Result<RebuildReport, AppError> {
+    let mut report = RebuildReport {
+        dry_run,
+        checked: 0,
+        differences: Vec::new(),
+        errors: Vec::new(),
+    };
+
+    let mut objects = Vec::new();
+    for object_id in state.db.object_ids(gym).await? {
+        objects.push(
+            Object::lookup_including_deleted(state, gym, &object_id).await?,
+        );
+    }
+
+    for view in views() {
+        let collection = view.collection();
+        let mut rows: HashMap<ObjectId, Value> = state
+            .db
+            .query_view(gym, collection, &ViewQuery::default())
+            .await?
+            .into_iter()
+            .filter_map(|row| {
+                let id = row.get("id")?.as_str()?.to_string();
+                Some((id, row))
+            })
+            .collect();
+
+        let mut live = HashSet::new();
+        let of_view = objects.iter().filter(|obj| {
+            view_of(&obj.object_type)
+                .is_some_and(|v| v.collection() == collection)
+        });
+        for obj in of_view {
+            report.checked += 1;
+            let object_id = obj.id.clone();
+
+            let expected = match expected_row(state, gym, view, obj).await {
+                Ok(Some(expected)) => expected,
+                // deleted, its row (if any) is reported as stale below
+                Ok(None) => continue,
+                Err(e) => {
+                    report.errors.push(RebuildError {
+                        object_id: object_id.clone(),
+                        error: e.status_and_message().1,
+                    });
+                    // keep the row of objects we could not check
+                    live.insert(object_id);
+                    continue;
+                }
+            };
+            live.insert(object_id.clone());
+
+            let actual = rows.remove(&object_id);
+            let kind = match &actual {
+                None => DifferenceKind::Missing,
+                Some(actual) if *actual != expected => DifferenceKind::Changed,
+                Some(_) => continue,
+            };
+            if !dry_run {
+                state
+                    .db
+                    .store_view(gym, collection, &object_id, &expected)
+                    .await?;
+            }
+            report.differences.push(Difference {
+                collection,
+                object_id,
+                kind,
+                expected: Some(expected),
+                actual,
+            });
+        }
+
+        // rows left belong to objects which are gone or deleted
+        for (object_id, actual) in rows {
+            if live.contains(&object_id) {
+                continue;
+            }
+            if !dry_run {
+                state.db.delete_view(gym, collection, &object_id).await?;
+            }
+            report.differences.push(Difference {
+                collection,
+                object_id,
+                kind: DifferenceKind::Stale,
+                expected: None,
+                actual: Some(actual),
+            });
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        test_boulder,
+        types::{BouldersView, ObjectType},
+    };
+
+    #[tokio::test]
+    async fn rebuild_fixes_diverged_rows() {
+        let state = AppState::in_memory();
+        let gym = "test";
+        let obj = Object::from_value(
+            &state,
+            gym,
+            String::from("author"),
+            ObjectType::Boulder,
+            &test_boulder(),
+        )
+        .await
+        .unwrap();
+        let collection = BouldersView.collection();
+        let stale = String::from("gone");
+        state
+            .db
+            .store_view(gym, collection, &obj.id, &json!({ "id": obj.id }))
+            .await
+            .unwrap();
+        state
+            .db
+            .store_view(gym, collection, &stale, &json!({ "id": stale }))
+            .await
+            .unwrap();
+
+        let kinds =
+            |report: &RebuildReport| -> Vec<(ObjectId, DifferenceKind)> {
+                let mut kinds: Vec<_> = report
+                    .differences
+                    .iter()
+                    .map(|d| (d.object_id.clone(), d.kind.clone()))
+                    .collect();
+                kinds.sort_by(|a, b| a.0.cmp(&b.0));
+                kinds
+            };
+        let mut expected = vec![
+            (obj.id.clone(), DifferenceKind::Changed),
+            (stale.clone(), DifferenceKind::Stale),
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // a dry run only reports
+        let report = rebuild_views(&state, gym, true).await.unwrap();
+        assert_eq!(1, report.checked);
+        assert_eq!(expected, kinds(&report));
+        let report = rebuild_views(&state, gym, false).await.unwrap();
+        assert_eq!(expected, kinds(&report));
+
+        let report = rebuild_views(&state, gym, true).await.unwrap();
+        assert!(report.differences.is_empty());
+        let row = state
+            .db
+            .lookup_view(gym, collection, &obj.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            Some(&json!("blue")),
+            row.as_ref().and_then(|r| r.get("grade"))
+        );
+    }
+}
@@ -1,13 +1,24 @@
-use axum::{Router, extract::State, routing::get};
-use tower_http::cors::CorsLayer;
+use axum::{
+    Router, extract::State, middleware::from_fn_with_state, response::Json,
+    routing::get,
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
 
-use crate::{AppError, AppState, passport};
+use crate::{AppError, AppState, cache::CacheStats, passport};
 
 mod api;
+mod auth;
 mod collection;
+mod gyms;
 mod stats;
 
 pub use api::PatchObjectResponse;
+pub use auth::Principal;
+pub use gyms::KnownGym;
 
 mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -24,31 +35,42 @@ async fn revision(
 async fn healthz(
     State(state): State<AppState>,
 ) -> Result<&'static str, AppError> {
-    let _db_is_alive = state
-        .db
-        .fluent()
-        .list()
-        .collections()
-        .stream_all_with_errors()
-        .await?;
+    state.db.healthz().await?;
 
     Ok("alive and kickin")
 }
 
+async fn cache_stats(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<CacheStats>, AppError> {
+    gyms::authorize_admin_token(&state, bearer)?;
+    Ok(Json(state.snapshots.stats()))
+}
+
 pub fn app(state: AppState) -> Router {
     // TODO simplify gym capture?
-    // TODO allow any? see examples here: https://docs.rs/tower-http/latest/tower_http/cors/struct.CorsLayer.html#method.allow_origin
-    let cors = CorsLayer::very_permissive();
-    // let cors = CorsLayer::new().allow_origin(Any)..allow_credentials(true);
+    let cors_state = state.clone();
+    let cors = CorsLayer::very_permissive().allow_origin(
+        AllowOrigin::predicate(move |origin, parts| {
+            gyms::allows_origin(&cors_state, origin, parts.uri.path())
+        }),
+    );
 
-    Router::new()
-        .route("/revision", get(revision))
-        .route("/healthz", get(healthz))
-        // app routes
+    // app routes, only served for registered gyms
+    let gym_routes = Router::new()
         .merge(collection::routes())
         .merge(stats::routes())
         .merge(api::routes())
         .merge(passport::routes())
+        .route_layer(from_fn_with_state(state.clone(), gyms::require_gym));
+
+    Router::new()
+        .route("/revision", get(revision))
+        .route("/healthz", get(healthz))
+        .route("/cache", get(cache_stats))
+        .merge(gyms::routes())
+        .merge(gym_routes)
         //
         .with_state(state)
         .layer(cors)
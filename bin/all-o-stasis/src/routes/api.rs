@@ -1,8 +1,11 @@
-use std::net::SocketAddr;
+use std::{collections::HashSet, net::SocketAddr};
 
 use axum::{
     Router,
-    extract::{Path, State, connect_info::ConnectInfo, ws::WebSocketUpgrade},
+    extract::{
+        Path, Query, State, connect_info::ConnectInfo, ws::WebSocketUpgrade,
+    },
+    http::StatusCode,
     response::{IntoResponse, Json},
     routing::{any, delete, get, patch, post},
 };
@@ -14,17 +17,18 @@ use axum_extra::{
 use chrono::{DateTime, Utc};
 use cookie::time::Duration;
 use otp::{ObjectId, Operation, RevId};
-use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
 use crate::{
     AppError, AppState,
-    passport::{Session, author_from_session},
-    storage::apply_object_updates,
-    types::{
-        AccountRole, AccountsView, Boulder, Object, ObjectType, Patch, Snapshot,
-    },
+    consistency::{self, ConsistencyReport},
+    passport::{Session, session_cookie_max_age},
+    policy::{self, Action, ObjectFacts},
+    rebuild::{self, RebuildReport},
+    routes::auth::{Admin, Principal},
+    storage::{ObjectUpdates, apply_batch_updates, apply_object_updates},
+    types::{Object, ObjectBlame, ObjectType, Patch, Snapshot},
     ws::handle_socket,
 };
 
@@ -59,23 +63,137 @@ pub struct LookupObjectResponse {
 impl LookupObjectResponse {
     pub async fn build(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         id: ObjectId,
     ) -> Result<Self, AppError> {
         let obj = Object::lookup(state, gym, &id).await?;
         let snapshot = Snapshot::lookup_latest(state, gym, &id.clone()).await?;
 
-        Ok(LookupObjectResponse {
-            id,
-            ot_type: obj.object_type,
+        Ok(Self::from_snapshot(&obj, snapshot))
+    }
+
+    /// the object as it was at revision rev_id
+    pub async fn build_at(
+        state: &AppState,
+        gym: &str,
+        id: ObjectId,
+        rev_id: RevId,
+    ) -> Result<Self, AppError> {
+        let obj = Object::lookup(state, gym, &id).await?;
+        let snapshot = lookup_revision(state, gym, &id, rev_id).await?;
+
+        Ok(Self::from_snapshot(&obj, snapshot))
+    }
+
+    /// the response without the fields the reader may not read
+    fn readable_by(
+        mut self,
+        reader: Option<&Principal>,
+    ) -> Result<Self, AppError> {
+        let facts = ObjectFacts::new(&self.ot_type, &self.id, &self.created_by);
+        policy::authorize_read(reader, &facts)?;
+        policy::redact(reader, &facts, &mut self.content);
+        Ok(self)
+    }
+
+    fn from_snapshot(obj: &Object, snapshot: Snapshot) -> Self {
+        LookupObjectResponse {
+            id: obj.id.clone(),
+            ot_type: obj.object_type.clone(),
             created_at: obj.created_at,
-            created_by: obj.created_by,
+            created_by: obj.created_by.clone(),
             revision_id: snapshot.revision_id,
             content: snapshot.content,
-        })
+        }
     }
 }
 
+/// maximum number of ids in a `batch_get_objects` request
+const MAX_BATCH_GET_IDS: usize = 500;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchGetBody {
+    ids: Vec<ObjectId>,
+}
+
+/// the object or the error looking it up failed with
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchGetEntry {
+    id: ObjectId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object: Option<LookupObjectResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl BatchGetEntry {
+    fn new(
+        id: ObjectId,
+        result: Result<LookupObjectResponse, AppError>,
+    ) -> Self {
+        match result {
+            Ok(object) => Self {
+                id,
+                object: Some(object),
+                status: None,
+                error: None,
+            },
+            Err(e) => {
+                let (status, error) = e.status_and_message();
+                Self {
+                    id,
+                    object: None,
+                    status: Some(status.as_u16()),
+                    error: Some(error),
+                }
+            }
+        }
+    }
+}
+
+/// one entry per requested id in request order
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchGetResponse {
+    objects: Vec<BatchGetEntry>,
+}
+
+/// the snapshot at exactly rev_id, fails if the object has no such revision
+async fn lookup_revision(
+    state: &AppState,
+    gym: &str,
+    id: &ObjectId,
+    rev_id: RevId,
+) -> Result<Snapshot, AppError> {
+    let snapshot = Snapshot::lookup(state, gym, id, rev_id).await?;
+    if rev_id < otp::ZERO_REV_ID || snapshot.revision_id != rev_id {
+        return Err(AppError::Query(format!(
+            "object {id} has no revision {rev_id}"
+        )));
+    }
+    Ok(snapshot)
+}
+
+#[derive(Deserialize)]
+struct DiffRange {
+    from: RevId,
+    to: RevId,
+}
+
+/// the operations which turn revision `from` into revision `to`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DiffResponse {
+    object_id: ObjectId,
+    from_revision_id: RevId,
+    to_revision_id: RevId,
+    operations: Vec<Operation>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct PatchObjectBody {
@@ -105,6 +223,81 @@ impl PatchObjectResponse {
     }
 }
 
+/// maximum number of objects in a `batch_patch_objects` request
+const MAX_BATCH_PATCH_OBJECTS: usize = 100;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchPatchEntry {
+    object_id: ObjectId,
+    revision_id: RevId,
+    operations: Vec<Operation>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchPatchBody {
+    updates: Vec<BatchPatchEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchPatchResult {
+    object_id: ObjectId,
+    #[serde(flatten)]
+    response: PatchObjectResponse,
+}
+
+/// one result per update in request order
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchPatchResponse {
+    results: Vec<BatchPatchResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RebuildViewsParams {
+    /// only report differences without fixing them
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConsistencyParams {
+    /// fix the issues which are safe to fix
+    #[serde(default)]
+    repair: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RevertObjectBody {
+    revision_id: RevId,
+}
+
+/// default and maximum number of patches returned by `lookup_patches`
+const PATCHES_PAGE_SIZE: usize = 100;
+const MAX_PATCHES_PAGE_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct PatchRange {
+    /// first revision (inclusive)
+    from: Option<RevId>,
+    /// last revision (inclusive)
+    to: Option<RevId>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PatchesResponse {
+    patches: Vec<Patch>,
+    /// `from` of the next page if there are more patches in the range
+    next_from: Option<RevId>,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct LookupSessionResponse {
@@ -112,13 +305,27 @@ struct LookupSessionResponse {
     obj_id: ObjectId,
 }
 
-async fn account_role(
-    state: &AppState,
-    gym: &String,
-    object_id: &ObjectId,
-) -> Result<AccountRole, AppError> {
-    let account = AccountsView::with_id(state, gym, object_id.clone()).await?;
-    Ok(account.role)
+/// a session of the account, without the id which would allow to use it
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionInfo {
+    handle: String,
+    created_at: Option<DateTime<Utc>>,
+    last_accessed_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    /// the session of the request
+    current: bool,
+}
+
+/// the cookie telling the browser to drop its session
+fn removal_cookie() -> Cookie<'static> {
+    Cookie::build(("session", ""))
+        .path("/")
+        .max_age(Duration::seconds(0))
+        .secure(true)
+        .http_only(true)
+        .build()
 }
 
 pub fn routes() -> Router<AppState> {
@@ -126,62 +333,128 @@ pub fn routes() -> Router<AppState> {
         .route("/{gym}/session", get(lookup_session))
         // signout
         .route("/{gym}/session", delete(delete_session))
+        // active sessions of the account, revoke one or all of them
+        .route("/{gym}/sessions", get(list_sessions))
+        .route("/{gym}/sessions", delete(delete_all_sessions))
+        .route("/{gym}/sessions/{handle}", delete(revoke_session))
         .route("/{gym}/objects", post(new_object))
+        .route("/{gym}/objects:batchGet", post(batch_get_objects))
+        .route("/{gym}/objects:batchPatch", post(batch_patch_objects))
         .route("/{gym}/objects/{id}", get(lookup_object))
         .route("/{gym}/objects/{id}", patch(patch_object))
+        .route("/{gym}/objects/{id}", delete(delete_object))
+        .route("/{gym}/objects/{id}/restore", post(restore_object))
+        .route("/{gym}/objects/{id}/revert", post(revert_object))
+        .route("/{gym}/objects/{id}/blame", get(blame_object))
+        .route(
+            "/{gym}/objects/{id}/revisions/{rev_id}",
+            get(lookup_revision_of),
+        )
+        .route("/{gym}/objects/{id}/diff", get(diff_object))
+        .route("/{gym}/objects/{id}/patches", get(lookup_patches))
         .route("/{gym}/objects/{id}/patches/{rev_id}", get(lookup_patch))
+        .route("/{gym}/views/rebuild", post(rebuild_views))
+        .route("/{gym}/consistency", post(check_consistency))
         // feed (raw websocket) -- to subscribe to object updates (patches)
         .route("/{gym}/feed", any(feed))
 }
 
-// TODO maybe impl FromRequestParts into a state/path context
 async fn delete_session(
     State(state): State<AppState>,
     Path(gym): Path<String>,
+    principal: Principal,
     jar: CookieJar,
 ) -> Result<impl IntoResponse, AppError> {
-    let session_id = jar
-        .get("session")
-        .ok_or(AppError::NoSession())?
-        .value()
-        .to_owned();
-    Session::delete(&state, &gym, &session_id).await?;
+    principal.session.revoke(&state, &gym).await?;
 
-    let cookie = Cookie::build(("session", session_id))
-        .path("/")
-        .max_age(Duration::seconds(0))
-        .secure(true)
-        .http_only(true);
+    Ok(jar.add(removal_cookie()))
+}
 
-    Ok(jar.add(cookie))
+async fn list_sessions(
+    State(state): State<AppState>,
+    Path(gym): Path<String>,
+    principal: Principal,
+) -> Result<Json<Vec<SessionInfo>>, AppError> {
+    let current = principal.session.handle();
+    let mut sessions: Vec<SessionInfo> =
+        Session::of_account(&state, &gym, &principal.account_id)
+            .await?
+            .into_iter()
+            .filter_map(|session| {
+                let handle = session.handle()?;
+                Some(SessionInfo {
+                    current: Some(&handle) == current.as_ref(),
+                    handle,
+                    created_at: session.created_at,
+                    last_accessed_at: session.last_accessed_at,
+                    user_agent: session.user_agent,
+                    ip: session.ip,
+                })
+            })
+            .collect();
+    sessions.sort_by_key(|s| std::cmp::Reverse(s.last_accessed_at));
+
+    Ok(Json(sessions))
 }
 
-async fn lookup_session(
+/// revoke one of the sessions of the account
+async fn revoke_session(
+    State(state): State<AppState>,
+    Path((gym, handle)): Path<(String, String)>,
+    principal: Principal,
+) -> Result<StatusCode, AppError> {
+    let session = Session::of_account(&state, &gym, &principal.account_id)
+        .await?
+        .into_iter()
+        .find(|session| session.handle().as_ref() == Some(&handle))
+        .ok_or(AppError::NoSession())?;
+    session.revoke(&state, &gym).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// sign out everywhere: revoke all sessions of the account
+async fn delete_all_sessions(
     State(state): State<AppState>,
     Path(gym): Path<String>,
+    principal: Principal,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, AppError> {
+    let sessions = state
+        .db
+        .account_sessions(&gym, &principal.account_id)
+        .await?;
+    for session in sessions {
+        session.revoke(&state, &gym).await?;
+    }
+    tracing::info!("{} signed out everywhere", principal.account_id);
+
+    Ok(jar.add(removal_cookie()))
+}
+
+async fn lookup_session(
+    State(state): State<AppState>,
+    principal: Principal,
     jar: CookieJar,
 ) -> Result<impl IntoResponse, AppError> {
-    // TODO NoSession correct here?
+    // only the hash of the session id is stored
     let session_id = jar
         .get("session")
         .ok_or(AppError::NoSession())?
         .value()
         .to_owned();
-    let session = Session::lookup(&state, &gym, session_id.clone()).await?;
 
     let cookie = Cookie::build(("session", session_id.clone()))
         .path("/")
-        .max_age(Duration::weeks(52))
+        .max_age(session_cookie_max_age(&state.config))
         .secure(true)
         .http_only(true);
 
     Ok((
         jar.add(cookie),
         Json(LookupSessionResponse {
-            id: session
-                .id
-                .ok_or(AppError::Internal("session missing id".to_string()))?,
-            obj_id: session.obj_id,
+            id: session_id,
+            obj_id: principal.account_id,
         }),
     ))
 }
@@ -189,26 +462,20 @@ async fn lookup_session(
 async fn new_object(
     State(state): State<AppState>,
     Path(gym): Path<String>,
-    jar: CookieJar,
+    principal: Principal,
     Json(payload): axum::extract::Json<CreateObjectBody>,
 ) -> Result<Json<CreateObjectResponse>, AppError> {
-    let session_id = jar.get("session").ok_or(AppError::NoSession())?;
-    let created_by = author_from_session(&state, &gym, session_id).await?;
-
-    // unauthorized users should be able to create accounts
-    // but this happens in the passport routes
-    // so we dont allow unauthorized here
-    // if created_by == ROOT_OBJ_ID {
-    //     return Err(AppError::NotAuthorized());
-    // }
-
-    // only admins and setters can add objects (account setup in passport)
-    let role = account_role(&state, &gym, &created_by).await?;
-    if AccountRole::User == role {
-        return Err(AppError::NotAuthorized());
-    }
-
+    let created_by = principal.account_id.clone();
     let ot_type = payload.ot_type;
+    // accounts are set up by passport, this is for admins and setters
+    let facts = ObjectFacts::new(&ot_type, &created_by, &created_by);
+    policy::authorize(
+        Action::Create,
+        Some(&principal),
+        &facts,
+        [otp::ROOT_PATH],
+    )?;
+
     let content = payload.content;
     // changing this to also add the object to the view
     let obj =
@@ -225,90 +492,215 @@ async fn new_object(
 async fn lookup_object(
     State(state): State<AppState>,
     Path((gym, id)): Path<(String, String)>,
-    jar: CookieJar,
+    reader: Option<Principal>,
 ) -> Result<Json<LookupObjectResponse>, AppError> {
-    let response = Json(LookupObjectResponse::build(&state, &gym, id).await?);
+    let response = LookupObjectResponse::build(&state, &gym, id).await?;
+    let response = response.readable_by(reader.as_ref())?;
 
-    // anyone can lookup boulders
-    if response.ot_type == ObjectType::Boulder {
-        return Ok(response);
+    Ok(Json(response))
+}
+
+async fn batch_get_objects(
+    State(state): State<AppState>,
+    Path(gym): Path<String>,
+    reader: Option<Principal>,
+    Json(payload): Json<BatchGetBody>,
+) -> Result<Json<BatchGetResponse>, AppError> {
+    if payload.ids.len() > MAX_BATCH_GET_IDS {
+        return Err(AppError::Query(format!(
+            "at most {MAX_BATCH_GET_IDS} ids per batch"
+        )));
     }
 
-    let session_id = jar.get("session").ok_or(AppError::NoSession())?;
-    let created_by = author_from_session(&state, &gym, session_id).await?;
+    let mut ids = payload.ids.clone();
+    ids.sort();
+    ids.dedup();
+    let objects = Object::lookup_many(&state, &gym, &ids).await?;
+    let live: Vec<ObjectId> = objects
+        .values()
+        .filter(|obj| !obj.deleted)
+        .map(|obj| obj.id.clone())
+        .collect();
+    let snapshots = Snapshot::lookup_latest_many(&state, &gym, &live).await?;
+
+    let lookup = |id: &ObjectId| {
+        let obj = objects.get(id).ok_or(AppError::Query(format!(
+            "lookup_object: failed to get object {id}"
+        )))?;
+        if obj.deleted {
+            return Err(AppError::Gone(obj.id.clone()));
+        }
+        let snapshot = snapshots
+            .get(id)
+            .cloned()
+            .ok_or(AppError::Internal(format!("no snapshot of {id}")))?;
+        LookupObjectResponse::from_snapshot(obj, snapshot)
+            .readable_by(reader.as_ref())
+    };
 
-    // otherwise just object the owner owns
-    if created_by == response.created_by {
-        return Ok(response);
+    let objects = payload
+        .ids
+        .into_iter()
+        .map(|id| {
+            let result = lookup(&id);
+            BatchGetEntry::new(id, result)
+        })
+        .collect();
+    Ok(Json(BatchGetResponse { objects }))
+}
+
+async fn delete_object(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    Admin(admin): Admin,
+) -> Result<StatusCode, AppError> {
+    let author = admin.account_id;
+    let object = Object::lookup(&state, &gym, &id).await?;
+    object.delete(&state, &gym).await?;
+    tracing::info!("{author} deleted {object}");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// recompute the view rows of all objects of the gym (admin)
+async fn rebuild_views(
+    State(state): State<AppState>,
+    Path(gym): Path<String>,
+    Query(params): Query<RebuildViewsParams>,
+    Admin(admin): Admin,
+) -> Result<Json<RebuildReport>, AppError> {
+    let author = admin.account_id;
+    let report = rebuild::rebuild_views(&state, &gym, params.dry_run).await?;
+    if !params.dry_run {
+        tracing::info!(
+            "{author} rebuilt views of {gym}: {} differences fixed",
+            report.differences.len()
+        );
     }
 
-    let role = account_role(&state, &gym, &created_by).await?;
-    // or if the user is an admin/setter
-    if role == AccountRole::Admin || role == AccountRole::Setter {
-        Ok(response)
-    } else {
-        Err(AppError::NotAuthorized())
+    Ok(Json(report))
+}
+
+/// check the history of all objects of the gym (admin)
+async fn check_consistency(
+    State(state): State<AppState>,
+    Path(gym): Path<String>,
+    Query(params): Query<ConsistencyParams>,
+    Admin(admin): Admin,
+) -> Result<Json<ConsistencyReport>, AppError> {
+    let author = admin.account_id;
+    let report = consistency::check_gym(&state, &gym, params.repair).await?;
+    if params.repair {
+        tracing::info!(
+            "{author} repaired {gym}: {} of {} issues fixed",
+            report.findings.iter().filter(|f| f.repaired).count(),
+            report.findings.len()
+        );
     }
+
+    Ok(Json(report))
 }
 
-async fn patch_object(
+async fn restore_object(
     State(state): State<AppState>,
     Path((gym, id)): Path<(String, String)>,
-    jar: CookieJar,
-    Json(payload): axum::extract::Json<PatchObjectBody>,
-) -> Result<Json<PatchObjectResponse>, AppError> {
-    let session_id = jar.get("session").ok_or(AppError::NoSession())?;
-    let created_by = author_from_session(&state, &gym, session_id).await?;
-
-    // users cant patch atm
-    // TODO should be able to patch their account? (probably not implemented in
-    // the client?)
-    let role = account_role(&state, &gym, &created_by).await?;
-    if role == AccountRole::User {
-        return Err(AppError::NotAuthorized());
+    Admin(admin): Admin,
+) -> Result<Json<LookupObjectResponse>, AppError> {
+    let author = &admin.account_id;
+    let object = Object::lookup_including_deleted(&state, &gym, &id).await?;
+    if object.deleted {
+        object.restore(&state, &gym).await?;
+        tracing::info!("{author} restored {object}");
     }
 
+    let response = LookupObjectResponse::build(&state, &gym, id).await?;
+    Ok(Json(response.readable_by(Some(&admin))?))
+}
+
+async fn lookup_revision_of(
+    State(state): State<AppState>,
+    Path((gym, id, rev_id)): Path<(String, String, RevId)>,
+    reader: Option<Principal>,
+) -> Result<Json<LookupObjectResponse>, AppError> {
+    let response =
+        LookupObjectResponse::build_at(&state, &gym, id, rev_id).await?;
+    let response = response.readable_by(reader.as_ref())?;
+
+    Ok(Json(response))
+}
+
+async fn diff_object(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    Query(range): Query<DiffRange>,
+    reader: Option<Principal>,
+) -> Result<Json<DiffResponse>, AppError> {
     let object = Object::lookup(&state, &gym, &id).await?;
-    match object.object_type {
-        ObjectType::Account => {
-            if role == AccountRole::Setter {
-                // only admins can change the role of an Account
-                let patch_changes_role = payload
-                    .operations
-                    .clone()
-                    .into_iter()
-                    .find(|op| op.path().contains("role"));
-                if patch_changes_role.is_some() {
-                    return Err(AppError::NotAuthorized());
-                }
+    policy::authorize_read_history(reader.as_ref(), &ObjectFacts::of(&object))?;
 
-                // otherwise we can change our own account?
-                if id.clone() != created_by.clone() {
-                    return Err(AppError::NotAuthorized());
-                }
-            }
-        }
-        ObjectType::Boulder => {
-            let boulder = Boulder::lookup(&state, &gym, &id).await?;
-            if boulder.is_draft > 0 {
-                // drafts can be edited by any admin/setter
-            } else {
-                // admin and setter of boulder or created by
-                #[allow(clippy::collapsible_if)]
-                if role == AccountRole::Setter {
-                    if !(id.clone() == created_by
-                        || boulder.in_setter(&created_by.clone()))
-                    {
-                        tracing::debug!(
-                            "PATCH: setter cant patch this boulder"
-                        );
-                        return Err(AppError::NotAuthorized());
-                    }
-                }
-            }
-        }
-        ObjectType::Passport => (),
+    if range.from < otp::ZERO_REV_ID || range.from > range.to {
+        return Err(AppError::Query(format!(
+            "diff: invalid revision range {}..{}",
+            range.from, range.to
+        )));
     }
+    // all revisions up to an existing one exist
+    lookup_revision(&state, &gym, &id, range.to).await?;
+
+    let operations = Patch::after_revision(&state, &gym, &id, range.from)
+        .await?
+        .into_iter()
+        .filter(|p| p.revision_id <= range.to)
+        .map(|p| p.operation)
+        .collect();
+
+    Ok(Json(DiffResponse {
+        object_id: id,
+        from_revision_id: range.from,
+        to_revision_id: range.to,
+        operations,
+    }))
+}
+
+async fn blame_object(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    reader: Option<Principal>,
+) -> Result<Json<ObjectBlame>, AppError> {
+    let object = Object::lookup(&state, &gym, &id).await?;
+    policy::authorize_read_history(reader.as_ref(), &ObjectFacts::of(&object))?;
+
+    let blame = ObjectBlame::lookup(&state, &gym, &id).await?;
+    Ok(Json(blame))
+}
+
+/// Check whether the principal may apply the operations to the object
+async fn authorize_patch(
+    state: &AppState,
+    gym: &str,
+    principal: &Principal,
+    object: &Object,
+    operations: &[Operation],
+) -> Result<(), AppError> {
+    let facts = ObjectFacts::lookup(state, gym, object).await?;
+    let paths: Vec<_> = operations.iter().map(Operation::path).collect();
+    policy::authorize(
+        Action::Patch,
+        Some(principal),
+        &facts,
+        paths.iter().map(String::as_str),
+    )
+}
+
+async fn patch_object(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    principal: Principal,
+    Json(payload): axum::extract::Json<PatchObjectBody>,
+) -> Result<Json<PatchObjectResponse>, AppError> {
+    let object = Object::lookup(&state, &gym, &id).await?;
+    authorize_patch(&state, &gym, &principal, &object, &payload.operations)
+        .await?;
 
     tracing::debug!(
         "patch object ({}@{}): {} operations",
@@ -321,7 +713,7 @@ async fn patch_object(
         &gym,
         id,
         payload.revision_id,
-        created_by,
+        principal.account_id,
         payload.operations,
     )
     .await?;
@@ -329,17 +721,132 @@ async fn patch_object(
     Ok(result)
 }
 
+/// patch several objects, either all patches are stored or none
+async fn batch_patch_objects(
+    State(state): State<AppState>,
+    Path(gym): Path<String>,
+    principal: Principal,
+    Json(payload): Json<BatchPatchBody>,
+) -> Result<Json<BatchPatchResponse>, AppError> {
+    if payload.updates.len() > MAX_BATCH_PATCH_OBJECTS {
+        return Err(AppError::Query(format!(
+            "at most {MAX_BATCH_PATCH_OBJECTS} objects per batch"
+        )));
+    }
+    let mut seen = HashSet::new();
+    for update in &payload.updates {
+        if !seen.insert(&update.object_id) {
+            return Err(AppError::Query(format!(
+                "object {} is patched more than once",
+                update.object_id
+            )));
+        }
+
+        let object = Object::lookup(&state, &gym, &update.object_id).await?;
+        authorize_patch(&state, &gym, &principal, &object, &update.operations)
+            .await?;
+    }
+
+    tracing::debug!("batch patch of {} objects", payload.updates.len());
+    let updates: Vec<ObjectUpdates> = payload
+        .updates
+        .into_iter()
+        .map(|u| ObjectUpdates {
+            object_id: u.object_id,
+            revision_id: u.revision_id,
+            operations: u.operations,
+        })
+        .collect();
+    let responses =
+        apply_batch_updates(&state, &gym, principal.account_id, &updates)
+            .await?;
+
+    let results = updates
+        .into_iter()
+        .zip(responses)
+        .map(|(update, response)| BatchPatchResult {
+            object_id: update.object_id,
+            response,
+        })
+        .collect();
+    Ok(Json(BatchPatchResponse { results }))
+}
+
+/// revert the content to an earlier revision by storing new patches
+async fn revert_object(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    principal: Principal,
+    Json(payload): axum::extract::Json<RevertObjectBody>,
+) -> Result<Json<PatchObjectResponse>, AppError> {
+    let object = Object::lookup(&state, &gym, &id).await?;
+    let latest = Snapshot::lookup_latest(&state, &gym, &id).await?;
+    let target =
+        lookup_revision(&state, &gym, &id, payload.revision_id).await?;
+    let operations = otp::diff(&latest.content, &target.content);
+    authorize_patch(&state, &gym, &principal, &object, &operations).await?;
+
+    tracing::debug!(
+        "revert object {id}@{} to {}: {} operations",
+        latest.revision_id,
+        payload.revision_id,
+        operations.len()
+    );
+    apply_object_updates(
+        &state,
+        &gym,
+        id,
+        latest.revision_id,
+        principal.account_id,
+        operations,
+    )
+    .await
+}
+
 async fn lookup_patch(
     State(state): State<AppState>,
     Path((gym, id, rev_id)): Path<(String, String, i64)>,
+    reader: Option<Principal>,
 ) -> Result<Json<Patch>, AppError> {
+    let object = Object::lookup(&state, &gym, &id).await?;
+    policy::authorize_read_history(reader.as_ref(), &ObjectFacts::of(&object))?;
+
     let patch = Patch::lookup(&state, &gym, &id, rev_id).await?;
     Ok(Json(patch))
 }
 
+async fn lookup_patches(
+    State(state): State<AppState>,
+    Path((gym, id)): Path<(String, String)>,
+    Query(range): Query<PatchRange>,
+    reader: Option<Principal>,
+) -> Result<Json<PatchesResponse>, AppError> {
+    let object = Object::lookup(&state, &gym, &id).await?;
+    policy::authorize_read_history(reader.as_ref(), &ObjectFacts::of(&object))?;
+
+    let from = range.from.unwrap_or(otp::ZERO_REV_ID);
+    let limit = range
+        .limit
+        .unwrap_or(PATCHES_PAGE_SIZE)
+        .clamp(1, MAX_PATCHES_PAGE_SIZE);
+
+    // one more to know whether there is a next page
+    let mut patches =
+        Patch::in_range(&state, &gym, &id, (from, range.to), limit + 1).await?;
+
+    let next_from = if patches.len() > limit {
+        patches.pop().map(|p| p.revision_id)
+    } else {
+        None
+    };
+
+    Ok(Json(PatchesResponse { patches, next_from }))
+}
+
 async fn feed(
     State(state): State<AppState>,
     Path(gym): Path<String>,
+    reader: Option<Principal>,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
@@ -351,17 +858,5 @@ async fn feed(
     };
     tracing::debug!("`{user_agent}` at {addr} connected.");
 
-    match state.db.parent_path("gyms", &gym) {
-        Ok(path) => ws
-            .on_upgrade(move |socket| handle_socket(socket, addr, state, path))
-            .into_response(),
-        Err(e) => {
-            tracing::error!("firestore parent_path {gym}: {e:?}");
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("firestore parent_path {gym}: {e:?}"),
-            )
-                .into_response()
-        }
-    }
+    ws.on_upgrade(move |socket| handle_socket(socket, addr, state, gym, reader))
 }
@@ -0,0 +1,100 @@
+use axum::{
+    extract::{FromRequestParts, OptionalFromRequestParts},
+    http::request::Parts,
+};
+use axum_extra::extract::CookieJar;
+use otp::ObjectId;
+
+use crate::{
+    AppError, AppState,
+    passport::Session,
+    routes::KnownGym,
+    types::{AccountRole, AccountsView},
+};
+
+/// The account a request is made by, from the session cookie of the request.
+/// Fails with `NoSession` without a cookie, for an unknown session or for the
+/// session of a missing account.
+pub struct Principal {
+    pub account_id: ObjectId,
+    pub role: AccountRole,
+    pub session: Session,
+}
+
+impl Principal {
+    async fn resolve(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, AppError> {
+        let jar = CookieJar::from_headers(&parts.headers);
+        let Some(session_id) = jar.get("session") else {
+            return Ok(None);
+        };
+        let KnownGym(gym) = KnownGym::from_request_parts(parts, state).await?;
+
+        let session =
+            Session::lookup_active(state, &gym.name, session_id.value())
+                .await?;
+        // the session of a deleted account is not a session anymore
+        let account =
+            AccountsView::with_id(state, &gym.name, session.obj_id.clone())
+                .await?
+                .ok_or(AppError::NoSession())?;
+        Ok(Some(Self {
+            account_id: session.obj_id.clone(),
+            role: account.role,
+            session,
+        }))
+    }
+}
+
+impl FromRequestParts<AppState> for Principal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Self::resolve(parts, state)
+            .await?
+            .ok_or(AppError::NoSession())
+    }
+}
+
+/// `None` without a session, for an unknown (e.g. signed out) session or a
+/// missing account, so a stale cookie does not break routes which are open to
+/// anyone.
+impl OptionalFromRequestParts<AppState> for Principal {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        match Self::resolve(parts, state).await {
+            Err(AppError::NoSession()) => Ok(None),
+            result => result,
+        }
+    }
+}
+
+/// a [`Principal`] with the admin role
+pub struct Admin(pub Principal);
+
+impl FromRequestParts<AppState> for Admin {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let principal = <Principal as FromRequestParts<_>>::from_request_parts(
+            parts, state,
+        )
+        .await?;
+        if principal.role != AccountRole::Admin {
+            return Err(AppError::NotAuthorized());
+        }
+        Ok(Self(principal))
+    }
+}
@@ -4,14 +4,13 @@ use axum::{
     response::Json,
     routing::get,
 };
-use axum_extra::extract::CookieJar;
 use otp::ObjectId;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::{
     AppError, AppState,
-    passport::author_from_session,
+    routes::auth::Principal,
     types::{Account, AccountsView, BouldersView, Snapshot},
 };
 
@@ -103,14 +102,9 @@ async fn draft_boulders(
 async fn own_boulders(
     State(state): State<AppState>,
     Path(gym): Path<String>,
-    jar: CookieJar,
+    principal: Principal,
 ) -> Result<Json<Vec<ObjectId>>, AppError> {
-    let session_id = jar.get("session").ok_or(AppError::NoSession())?;
-    let own = author_from_session(&state, &gym, session_id).await?;
-    // TODO not sure if it is okay to return NotAuthorized
-    // if own == ROOT_OBJ_ID {
-    //     return Ok(Json(Vec::new()));
-    // }
+    let own = principal.account_id;
 
     let as_vec = BouldersView::with_id(&state, &gym, own).await?;
     Ok(Json(
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+
+use axum::{
+    Router,
+    extract::{FromRequestParts, Path, Request, State},
+    http::{HeaderValue, StatusCode, request::Parts},
+    middleware::Next,
+    response::{Json, Response},
+    routing::{get, post, put},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    AppError, AppState, compaction,
+    types::{Gym, GymSettings, GymStatus},
+};
+
+/// The registered and active gym of a `/{gym}/...` route. Unknown gyms are
+/// rejected with 404, suspended ones with 403.
+pub struct KnownGym(pub Gym);
+
+impl FromRequestParts<AppState> for KnownGym {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        // looked up already by the layer of the route
+        if let Some(gym) = parts.extensions.get::<Gym>() {
+            return Ok(Self(gym.clone()));
+        }
+        let Path(params) =
+            Path::<HashMap<String, String>>::from_request_parts(parts, state)
+                .await
+                .map_err(|e| AppError::Query(e.body_text()))?;
+        let name = params
+            .get("gym")
+            .ok_or(AppError::Internal("route without gym".to_string()))?;
+        Ok(Self(Gym::lookup_active(state, name)?))
+    }
+}
+
+/// Whether a browser on `origin` may make requests to `path`. Gyms restrict
+/// this with their allowed origins, other routes are open to any origin.
+pub fn allows_origin(
+    state: &AppState,
+    origin: &HeaderValue,
+    path: &str,
+) -> bool {
+    let Some(name) = path.trim_start_matches('/').split('/').next() else {
+        return true;
+    };
+    let Ok(origin) = origin.to_str() else {
+        return false;
+    };
+    match state.gyms.get(name) {
+        Some(gym) => gym.allows_origin(origin),
+        // unknown gyms are rejected by the routes anyway
+        None => true,
+    }
+}
+
+/// layer of all `/{gym}/...` routes, so no request reaches an unknown gym
+pub async fn require_gym(
+    KnownGym(gym): KnownGym,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    request.extensions_mut().insert(gym);
+    next.run(request).await
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateGymBody {
+    name: String,
+    display_name: String,
+    #[serde(default)]
+    settings: GymSettings,
+}
+
+/// the registry and the server are managed with the `ADMIN_TOKEN` of the
+/// server, there is no account which is admin of all gyms
+pub fn authorize_admin_token(
+    state: &AppState,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<(), AppError> {
+    let expected = state
+        .config
+        .admin_token
+        .as_ref()
+        .ok_or(AppError::NotAuthorized())?;
+    let TypedHeader(Authorization(bearer)) =
+        bearer.ok_or(AppError::NotAuthorized())?;
+    // compare digests to not leak the length or prefix of the token
+    if Sha256::digest(bearer.token()) == Sha256::digest(expected) {
+        Ok(())
+    } else {
+        Err(AppError::NotAuthorized())
+    }
+}
+
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/gyms", get(list_gyms))
+        .route("/gyms", post(create_gym))
+        .route("/gyms/{name}/suspend", post(suspend_gym))
+        .route("/gyms/{name}/activate", post(activate_gym))
+        .route("/gyms/{name}/settings", put(update_settings))
+        .route("/gyms/{name}/compact", post(compact_gym))
+}
+
+async fn list_gyms(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Vec<Gym>>, AppError> {
+    authorize_admin_token(&state, bearer)?;
+    Ok(Json(state.db.registered_gyms().await?))
+}
+
+async fn create_gym(
+    State(state): State<AppState>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(body): Json<CreateGymBody>,
+) -> Result<(StatusCode, Json<Gym>), AppError> {
+    authorize_admin_token(&state, bearer)?;
+    if state.db.lookup_gym(&body.name).await?.is_some() {
+        return Err(AppError::Query(format!("gym {} exists", body.name)));
+    }
+
+    let gym = Gym {
+        settings: body.settings,
+        ..Gym::new(&body.name, &body.display_name)?
+    };
+    Gym::store(&state, &gym).await?;
+    tracing::info!("registered {gym}");
+    Ok((StatusCode::CREATED, Json(gym)))
+}
+
+async fn update_gym(
+    state: &AppState,
+    name: &str,
+    update: impl FnOnce(Gym) -> Gym,
+) -> Result<Gym, AppError> {
+    let gym = state
+        .db
+        .lookup_gym(name)
+        .await?
+        .ok_or(AppError::UnknownGym(name.to_string()))?;
+    let gym = update(gym);
+    Gym::store(state, &gym).await?;
+    tracing::info!("updated {gym}");
+    Ok(gym)
+}
+
+async fn suspend_gym(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Gym>, AppError> {
+    authorize_admin_token(&state, bearer)?;
+    Ok(Json(
+        update_gym(&state, &name, |gym| Gym {
+            status: GymStatus::Suspended,
+            ..gym
+        })
+        .await?,
+    ))
+}
+
+async fn activate_gym(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Gym>, AppError> {
+    authorize_admin_token(&state, bearer)?;
+    Ok(Json(
+        update_gym(&state, &name, |gym| Gym {
+            status: GymStatus::Active,
+            ..gym
+        })
+        .await?,
+    ))
+}
+
+async fn update_settings(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    Json(settings): Json<GymSettings>,
+) -> Result<Json<Gym>, AppError> {
+    authorize_admin_token(&state, bearer)?;
+    Ok(Json(
+        update_gym(&state, &name, |gym| Gym { settings, ..gym }).await?,
+    ))
+}
+
+/// prune the snapshots of the gym, see [`compaction`]
+async fn compact_gym(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Json<Value>, AppError> {
+    authorize_admin_token(&state, bearer)?;
+    let gym = state
+        .db
+        .lookup_gym(&name)
+        .await?
+        .ok_or(AppError::UnknownGym(name))?;
+    let deleted = compaction::compact_gym(&state, &gym.name).await?;
+    tracing::info!("compacted {gym}: {deleted} snapshots");
+    Ok(Json(json!({ "deleted": deleted })))
+}
@@ -1,13 +1,60 @@
+use std::collections::VecDeque;
+
 use axum::Json;
-use otp::{ObjectId, Operation, RevId, rebase};
+use otp::{ObjectId, Operation, RevId, rebase_many};
 use serde_json::Value;
 
 use crate::{
     AppError, AppState,
+    backend::{ViewWrite, WriteBatch},
     routes::PatchObjectResponse,
-    types::{AccountsView, BouldersView, Object, ObjectType, Patch, Snapshot},
+    types::{
+        Object, ObjectType, Patch, Snapshot, SnapshotPolicy, view::view_of,
+    },
 };
 
+/// how often a patch is rebased onto concurrently stored revisions
+pub(crate) const MAX_SAVE_RETRIES: usize = 10;
+
+/// patches stored since the latest snapshot of an object
+struct SinceSnapshot {
+    revision_id: RevId,
+    patch_bytes: usize,
+}
+
+impl SinceSnapshot {
+    fn new(snapshot: &Snapshot, patches: &[Patch]) -> Self {
+        Self {
+            revision_id: snapshot.revision_id,
+            patch_bytes: patches.iter().map(Patch::operation_len).sum(),
+        }
+    }
+
+    fn add(&mut self, patch: &Patch) {
+        self.patch_bytes += patch.operation_len();
+    }
+
+    /// add the patch which created snapshot, returns whether the snapshot is
+    /// due under the policy and starts counting from it if so
+    fn snapshot_due(
+        &mut self,
+        policy: &SnapshotPolicy,
+        snapshot: &Snapshot,
+        patch: &Patch,
+    ) -> bool {
+        self.add(patch);
+        let revisions = snapshot.revision_id - self.revision_id;
+        let due = policy.is_due(revisions, self.patch_bytes);
+        if due {
+            *self = SinceSnapshot {
+                revision_id: snapshot.revision_id,
+                patch_bytes: 0,
+            };
+        }
+        due
+    }
+}
+
 struct SaveOp {
     patch: Patch,
     snapshot: Snapshot,
@@ -15,7 +62,7 @@ struct SaveOp {
 
 pub(crate) async fn update_view(
     state: &AppState,
-    gym: &String,
+    gym: &str,
     object_id: &ObjectId,
     content: &Value,
 ) -> Result<(), AppError> {
@@ -25,73 +72,164 @@ pub(crate) async fn update_view(
 
 pub(crate) async fn update_view_typed(
     state: &AppState,
-    gym: &String,
+    gym: &str,
     object_id: &ObjectId,
     object_type: &ObjectType,
     content: &Value,
 ) -> Result<(), AppError> {
-    match object_type {
-        ObjectType::Account => {
-            AccountsView::store(state, gym, object_id, content).await?
+    match view_write(object_id, object_type, content)? {
+        Some(w) => {
+            state
+                .db
+                .store_view(gym, w.collection, &w.object_id, &w.row)
+                .await
         }
-        ObjectType::Boulder => {
-            BouldersView::store(state, gym, object_id, content).await?
+        None => Ok(()),
+    }
+}
+
+pub(crate) async fn remove_view_typed(
+    state: &AppState,
+    gym: &str,
+    object_id: &ObjectId,
+    object_type: &ObjectType,
+) -> Result<(), AppError> {
+    match view_of(object_type) {
+        Some(view) => {
+            state
+                .db
+                .delete_view(gym, view.collection(), object_id)
+                .await
         }
-        ObjectType::Passport => {
-            // no view table
+        None => Ok(()),
+    }
+}
+
+/// the latest revision of an object and the submitted operations rebased
+/// onto it
+struct Rebased {
+    /// patches the client did not know about when creating the operations
+    previous_patches: Vec<Patch>,
+    snapshot: Snapshot,
+    since_snapshot: SinceSnapshot,
+    pending: VecDeque<Operation>,
+}
+
+/// rebase operations created against revision rev_id of an object through
+/// all patches stored after it
+async fn rebase_onto_latest(
+    state: &AppState,
+    gym: &str,
+    obj_id: &ObjectId,
+    rev_id: RevId,
+    operations: Vec<Operation>,
+) -> Result<Rebased, AppError> {
+    let (checkpoint, patches) =
+        Snapshot::checkpoint(state, gym, obj_id).await?;
+    let since_snapshot = SinceSnapshot::new(&checkpoint, &patches);
+
+    // the 'Snapshot' against which the submitted operations were created
+    // this only contains patches until base_snapshot.revision_id. if there are
+    // any patches which the client doesn't know about we need to let her know
+    let (base_snapshot, previous_patches) = if checkpoint.revision_id <= rev_id
+    {
+        let (known, previous): (Vec<_>, Vec<_>) =
+            patches.into_iter().partition(|p| p.revision_id <= rev_id);
+        (checkpoint.apply_patches(&known)?, previous)
+    } else {
+        // the client is behind the latest stored snapshot
+        (
+            Snapshot::lookup(state, gym, obj_id, rev_id).await?,
+            Patch::after_revision(state, gym, obj_id, rev_id).await?,
+        )
+    };
+    let snapshot = base_snapshot.apply_patches(&previous_patches)?;
+
+    // rebase all submitted operations through the previous patches at once
+    let pending = match rebase_many(
+        base_snapshot.content,
+        operations,
+        previous_patches.iter().map(|p| &p.operation),
+    ) {
+        Ok(rebased_ops) => accepted(rebased_ops),
+        Err(e) => {
+            tracing::error!("rebase failed with error: {e}");
+            // TODO error? or skip?
+            VecDeque::new()
         }
     };
 
-    Ok(())
+    Ok(Rebased {
+        previous_patches,
+        snapshot,
+        since_snapshot,
+        pending,
+    })
 }
 
 pub async fn apply_object_updates(
     state: &AppState,
-    gym: &String,
+    gym: &str,
     obj_id: ObjectId,
     rev_id: RevId,
     author: ObjectId,
     operations: Vec<Operation>,
 ) -> Result<Json<PatchObjectResponse>, AppError> {
-    // the 'Snapshot' against which the submitted operations were created
-    // this only contains patches until base_snapshot.revision_id
-    let base_snapshot = Snapshot::lookup(state, gym, &obj_id, rev_id).await?;
-
-    // if there are any patches which the client doesn't know about we need
-    // to let her know
-    let previous_patches =
-        Patch::after_revision(state, gym, &obj_id, rev_id).await?;
-    let latest_snapshot = base_snapshot.apply_patches(&previous_patches)?;
-
-    let (patches, num_processed, snapshot) = {
-        let mut patches = Vec::<Patch>::new();
-        let mut num_processed = 0;
-        let mut snapshot = latest_snapshot;
-        for op in operations {
-            match save_operation(
-                state,
-                gym,
-                author.clone(),
-                (base_snapshot.content).clone(),
-                &snapshot,
-                previous_patches.iter().chain(patches.iter()),
-                op,
-            )
-            .await
+    let num_processed = operations.len();
+    let Rebased {
+        mut previous_patches,
+        mut snapshot,
+        mut since_snapshot,
+        mut pending,
+    } = rebase_onto_latest(state, gym, &obj_id, rev_id, operations).await?;
+
+    let mut patches = Vec::<Patch>::new();
+    let mut retries = 0;
+    while let Some(op) = pending.pop_front() {
+        match save_operation(
+            state,
+            gym,
+            author.clone(),
+            &snapshot,
+            &mut since_snapshot,
+            op.clone(),
+        )
+        .await
+        {
+            Ok(Some(saved)) => {
+                patches.push(saved.patch);
+                snapshot = saved.snapshot;
+            }
+            Ok(None) => {}
+            Err(AppError::RevisionConflict(_, rev_id))
+                if retries < MAX_SAVE_RETRIES =>
             {
-                Err(e) => return Err(e),
-                Ok(saved) => {
-                    num_processed += 1;
-                    if let Some(saved) = saved {
-                        patches.push(saved.patch);
-                        snapshot = saved.snapshot;
-                    }
-                }
+                // another request stored this revision first: rebase the
+                // remaining operations through its patches and try again
+                retries += 1;
+                tracing::debug!("revision {rev_id} taken, rebasing");
+                let concurrent = Patch::after_revision(
+                    state,
+                    gym,
+                    &obj_id,
+                    snapshot.revision_id,
+                )
+                .await?;
+                pending.push_front(op);
+                pending = accepted(rebase_many(
+                    snapshot.content.clone(),
+                    pending.drain(..),
+                    concurrent.iter().map(|p| &p.operation),
+                )?);
+                snapshot = snapshot.apply_patches(&concurrent)?;
+                concurrent.iter().for_each(|p| since_snapshot.add(p));
+                previous_patches.extend(concurrent);
             }
+            Err(e) => return Err(e),
         }
-        (patches, num_processed, snapshot)
-    };
+    }
 
+    state.snapshots.advance(gym, &snapshot);
     update_view(state, gym, &snapshot.object_id, &snapshot.content).await?;
 
     Ok(Json(PatchObjectResponse::new(
@@ -101,44 +239,180 @@ pub async fn apply_object_updates(
     )))
 }
 
-/// Rebase and then apply the operation to the snapshot to get a new snapshot
-/// Returns `None` if the rebasing fails or applying the (rebased) operation
-/// yields the same snapshot.
+/// operations on one object of a batch, created against revision_id
+pub struct ObjectUpdates {
+    pub object_id: ObjectId,
+    pub revision_id: RevId,
+    pub operations: Vec<Operation>,
+}
+
+/// an object of a batch with its new revisions, not yet committed
+struct PreparedUpdates {
+    previous_patches: Vec<Patch>,
+    num_processed: usize,
+    num_patches: usize,
+    snapshot: Snapshot,
+}
+
+/// Apply operations to several objects at once. The operations of each object
+/// are rebased like in [`apply_object_updates`], but all resulting patches,
+/// snapshots and view updates are committed together or not at all. If
+/// another request stores a revision first, the whole batch is rebased again.
+pub async fn apply_batch_updates(
+    state: &AppState,
+    gym: &str,
+    author: ObjectId,
+    updates: &[ObjectUpdates],
+) -> Result<Vec<PatchObjectResponse>, AppError> {
+    let mut retries = 0;
+    loop {
+        let mut batch = WriteBatch::default();
+        let mut prepared = Vec::with_capacity(updates.len());
+        for update in updates {
+            prepared.push(
+                prepare_updates(state, gym, &author, update, &mut batch)
+                    .await?,
+            );
+        }
+
+        match state.db.commit(gym, &batch).await {
+            Ok(stored) => {
+                let mut stored = stored.into_iter();
+                let responses = prepared
+                    .into_iter()
+                    .map(|p| {
+                        state.snapshots.advance(gym, &p.snapshot);
+                        PatchObjectResponse::new(
+                            p.previous_patches,
+                            p.num_processed,
+                            stored.by_ref().take(p.num_patches).collect(),
+                        )
+                    })
+                    .collect();
+                return Ok(responses);
+            }
+            Err(AppError::RevisionConflict(obj_id, rev_id))
+                if retries < MAX_SAVE_RETRIES =>
+            {
+                retries += 1;
+                tracing::debug!(
+                    "revision {rev_id} of {obj_id} taken, rebasing"
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// add the new revisions of one object of a batch to the writes
+async fn prepare_updates(
+    state: &AppState,
+    gym: &str,
+    author: &ObjectId,
+    update: &ObjectUpdates,
+    batch: &mut WriteBatch,
+) -> Result<PreparedUpdates, AppError> {
+    let obj = Object::lookup(state, gym, &update.object_id).await?;
+    let Rebased {
+        previous_patches,
+        mut snapshot,
+        mut since_snapshot,
+        pending,
+    } = rebase_onto_latest(
+        state,
+        gym,
+        &update.object_id,
+        update.revision_id,
+        update.operations.clone(),
+    )
+    .await?;
+
+    let mut num_patches = 0;
+    for op in pending {
+        let Some((next, patch)) = snapshot.new_revision(author.clone(), op)?
+        else {
+            continue;
+        };
+        if since_snapshot.snapshot_due(&state.snapshot_policy, &next, &patch) {
+            batch.snapshots.push(next.clone());
+        }
+        batch.patches.push(patch);
+        num_patches += 1;
+        snapshot = next;
+    }
+    batch.views.extend(view_write(
+        &obj.id,
+        &obj.object_type,
+        &snapshot.content,
+    )?);
+
+    Ok(PreparedUpdates {
+        previous_patches,
+        num_processed: update.operations.len(),
+        num_patches,
+        snapshot,
+    })
+}
+
+/// the view row of an object with the content, `None` for types without view
+fn view_write(
+    object_id: &ObjectId,
+    object_type: &ObjectType,
+    content: &Value,
+) -> Result<Option<ViewWrite>, AppError> {
+    view_of(object_type)
+        .map(|view| {
+            Ok(ViewWrite {
+                collection: view.collection(),
+                object_id: object_id.clone(),
+                row: view.project(object_id, content)?,
+            })
+        })
+        .transpose()
+}
+
+/// drop the operations rejected by a rebase
+fn accepted(rebased_ops: Vec<Option<Operation>>) -> VecDeque<Operation> {
+    rebased_ops
+        .into_iter()
+        .filter_map(|op| {
+            if op.is_none() {
+                // TODO better error, log op, base_content
+                tracing::warn!("rebase failed due to a conflict");
+            }
+            op
+        })
+        .collect()
+}
+
+/// Apply the (rebased) operation to the snapshot to get a new snapshot.
+/// Returns `None` if applying the operation yields the same snapshot. The
+/// patch is stored first, so a concurrent request which already took the
+/// revision makes this fail with [`AppError::RevisionConflict`]. The new
+/// snapshot is only stored when the snapshot policy says so.
 async fn save_operation(
     state: &AppState,
-    gym: &String,
+    gym: &str,
     author_id: ObjectId,
-    base_content: Value,
     snapshot: &Snapshot,
-    previous_patches: impl Iterator<Item = &Patch>,
+    since_snapshot: &mut SinceSnapshot,
     op: Operation,
 ) -> Result<Option<SaveOp>, AppError> {
-    let rebased_op = match rebase(
-        base_content,
-        op,
-        previous_patches.map(|p| &p.operation),
-    ) {
-        Ok(Some(rebased_op)) => rebased_op,
-        Ok(None) => {
-            // TODO better error, log op, base_content
-            tracing::warn!("rebase failed due to a conflict");
-            return Ok(None);
-        }
-        Err(e) => {
-            tracing::error!("rebase failed with error: {e}");
-            // TODO error? or skip?
-            return Ok(None);
-        }
-    };
-
-    match snapshot.new_revision(author_id, rebased_op)? {
+    match snapshot.new_revision(author_id, op)? {
         None => Ok(None),
         Some((new_snapshot, patch)) => {
-            let s = new_snapshot.store(state, gym).await?;
             let p = patch.store(state, gym).await?;
+            if since_snapshot.snapshot_due(
+                &state.snapshot_policy,
+                &new_snapshot,
+                &p,
+            ) {
+                new_snapshot.store(state, gym).await?;
+            }
+
             Ok(Some(SaveOp {
                 patch: p,
-                snapshot: s,
+                snapshot: new_snapshot,
             }))
         }
     }
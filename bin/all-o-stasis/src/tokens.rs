@@ -0,0 +1,68 @@
+//! Keyed hashes of secret tokens.
+//!
+//! Session ids and passport confirmation tokens grant access to an account,
+//! so only their HMAC-SHA256 under the `TOKEN_KEY` of the server is stored.
+//! A leaked database does not reveal usable tokens without the key.
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+fn mac(key: &[u8], token: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes keys of any length, longer ones are hashed first
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)
+        .unwrap_or_else(|_| unreachable!("HMAC keys have no length limit"));
+    mac.update(token);
+    mac
+}
+
+/// the hash of token which is stored instead of it
+pub fn keyed_hash(key: &str, token: &str) -> String {
+    hex::encode(
+        mac(key.as_bytes(), token.as_bytes())
+            .finalize()
+            .into_bytes(),
+    )
+}
+
+/// whether token hashes to hash, in constant time
+pub fn verify(key: &str, token: &str, hash: &str) -> bool {
+    hex::decode(hash).is_ok_and(|hash| {
+        mac(key.as_bytes(), token.as_bytes())
+            .verify_slice(&hash)
+            .is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_test_vectors() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            keyed_hash("Jefe", "what do ya want for nothing?")
+        );
+        assert_eq!(
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            hex::encode(
+                mac(
+                    &[0xaa; 131],
+                    b"Test Using Larger Than Block-Size Key - Hash Key First"
+                )
+                .finalize()
+                .into_bytes()
+            )
+        );
+    }
+
+    #[test]
+    fn verify_tokens() {
+        let hash = keyed_hash("key", "token");
+        assert!(verify("key", "token", &hash));
+        assert!(!verify("key", "other", &hash));
+        assert!(!verify("other", "token", &hash));
+        assert!(!verify("key", "token", "token"));
+    }
+}
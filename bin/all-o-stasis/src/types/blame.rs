@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use otp::{
+    ObjectId, Path, RevId, ZERO_REV_ID, is_subpath, leaf_paths, value_at,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{AppError, AppState, types::Patch};
+
+/// The last change to a single leaf path of an object
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldBlame {
+    pub revision_id: RevId,
+    pub author_id: ObjectId,
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl FieldBlame {
+    fn new(patch: &Patch) -> Self {
+        Self {
+            revision_id: patch.revision_id,
+            author_id: patch.author_id.clone(),
+            created_at: patch.created_at,
+        }
+    }
+}
+
+/// Attribution of every leaf path in the latest content of an object to the
+/// patch which changed it last
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObjectBlame {
+    pub object_id: ObjectId,
+    pub revision_id: RevId,
+    pub fields: BTreeMap<Path, FieldBlame>,
+}
+
+impl ObjectBlame {
+    pub async fn lookup(
+        state: &AppState,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Self, AppError> {
+        // replay all patches, including the initial one
+        let patches =
+            Patch::after_revision(state, gym, object_id, ZERO_REV_ID - 1)
+                .await?;
+        Self::from_patches(object_id.clone(), &patches)
+    }
+
+    /// replay patches (ordered by revision) starting from empty content
+    pub fn from_patches(
+        object_id: ObjectId,
+        patches: &[Patch],
+    ) -> Result<Self, AppError> {
+        let mut content = json!({});
+        let mut revision_id = ZERO_REV_ID - 1;
+        let mut fields: BTreeMap<Path, FieldBlame> = BTreeMap::new();
+
+        for patch in patches {
+            let next = patch.operation.apply_to(content.clone())?;
+            let path = patch.operation.path();
+
+            // leaves below the changed path are recomputed and parents of the
+            // changed path can not be leaves anymore
+            let (mut previous, rest): (BTreeMap<_, _>, BTreeMap<_, _>) =
+                fields.into_iter().partition(|(p, _)| is_subpath(p, &path));
+            fields = rest
+                .into_iter()
+                .filter(|(p, _)| !is_subpath(&path, p))
+                .collect();
+
+            let leaves = value_at(&path, &next)
+                .map(|v| leaf_paths(&path, v))
+                .unwrap_or_default();
+            for leaf in leaves {
+                let before = value_at(&leaf, &content);
+                let after = value_at(&leaf, &next);
+                let blame = match previous.remove(&leaf) {
+                    Some(blame) if before == after => blame,
+                    _ => FieldBlame::new(patch),
+                };
+                fields.insert(leaf, blame);
+            }
+
+            content = next;
+            revision_id = patch.revision_id;
+        }
+
+        Ok(Self {
+            object_id,
+            revision_id,
+            fields,
+        })
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{PoisonError, RwLock},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    AppError, AppState,
+    backend::Storage,
+    config::{Environment, ServerConfig},
+};
+
+/// how often the registry is reloaded to see changes by other instances
+const REGISTRY_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// production gyms whose frontend was hard-coded before gyms had settings
+const LEGACY_FRONTEND_URLS: [(&str, &str); 1] =
+    [("leutsch", "https://minimum-leutsch.boulderhalle.app")];
+
+/// path segments of routes which are not gyms
+const RESERVED_NAMES: [&str; 4] = ["cache", "gyms", "healthz", "revision"];
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum GymStatus {
+    Active,
+    /// all requests to the gym are rejected, its data is kept
+    Suspended,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Locale {
+    #[default]
+    En,
+    De,
+}
+
+/// Settings of a gym, unset ones fall back to the server configuration.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GymSettings {
+    /// where users land after confirming a login
+    pub frontend_url: Option<String>,
+    /// sender of login mails, the display name of the gym if unset
+    pub email_sender_name: Option<String>,
+    /// language of login mails
+    pub locale: Locale,
+    /// origins allowed to make cross-origin requests, any if empty
+    pub allowed_origins: Vec<String>,
+}
+
+/// A gym in the registry. Only registered gyms are served, so a typo in a URL
+/// does not create a new gym.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Gym {
+    /// the name used in URLs and storage paths
+    pub name: String,
+    pub display_name: String,
+    pub status: GymStatus,
+    pub created_at: DateTime<Utc>,
+    /// missing in gyms registered before settings existed
+    #[serde(default)]
+    pub settings: GymSettings,
+}
+
+/// The registered gyms in memory, every request looks up its gym. Changes by
+/// the admin routes of this instance apply immediately, the ones of other
+/// instances once the registry is reloaded.
+#[derive(Default)]
+pub struct GymRegistry {
+    gyms: RwLock<HashMap<String, Gym>>,
+}
+
+impl GymRegistry {
+    pub fn get(&self, name: &str) -> Option<Gym> {
+        let gyms = self.gyms.read().unwrap_or_else(PoisonError::into_inner);
+        gyms.get(name).cloned()
+    }
+
+    /// the names of all registered gyms, sorted
+    pub fn names(&self) -> Vec<String> {
+        let gyms = self.gyms.read().unwrap_or_else(PoisonError::into_inner);
+        let mut names: Vec<_> = gyms.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let gyms = self.gyms.read().unwrap_or_else(PoisonError::into_inner);
+        gyms.is_empty()
+    }
+
+    fn insert(&self, gym: Gym) {
+        let mut gyms =
+            self.gyms.write().unwrap_or_else(PoisonError::into_inner);
+        gyms.insert(gym.name.clone(), gym);
+    }
+
+    /// replace the gyms in memory with the stored ones
+    pub async fn reload(&self, db: &dyn Storage) -> Result<(), AppError> {
+        let stored = db
+            .registered_gyms()
+            .await?
+            .into_iter()
+            .map(|gym| (gym.name.clone(), gym))
+            .collect();
+        *self.gyms.write().unwrap_or_else(PoisonError::into_inner) = stored;
+        Ok(())
+    }
+
+    /// reload the registry periodically
+    pub async fn follow(state: AppState) {
+        let start = tokio::time::Instant::now() + REGISTRY_RELOAD_INTERVAL;
+        let mut ticks =
+            tokio::time::interval_at(start, REGISTRY_RELOAD_INTERVAL);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = state.gyms.reload(state.db.as_ref()).await {
+                tracing::warn!("reloading the gym registry failed: {e:?}");
+            }
+        }
+    }
+}
+
+impl fmt::Display for Gym {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Gym: {} status={:?}", self.name, self.status)
+    }
+}
+
+/// names are lowercase letters, digits and dashes, not starting with a dash
+fn validate_name(name: &str) -> Result<(), AppError> {
+    let valid = !name.is_empty()
+        && name.len() <= 64
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !RESERVED_NAMES.contains(&name);
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::Query(format!("invalid gym name: {name}")))
+    }
+}
+
+impl Gym {
+    pub fn new(name: &str, display_name: &str) -> Result<Self, AppError> {
+        validate_name(name)?;
+        Ok(Self {
+            name: name.to_string(),
+            display_name: display_name.to_string(),
+            status: GymStatus::Active,
+            created_at: Utc::now(),
+            settings: GymSettings::default(),
+        })
+    }
+
+    /// where users of the gym land after confirming a login
+    pub fn frontend_url(&self, config: &ServerConfig) -> String {
+        self.settings
+            .frontend_url
+            .clone()
+            .unwrap_or_else(|| config.frontend_url_of(&self.name))
+    }
+
+    pub fn email_sender_name(&self) -> &str {
+        self.settings
+            .email_sender_name
+            .as_deref()
+            .unwrap_or(&self.display_name)
+    }
+
+    /// whether a browser on `origin` may make requests to the gym
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.settings.allowed_origins.is_empty()
+            || self.settings.allowed_origins.iter().any(|o| o == origin)
+    }
+
+    /// the registered gym, fails if it is unknown or suspended
+    pub fn lookup_active(
+        state: &AppState,
+        name: &str,
+    ) -> Result<Self, AppError> {
+        let gym = state
+            .gyms
+            .get(name)
+            .ok_or(AppError::UnknownGym(name.to_string()))?;
+        match gym.status {
+            GymStatus::Active => Ok(gym),
+            GymStatus::Suspended => Err(AppError::GymSuspended(gym.name)),
+        }
+    }
+
+    /// create or replace the gym in the registry
+    pub async fn store(state: &AppState, gym: &Gym) -> Result<(), AppError> {
+        state.db.store_gym(gym).await?;
+        state.gyms.insert(gym.clone());
+        Ok(())
+    }
+
+    /// Register a gym unless it is registered already. Returns whether it was
+    /// added.
+    pub async fn register(
+        state: &AppState,
+        name: &str,
+    ) -> Result<bool, AppError> {
+        if state.db.lookup_gym(name).await?.is_some() {
+            return Ok(false);
+        }
+        Gym::store(state, &Gym::new(name, name)?).await?;
+        Ok(true)
+    }
+
+    /// Register all gyms with data if the registry is empty, for deployments
+    /// from before the registry existed.
+    /// Scans all objects, so only done while the registry is empty.
+    pub async fn seed_registry(state: &AppState) -> Result<(), AppError> {
+        if !state.gyms.is_empty() {
+            return Ok(());
+        }
+        for name in state.db.gyms().await? {
+            match Gym::register(state, &name).await {
+                Ok(_) => tracing::info!("registered existing gym {name}"),
+                Err(e) => tracing::warn!(
+                    "not registering gym {name}: {}",
+                    e.status_and_message().1
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the frontend URL of production gyms which had theirs hard-coded,
+    /// unless it was set already. Returns the number of updated gyms.
+    pub async fn migrate_frontend_urls(
+        state: &AppState,
+    ) -> Result<usize, AppError> {
+        if state.config.environment != Environment::Production {
+            return Ok(0);
+        }
+        let mut migrated = 0;
+        for (name, frontend_url) in LEGACY_FRONTEND_URLS {
+            let Some(gym) = state.gyms.get(name) else {
+                continue;
+            };
+            if gym.settings.frontend_url.is_some() {
+                continue;
+            }
+            let settings = GymSettings {
+                frontend_url: Some(frontend_url.to_string()),
+                ..gym.settings
+            };
+            Gym::store(state, &Gym { settings, ..gym }).await?;
+            migrated += 1;
+        }
+        Ok(migrated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn gym_names() {
+        for name in ["kletterhalle", "minimum-leutsch", "gym42"] {
+            assert!(validate_name(name).is_ok(), "{name}");
+        }
+        for name in ["", "-gym", "Gym", "gym/x", "gym name", "gyms", "cache"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn settings_fall_back_to_server_config() {
+        let config = ServerConfig::default();
+        let mut gym = Gym::new("leutsch", "Minimum Leutsch").unwrap();
+        assert_eq!(
+            "https://leutsch.boulderhalle.app",
+            gym.frontend_url(&config)
+        );
+        assert_eq!("Minimum Leutsch", gym.email_sender_name());
+        assert!(gym.allows_origin("https://example.com"));
+
+        // registered before settings existed
+        let value = serde_json::json!({
+            "name": "leutsch",
+            "displayName": "Minimum Leutsch",
+            "status": "active",
+            "createdAt": "2025-01-01T00:00:00Z",
+        });
+        let stored: Gym = serde_json::from_value(value).unwrap();
+        assert_eq!(Locale::En, stored.settings.locale);
+
+        gym.settings = serde_json::from_value(serde_json::json!({
+            "frontendUrl": "https://minimum-leutsch.boulderhalle.app",
+            "locale": "de",
+            "allowedOrigins": ["https://minimum-leutsch.boulderhalle.app"],
+        }))
+        .unwrap();
+        assert_eq!(
+            "https://minimum-leutsch.boulderhalle.app",
+            gym.frontend_url(&config)
+        );
+        assert_eq!(Locale::De, gym.settings.locale);
+        assert!(gym.allows_origin("https://minimum-leutsch.boulderhalle.app"));
+        assert!(!gym.allows_origin("https://example.com"));
+    }
+
+    #[tokio::test]
+    async fn registry_serves_stored_gyms() {
+        let state = AppState::in_memory();
+        assert!(matches!(
+            Gym::lookup_active(&state, "test"),
+            Err(AppError::UnknownGym(_))
+        ));
+        assert!(Gym::register(&state, "test").await.unwrap());
+        assert!(Gym::lookup_active(&state, "test").is_ok());
+        assert_eq!(vec![String::from("test")], state.gyms.names());
+
+        // suspended by another instance
+        let suspended = Gym {
+            status: GymStatus::Suspended,
+            ..Gym::new("test", "Test").unwrap()
+        };
+        state.db.store_gym(&suspended).await.unwrap();
+        assert!(Gym::lookup_active(&state, "test").is_ok());
+        state.gyms.reload(state.db.as_ref()).await.unwrap();
+        assert!(matches!(
+            Gym::lookup_active(&state, "test"),
+            Err(AppError::GymSuspended(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn legacy_frontend_urls_are_kept() {
+        let production = ServerConfig {
+            environment: Environment::Production,
+            ..ServerConfig::default()
+        };
+        let state = AppState {
+            config: Arc::new(production),
+            ..AppState::in_memory()
+        };
+        Gym::register(&state, "leutsch").await.unwrap();
+        Gym::register(&state, "other").await.unwrap();
+
+        assert_eq!(1, Gym::migrate_frontend_urls(&state).await.unwrap());
+        let leutsch = state.db.lookup_gym("leutsch").await.unwrap().unwrap();
+        assert_eq!(
+            "https://minimum-leutsch.boulderhalle.app",
+            leutsch.frontend_url(&state.config)
+        );
+        assert_eq!(
+            "https://other.boulderhalle.app",
+            Gym::lookup_active(&state, "other")
+                .unwrap()
+                .frontend_url(&state.config)
+        );
+        // settings by an admin are kept
+        assert_eq!(0, Gym::migrate_frontend_urls(&state).await.unwrap());
+    }
+}
@@ -1,46 +1,26 @@
 use std::fmt;
 
-use firestore::{FirestoreQueryDirection, FirestoreResult, path_camel_case};
-use futures::{TryStreamExt, stream::BoxStream};
 use otp::ObjectId;
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, from_value};
+use serde_json::{Value, json};
 
 use crate::{AppError, AppState};
 
+pub mod blame;
+pub mod gym;
 pub mod object;
 pub mod patch;
 pub mod snapshot;
+pub mod view;
 
+pub use blame::ObjectBlame;
+pub use gym::{Gym, GymRegistry, GymSettings, GymStatus, Locale};
 pub use object::Object;
 pub use patch::Patch;
-pub use snapshot::Snapshot;
+pub use snapshot::{Snapshot, SnapshotPolicy};
+pub use view::{Filter, View, ViewQuery};
 
-macro_rules! store {
-    ($state:expr, $gym:expr, $entity:expr, $collection:expr) => {{
-        let parent_path = $state.db.parent_path("gyms", $gym)?;
-        let result = $state
-            .db
-            .fluent()
-            .insert()
-            .into($collection)
-            .generate_document_id()
-            .parent(&parent_path)
-            .object($entity)
-            .execute()
-            .await?;
-
-        match &result {
-            Some(r) => tracing::debug!("storing: {r}"),
-            None => tracing::warn!("failed to store: {}", $entity),
-        }
-
-        result
-    }};
-}
-pub(crate) use store;
-
-#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum AccountRole {
     User,
@@ -66,7 +46,7 @@ impl fmt::Display for ObjectType {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Account {
     #[serde(alias = "_firestore_id")]
@@ -79,7 +59,7 @@ pub struct Account {
     pub name: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Boulder {
     #[serde(alias = "_firestore_id")]
@@ -113,24 +93,12 @@ impl fmt::Display for Boulder {
 }
 
 impl Boulder {
-    pub fn in_setter(&self, setter: &ObjectId) -> bool {
-        self.setter.contains(setter)
-    }
-
     pub async fn lookup(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: &ObjectId,
     ) -> Result<Boulder, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        state
-            .db
-            .fluent()
-            .select()
-            .by_id_in(BouldersView::COLLECTION)
-            .parent(&parent_path)
-            .obj()
-            .one(&object_id)
+        view::lookup(state, gym, &BouldersView, object_id)
             .await?
             .ok_or(AppError::Query(format!(
                 "lookup_boulder: failed to get boulder {object_id}"
@@ -138,74 +106,45 @@ impl Boulder {
     }
 }
 
-pub struct AccountsView {}
+pub struct AccountsView;
 
-impl AccountsView {
-    const COLLECTION: &str = "accounts_view";
+impl View for AccountsView {
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Account
+    }
 
-    pub async fn store(
-        state: &AppState,
-        gym: &String,
-        object_id: &ObjectId,
-        content: &Value,
-    ) -> Result<(), AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let account = from_value::<Account>(content.clone())
-            .map_err(|e| AppError::ParseError(format!("{e} in: {content}")))?;
+    fn collection(&self) -> &'static str {
+        "accounts_view"
+    }
 
-        let _: Option<Boulder> = state
-            .db
-            .fluent()
-            .update()
-            .in_col(Self::COLLECTION)
-            .document_id(object_id.clone())
-            .parent(parent_path)
-            .object(&account)
-            .execute()
-            .await?;
+    fn indexed_fields(&self) -> &'static [&'static str] {
+        &["role", "email"]
+    }
 
-        Ok(())
+    fn project(
+        &self,
+        object_id: &ObjectId,
+        content: &Value,
+    ) -> Result<Value, AppError> {
+        view::project_as::<Account>(object_id, content)
     }
+}
 
+impl AccountsView {
     pub async fn all(
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Vec<Account>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Account>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-        let as_vec = object_stream.try_collect().await?;
-        Ok(as_vec)
+        view::query(state, gym, &AccountsView, &ViewQuery::default()).await
     }
 
     pub async fn admins(
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Vec<Account>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Account>> = state
-            .db
-            .fluent()
-            .select()
-            .from(AccountsView::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([q
-                    .field(path_camel_case!(Account::role))
-                    .neq(AccountRole::User)])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-        let as_vec = object_stream.try_collect().await?;
-        Ok(as_vec)
+        let query = ViewQuery::default()
+            .filter(Filter::Ne("role", json!(AccountRole::User)));
+        view::query(state, gym, &AccountsView, &query).await
     }
 
     pub async fn with_email(
@@ -213,213 +152,92 @@ impl AccountsView {
         gym: String,
         email: String,
     ) -> Result<Option<Account>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym.clone())?;
-
-        let account_stream: BoxStream<FirestoreResult<Account>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([q
-                    .field(path_camel_case!(Account::email))
-                    .eq(email.clone())])
-            })
-            .limit(1)
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let mut accounts: Vec<Account> = account_stream.try_collect().await?;
+        let query = ViewQuery::default()
+            .filter(Filter::Eq("email", json!(email)))
+            .limit(1);
+        let mut accounts =
+            view::query(state, &gym, &AccountsView, &query).await?;
         Ok(accounts.pop())
     }
 
     pub async fn with_id(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: ObjectId,
-    ) -> Result<Account, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        state
-            .db
-            .fluent()
-            .select()
-            .by_id_in(Self::COLLECTION)
-            .parent(&parent_path)
-            .obj()
-            .one(object_id.clone())
-            .await?
-            .ok_or(AppError::Query(format!(
-                "lookup accounts view: failed to get object {object_id}"
-            )))
+    ) -> Result<Option<Account>, AppError> {
+        view::lookup(state, gym, &AccountsView, &object_id).await
     }
 }
 
-pub struct BouldersView {}
+pub struct BouldersView;
 
-impl BouldersView {
-    const COLLECTION: &str = "boulders_view";
+impl View for BouldersView {
+    fn object_type(&self) -> ObjectType {
+        ObjectType::Boulder
+    }
 
-    pub async fn store(
-        state: &AppState,
-        gym: &String,
-        object_id: &ObjectId,
-        content: &Value,
-    ) -> Result<(), AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let boulder = from_value::<Boulder>(content.clone())
-            .map_err(|e| AppError::ParseError(format!("{e} in: {content}")))?;
+    fn collection(&self) -> &'static str {
+        "boulders_view"
+    }
 
-        let _: Option<Boulder> = state
-            .db
-            .fluent()
-            .update()
-            .in_col(Self::COLLECTION)
-            .document_id(object_id.clone())
-            .parent(parent_path)
-            .object(&boulder)
-            .execute()
-            .await?;
+    fn indexed_fields(&self) -> &'static [&'static str] {
+        &["removed", "isDraft", "setDate"]
+    }
 
-        Ok(())
+    fn project(
+        &self,
+        object_id: &ObjectId,
+        content: &Value,
+    ) -> Result<Value, AppError> {
+        view::project_as::<Boulder>(object_id, content)
     }
+}
 
+impl BouldersView {
+    /// neither removed nor drafts, most recently set first
     pub async fn active(
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Vec<Boulder>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Boulder>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([
-                    q.field(path_camel_case!(Boulder::removed)).eq(0),
-                    q.field(path_camel_case!(Boulder::is_draft)).eq(0),
-                ])
-            })
-            .order_by([(
-                path_camel_case!(Boulder::set_date),
-                FirestoreQueryDirection::Descending,
-            )])
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let boulders: Vec<Boulder> = object_stream.try_collect().await?;
-        Ok(boulders)
+        let query = ViewQuery::default()
+            .filter(Filter::Eq("removed", json!(0)))
+            .filter(Filter::Eq("isDraft", json!(0)))
+            .order_by_desc("setDate");
+        view::query(state, gym, &BouldersView, &query).await
     }
 
     pub async fn with_id(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: ObjectId,
     ) -> Result<Vec<Boulder>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Boulder>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([q
-                    .field(path_camel_case!(Boulder::id))
-                    .eq(object_id.to_owned())])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let as_vec: Vec<Boulder> = object_stream.try_collect().await?;
-        Ok(as_vec)
+        let boulder =
+            view::lookup(state, gym, &BouldersView, &object_id).await?;
+        Ok(boulder.into_iter().collect())
     }
 
+    /// drafts which are not removed
     pub async fn drafts(
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Vec<Boulder>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
         // XXX we used to have a separate collection for draft boulders but
-        // never used it in the (old) code. Here we choose to follow the
-        // old implementation and do not add a collection for draft
-        // boulders.
-        let object_stream: BoxStream<FirestoreResult<Boulder>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([
-                    q.field(path_camel_case!(Boulder::removed)).eq(0),
-                    q.field(path_camel_case!(Boulder::is_draft)).neq(0),
-                ])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let as_vec: Vec<Boulder> = object_stream.try_collect().await?;
-        Ok(as_vec)
+        // never used it in the (old) code. Here we choose to follow the old
+        // implementation and do not add a collection for draft boulders.
+        let query = ViewQuery::default()
+            .filter(Filter::Eq("removed", json!(0)))
+            .filter(Filter::Ne("isDraft", json!(0)));
+        view::query(state, gym, &BouldersView, &query).await
     }
 
+    /// all boulders which are not drafts
     pub async fn stats(
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Vec<Boulder>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
         // TODO this is too expensive: we read all records to compute the stats
-        let object_stream: BoxStream<FirestoreResult<Boulder>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([q.field(path_camel_case!(Boulder::is_draft)).eq(0)])
-            })
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let as_vec: Vec<Boulder> = object_stream.try_collect().await?;
-        Ok(as_vec)
+        let query =
+            ViewQuery::default().filter(Filter::Eq("isDraft", json!(0)));
+        view::query(state, gym, &BouldersView, &query).await
     }
-
-    // pub async fn collect(
-    //     state: &AppState,
-    //     gym: &String,
-    //     removed: Option<Bool>,
-    //     is_draft: Option<Bool>,
-    // ) -> Result<Vec<Boulder>, AppError> {
-    //     let parent_path = state.db.parent_path("gyms", gym)?;
-    //     let object_stream: BoxStream<FirestoreResult<Boulder>> = state
-    //         .db
-    //         .fluent()
-    //         .select()
-    //         .from(Self::COLLECTION)
-    //         .parent(&parent_path)
-    //         .filter(|q| {
-    //             q.for_all(
-    //                 [
-    //                     removed.map(|r|
-    // q.field(path_camel_case!(Boulder::removed)).eq(r)),
-    // is_draft.map(|d| q.field(path_camel_case!(Boulder::is_draft)).eq(d)),
-    //                 ]
-    //                 .into_iter()
-    //                 .flatten(),
-    //             )
-    //         })
-    //         .obj()
-    //         .stream_query_with_errors()
-    //         .await?;
-    //
-    //     let as_vec: Vec<Boulder> = object_stream.try_collect().await?;
-    //     Ok(as_vec)
-    // }
 }
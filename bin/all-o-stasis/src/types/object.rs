@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
 use chrono::{DateTime, Utc};
 use otp::ObjectId;
@@ -7,26 +7,24 @@ use serde_json::Value;
 
 use crate::{
     AppError, AppState,
-    storage::update_view_typed,
-    types::{ObjectType, Patch, store},
+    storage::{remove_view_typed, update_view_typed},
+    types::{ObjectType, Patch, Snapshot},
 };
 
 // Object storage representation - used for Firestore serialization
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct ObjectDoc {
     #[serde(alias = "_firestore_id")]
-    id: Option<ObjectId>,
+    pub id: Option<ObjectId>,
     #[serde(alias = "_firestore_created")]
-    created_at: Option<DateTime<Utc>>,
-    object_type: ObjectType,
-    created_by: ObjectId,
-    deleted: Option<bool>,
+    pub created_at: Option<DateTime<Utc>>,
+    pub object_type: ObjectType,
+    pub created_by: ObjectId,
+    pub deleted: Option<bool>,
 }
 
 impl ObjectDoc {
-    const COLLECTION: &str = "objects";
-
     fn new(object_type: ObjectType) -> Self {
         Self {
             id: None,
@@ -39,18 +37,12 @@ impl ObjectDoc {
 
     async fn lookup(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: ObjectId,
     ) -> Result<Self, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
         state
             .db
-            .fluent()
-            .select()
-            .by_id_in(ObjectDoc::COLLECTION)
-            .parent(&parent_path)
-            .obj()
-            .one(&object_id)
+            .lookup_object(gym, &object_id)
             .await?
             .ok_or(AppError::Query(format!(
                 "lookup_object: failed to get object {object_id}"
@@ -60,10 +52,9 @@ impl ObjectDoc {
     pub async fn store(
         &self,
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Self, AppError> {
-        let s: Option<Self> = store!(state, gym, self, Self::COLLECTION);
-        s.ok_or(AppError::Internal("storing object failed".to_string()))
+        state.db.create_object(gym, self).await
     }
 }
 
@@ -81,7 +72,6 @@ pub struct Object {
     pub created_at: DateTime<Utc>,
     pub object_type: ObjectType,
     pub created_by: ObjectId,
-    #[allow(dead_code)]
     pub deleted: bool,
 }
 
@@ -106,7 +96,7 @@ impl TryFrom<ObjectDoc> for Object {
 impl Object {
     pub async fn new(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_type: &ObjectType,
     ) -> Result<Self, AppError> {
         let obj_doc = ObjectDoc::new(object_type.clone())
@@ -116,9 +106,22 @@ impl Object {
         Ok(obj)
     }
 
+    /// lookup an object, fails with [`AppError::Gone`] if it was deleted
     pub async fn lookup(
         state: &AppState,
-        gym: &String,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Self, AppError> {
+        let obj = Self::lookup_including_deleted(state, gym, object_id).await?;
+        if obj.deleted {
+            return Err(AppError::Gone(obj.id));
+        }
+        Ok(obj)
+    }
+
+    pub async fn lookup_including_deleted(
+        state: &AppState,
+        gym: &str,
         object_id: &ObjectId,
     ) -> Result<Self, AppError> {
         let obj_doc = ObjectDoc::lookup(state, gym, object_id.clone()).await?;
@@ -126,9 +129,51 @@ impl Object {
         Ok(obj)
     }
 
+    /// all existing objects with one of the ids, including deleted ones
+    pub async fn lookup_many(
+        state: &AppState,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Self>, AppError> {
+        let mut objects = HashMap::new();
+        for obj_doc in state.db.lookup_objects(gym, object_ids).await? {
+            let obj: Object = obj_doc.try_into()?;
+            objects.insert(obj.id.clone(), obj);
+        }
+        Ok(objects)
+    }
+
+    /// mark the object as deleted and remove it from its view
+    pub async fn delete(
+        &self,
+        state: &AppState,
+        gym: &str,
+    ) -> Result<(), AppError> {
+        state.db.set_object_deleted(gym, &self.id, true).await?;
+        remove_view_typed(state, gym, &self.id, &self.object_type).await
+    }
+
+    /// undo [`Object::delete`], the view is rebuilt from the latest snapshot
+    pub async fn restore(
+        &self,
+        state: &AppState,
+        gym: &str,
+    ) -> Result<(), AppError> {
+        let snapshot = Snapshot::lookup_latest(state, gym, &self.id).await?;
+        update_view_typed(
+            state,
+            gym,
+            &self.id,
+            &self.object_type,
+            &snapshot.content,
+        )
+        .await?;
+        state.db.set_object_deleted(gym, &self.id, false).await
+    }
+
     pub async fn from_value(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         author_id: String,
         object_type: ObjectType,
         value: &Value,
@@ -1,45 +1,13 @@
-use std::{
-    collections::hash_map::DefaultHasher,
-    fmt,
-    hash::{Hash, Hasher},
-    net::SocketAddr,
-};
+use std::fmt;
 
 use chrono::{DateTime, Utc};
-use firestore::{
-    FirestoreDb, FirestoreListener, FirestoreListenerTarget,
-    FirestoreMemListenStateStorage, FirestoreQueryDirection, FirestoreResult,
-    ParentPathBuilder, path_camel_case,
-};
-use futures::{TryStreamExt, stream::BoxStream};
 use otp::{ObjectId, Operation, RevId};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::{
-    AppError, AppState,
-    types::{Snapshot, store},
-};
+use crate::{AppError, AppState, backend::PatchStream};
 
-fn hash_addr(addr: &SocketAddr) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    // TODO hash addr.ip()?
-
-    match addr {
-        SocketAddr::V4(v4) => {
-            v4.ip().octets().hash(&mut hasher);
-            v4.port().hash(&mut hasher);
-        }
-        SocketAddr::V6(v6) => {
-            v6.ip().octets().hash(&mut hasher);
-            v6.port().hash(&mut hasher);
-        }
-    }
-
-    hasher.finish()
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Patch {
     pub object_id: ObjectId,
@@ -61,8 +29,6 @@ impl fmt::Display for Patch {
 }
 
 impl Patch {
-    const COLLECTION: &str = "patches";
-
     pub fn new(object_id: ObjectId, author_id: String, value: &Value) -> Self {
         let op =
             Operation::new_set(otp::ROOT_PATH.to_owned(), value.to_owned());
@@ -90,84 +56,41 @@ impl Patch {
         }
     }
 
+    /// size of the serialised operation, used to decide when to snapshot
+    pub fn operation_len(&self) -> usize {
+        serde_json::to_vec(&self.operation).map_or(0, |v| v.len())
+    }
+
     pub async fn store(
         &self,
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Self, AppError> {
-        let s: Option<Self> = store!(state, gym, self, Self::COLLECTION);
-        s.ok_or(AppError::Internal("storing patch failed".to_string()))
+        state.db.store_patch(gym, self).await
     }
 
     /// lookup a patch with rev_id
     pub async fn lookup(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: &ObjectId,
         rev_id: RevId, // inclusive
     ) -> Result<Self, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let patch_stream: BoxStream<FirestoreResult<Patch>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([
-                    q.field(path_camel_case!(Patch::object_id))
-                        .eq(object_id.clone()),
-                    q.field(path_camel_case!(Patch::revision_id)).eq(rev_id),
-                ])
-            })
-            .limit(1)
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let mut patches: Vec<Patch> = patch_stream.try_collect().await?;
-        if patches.len() != 1 {
-            return Err(AppError::Internal(format!(
-                "lookup_patch found {} patches, expecting only 1",
-                patches.len()
-            )));
-        }
-        let patch = patches.pop().ok_or(AppError::Internal(
-            "patch vec empty after length check".to_string(),
-        ))?;
-        Ok(patch)
+        state.db.lookup_patch(gym, object_id, rev_id).await?.ok_or(
+            AppError::Internal(format!(
+                "lookup_patch found no patch {object_id}@{rev_id}"
+            )),
+        )
     }
 
     /// get all patches for an object with revision id > rev_id
     pub async fn after_revision(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         obj_id: &ObjectId,
         rev_id: RevId,
     ) -> Result<Vec<Patch>, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Patch>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Patch::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all([
-                    q.field(path_camel_case!(Patch::object_id)).eq(obj_id),
-                    q.field(path_camel_case!(Patch::revision_id))
-                        .greater_than(rev_id),
-                ])
-            })
-            .order_by([(
-                path_camel_case!(Snapshot::revision_id),
-                FirestoreQueryDirection::Ascending,
-            )])
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let patches: Vec<Patch> = object_stream.try_collect().await?;
+        let patches = state.db.patches_after(gym, obj_id, rev_id).await?;
         tracing::debug!(
             "patches after rev ({rev_id}): {}, obj = {obj_id}",
             patches.len()
@@ -175,38 +98,22 @@ impl Patch {
         Ok(patches)
     }
 
-    pub async fn listener(
+    /// at most `limit` patches of an object with revision ids in `range`
+    pub async fn in_range(
         state: &AppState,
-        parent_path: &ParentPathBuilder,
-        who: SocketAddr,
-    ) -> Option<FirestoreListener<FirestoreDb, FirestoreMemListenStateStorage>>
-    {
-        let client_id = hash_addr(&who) as u32;
-        let listener_id: FirestoreListenerTarget =
-            FirestoreListenerTarget::new(client_id);
-        tracing::debug!(
-            "connection {who} gets firestore listener id: {client_id:?}"
-        );
-
-        // now start streaming patches using firestore listeners: https://github.com/abdolence/firestore-rs/blob/master/examples/listen-changes.rs
-        let mut listener = match state
-            .db
-            .create_listener(FirestoreMemListenStateStorage::new())
-            .await
-        {
-            Ok(l) => l,
-            Err(..) => return None,
-        };
-
-        let _ = state
-            .db
-            .fluent()
-            .select()
-            .from(Patch::COLLECTION)
-            .parent(parent_path)
-            .listen()
-            .add_target(listener_id, &mut listener);
+        gym: &str,
+        obj_id: &ObjectId,
+        range: (RevId, Option<RevId>),
+        limit: usize,
+    ) -> Result<Vec<Patch>, AppError> {
+        state.db.patches_in_range(gym, obj_id, range, limit).await
+    }
 
-        Some(listener)
+    /// stream all patches of the gym which are stored from now on
+    pub async fn subscribe(
+        state: &AppState,
+        gym: &str,
+    ) -> Result<PatchStream, AppError> {
+        state.db.subscribe_patches(gym).await
     }
 }
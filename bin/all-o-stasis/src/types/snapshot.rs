@@ -1,15 +1,10 @@
-use std::fmt;
+use std::{collections::HashMap, fmt};
 
-use firestore::{FirestoreQueryDirection, FirestoreResult, path_camel_case};
-use futures::{TryStreamExt, stream::BoxStream};
 use otp::{ObjectId, Operation, OtError, RevId, ZERO_REV_ID};
 use serde::{Deserialize, Serialize};
 use serde_json::{Value, json};
 
-use crate::{
-    AppError, AppState,
-    types::{patch::Patch, store},
-};
+use crate::{AppError, AppState, types::patch::Patch};
 
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -19,6 +14,32 @@ pub struct Snapshot {
     pub content: Value,
 }
 
+/// When to store a snapshot: once `revisions` patches or `patch_bytes` of
+/// serialised operations were stored since the previous snapshot. Snapshots in
+/// between are not needed as lookups replay patches from the nearest one.
+#[derive(Clone, Debug)]
+pub struct SnapshotPolicy {
+    pub revisions: RevId,
+    pub patch_bytes: usize,
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            revisions: 20,
+            patch_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl SnapshotPolicy {
+    /// whether a snapshot is due after `revisions` patches with a total of
+    /// `patch_bytes` since the previous snapshot
+    pub fn is_due(&self, revisions: RevId, patch_bytes: usize) -> bool {
+        revisions >= self.revisions || patch_bytes >= self.patch_bytes
+    }
+}
+
 impl fmt::Display for Snapshot {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -30,8 +51,6 @@ impl fmt::Display for Snapshot {
 }
 
 impl Snapshot {
-    const COLLECTION: &str = "snapshots";
-
     // TODO why is this not ZERO_REV_ID?
     /// create a new empty snapshot with revision id -1
     pub fn new(object_id: ObjectId) -> Self {
@@ -72,7 +91,7 @@ impl Snapshot {
         )))
     }
 
-    fn apply_patch(&self, patch: &Patch) -> Result<Self, AppError> {
+    pub fn apply_patch(&self, patch: &Patch) -> Result<Self, AppError> {
         Ok(Self {
             object_id: self.object_id.to_owned(),
             revision_id: patch.revision_id,
@@ -96,17 +115,16 @@ impl Snapshot {
     pub async fn store(
         &self,
         state: &AppState,
-        gym: &String,
+        gym: &str,
     ) -> Result<Self, AppError> {
-        let s: Option<Snapshot> = store!(state, gym, self, Self::COLLECTION);
-        s.ok_or(AppError::Internal("storing snapshot failed".to_string()))
+        state.db.store_snapshot(gym, self).await
     }
 
     /// lookup a snapshot with rev_id or lower and apply patches with revision
     /// <= rev_id if necessary
     pub async fn lookup(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         obj_id: &ObjectId,
         rev_id: RevId, // inclusive
     ) -> Result<Snapshot, AppError> {
@@ -139,9 +157,96 @@ impl Snapshot {
     /// apply unapplied patches to get to the latest possible revision.
     pub async fn lookup_latest(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: &ObjectId,
     ) -> Result<Self, AppError> {
+        if let Some(snapshot) = state.snapshots.get(gym, object_id) {
+            return Ok(snapshot);
+        }
+
+        let epoch = state.snapshots.watch(state, gym).await?;
+        let (checkpoint, patches) =
+            Snapshot::checkpoint(state, gym, object_id).await?;
+        let snapshot = checkpoint.apply_patches(&patches)?;
+        state.snapshots.insert(gym, &snapshot, epoch);
+        Ok(snapshot)
+    }
+
+    /// [`Snapshot::lookup_latest`] of several objects with one query for
+    /// snapshots and one for patches
+    pub async fn lookup_latest_many(
+        state: &AppState,
+        gym: &str,
+        object_ids: &[ObjectId],
+    ) -> Result<HashMap<ObjectId, Self>, AppError> {
+        let mut snapshots = HashMap::new();
+        let mut missing = Vec::new();
+        for object_id in object_ids {
+            match state.snapshots.get(gym, object_id) {
+                Some(snapshot) => {
+                    snapshots.insert(object_id.clone(), snapshot);
+                }
+                None => missing.push(object_id.clone()),
+            }
+        }
+        if missing.is_empty() {
+            return Ok(snapshots);
+        }
+
+        let epoch = state.snapshots.watch(state, gym).await?;
+        let mut checkpoints: HashMap<ObjectId, Self> = missing
+            .iter()
+            .map(|id| (id.clone(), Snapshot::new(id.clone())))
+            .collect();
+        for snapshot in state.db.latest_snapshots(gym, &missing).await? {
+            checkpoints.insert(snapshot.object_id.clone(), snapshot);
+        }
+
+        let revisions: Vec<_> = checkpoints
+            .values()
+            .map(|s| (s.object_id.clone(), s.revision_id))
+            .collect();
+        let mut patches: HashMap<ObjectId, Vec<Patch>> = HashMap::new();
+        for patch in state.db.patches_after_many(gym, &revisions).await? {
+            patches
+                .entry(patch.object_id.clone())
+                .or_default()
+                .push(patch);
+        }
+
+        for (object_id, checkpoint) in checkpoints {
+            let patches = patches.remove(&object_id).unwrap_or_default();
+            let snapshot = checkpoint.apply_patches(&patches)?;
+            state.snapshots.insert(gym, &snapshot, epoch);
+            snapshots.insert(object_id, snapshot);
+        }
+        Ok(snapshots)
+    }
+
+    /// one stored snapshot of every revision of the object which has any
+    pub async fn lookup_stored(
+        state: &AppState,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<Vec<Self>, AppError> {
+        let mut revisions = state.db.snapshot_revisions(gym, object_id).await?;
+        revisions.dedup();
+
+        let mut snapshots = Vec::new();
+        for rev_id in revisions {
+            let range = (rev_id, Some(rev_id));
+            snapshots
+                .extend(state.db.latest_snapshot(gym, object_id, range).await?);
+        }
+        Ok(snapshots)
+    }
+
+    /// the latest stored snapshot of an object and all patches after it
+    pub async fn checkpoint(
+        state: &AppState,
+        gym: &str,
+        object_id: &ObjectId,
+    ) -> Result<(Self, Vec<Patch>), AppError> {
         let latest_snapshot = Snapshot::lookup_between(
             state,
             gym,
@@ -160,67 +265,28 @@ impl Snapshot {
         )
         .await?;
 
-        // apply those patches to the snapshot
-        latest_snapshot.apply_patches(&patches)
+        Ok((latest_snapshot, patches))
     }
 
-    /// get or create a latest snapshot between low and high (inclusive)
+    /// get the latest snapshot between low and high (inclusive) or an empty
+    /// one if there is none
     async fn lookup_between(
         state: &AppState,
-        gym: &String,
+        gym: &str,
         object_id: &ObjectId,
         range: (RevId, Option<RevId>),
     ) -> Result<Snapshot, AppError> {
-        let parent_path = state.db.parent_path("gyms", gym)?;
-        let object_stream: BoxStream<FirestoreResult<Snapshot>> = state
-            .db
-            .fluent()
-            .select()
-            .from(Self::COLLECTION)
-            .parent(&parent_path)
-            .filter(|q| {
-                q.for_all(
-                    [
-                        Some(
-                            q.field(path_camel_case!(Snapshot::object_id))
-                                .eq(object_id),
-                        ),
-                        Some(
-                            q.field(path_camel_case!(Snapshot::revision_id))
-                                .greater_than_or_equal(range.0),
-                        ),
-                        range.1.map(|h| {
-                            q.field(path_camel_case!(Snapshot::revision_id))
-                                .less_than_or_equal(h)
-                        }),
-                    ]
-                    .into_iter()
-                    .flatten(),
-                )
-            })
-            .limit(1)
-            .order_by([(
-                path_camel_case!(Snapshot::revision_id),
-                FirestoreQueryDirection::Descending,
-            )])
-            .obj()
-            .stream_query_with_errors()
-            .await?;
-
-        let snapshots: Vec<Snapshot> = object_stream.try_collect().await?;
+        let snapshot = state.db.latest_snapshot(gym, object_id, range).await?;
         tracing::debug!(
-            "snapshots ({} <= s <= {:?}): {} snapshots, obj={object_id}",
+            "snapshots ({} <= s <= {:?}): found={}, obj={object_id}",
             range.0,
             range.1,
-            snapshots.len(),
+            snapshot.is_some(),
         );
-        match snapshots.first() {
-            Some(snapshot) => Ok(snapshot.clone()),
-            None => {
-                // TODO we could already create the first snapshot on object
-                // creation?
-                Ok(Snapshot::new(object_id.clone()).store(state, gym).await?)
-            }
+        match snapshot {
+            Some(snapshot) => Ok(snapshot),
+            // the empty snapshot is implied, no need to store it
+            None => Ok(Snapshot::new(object_id.clone())),
         }
     }
 }
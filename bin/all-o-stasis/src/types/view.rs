@@ -0,0 +1,236 @@
+//! Materialized views of the latest snapshot of objects.
+//!
+//! Every object type which needs to be queried by its content has a [`View`].
+//! Whenever an object changes, its view row is replaced by the projection of
+//! the new content. Backends store rows of all views the same way and only
+//! need to know the fields queries use, see [`View::indexed_fields`].
+
+use std::cmp::Ordering;
+
+use otp::ObjectId;
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Value, from_value};
+
+use crate::{
+    AppError, AppState,
+    types::{AccountsView, BouldersView, ObjectType},
+};
+
+pub trait View: Send + Sync {
+    /// the type of objects whose snapshots this view holds
+    fn object_type(&self) -> ObjectType;
+
+    /// name of the collection holding the rows
+    fn collection(&self) -> &'static str;
+
+    /// fields queries filter or order by, backends index them
+    fn indexed_fields(&self) -> &'static [&'static str];
+
+    /// the row of an object with the content
+    fn project(
+        &self,
+        object_id: &ObjectId,
+        content: &Value,
+    ) -> Result<Value, AppError>;
+}
+
+/// all views, object types without view have no rows
+pub fn views() -> [&'static dyn View; 2] {
+    [&AccountsView, &BouldersView]
+}
+
+/// the view of an object type if it has one
+pub fn view_of(object_type: &ObjectType) -> Option<&'static dyn View> {
+    views()
+        .into_iter()
+        .find(|view| view.object_type() == *object_type)
+}
+
+/// parse the content into the row type, adding the object id
+pub fn project_as<T: Serialize + DeserializeOwned>(
+    object_id: &ObjectId,
+    content: &Value,
+) -> Result<Value, AppError> {
+    let row = from_value::<T>(content.clone())
+        .map_err(|e| AppError::ParseError(format!("{e} in: {content}")))?;
+    let mut row = serde_json::to_value(row).map_err(|e| {
+        AppError::Internal(format!("serialisation failed: {e}"))
+    })?;
+    if let Some(fields) = row.as_object_mut() {
+        fields.insert(String::from("id"), Value::String(object_id.clone()));
+    }
+    Ok(row)
+}
+
+/// Condition on a field of a row. Rows without the field match neither.
+#[derive(Clone, Debug)]
+pub enum Filter {
+    Eq(&'static str, Value),
+    Ne(&'static str, Value),
+}
+
+impl Filter {
+    pub fn field(&self) -> &'static str {
+        match self {
+            Filter::Eq(field, _) | Filter::Ne(field, _) => field,
+        }
+    }
+
+    fn matches(&self, row: &Value) -> bool {
+        match self {
+            Filter::Eq(field, value) => row.get(field) == Some(value),
+            Filter::Ne(field, value) => {
+                row.get(field).is_some_and(|v| v != value)
+            }
+        }
+    }
+}
+
+/// Rows of a view matching all filters
+#[derive(Clone, Debug, Default)]
+pub struct ViewQuery {
+    pub filters: Vec<Filter>,
+    /// order the rows by this field, newest (highest) first
+    pub order_by_desc: Option<&'static str>,
+    pub limit: Option<usize>,
+}
+
+impl ViewQuery {
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    pub fn order_by_desc(self, field: &'static str) -> Self {
+        Self {
+            order_by_desc: Some(field),
+            ..self
+        }
+    }
+
+    pub fn limit(self, limit: usize) -> Self {
+        Self {
+            limit: Some(limit),
+            ..self
+        }
+    }
+
+    /// evaluate the query on rows, for backends without query support
+    pub fn apply(&self, rows: impl IntoIterator<Item = Value>) -> Vec<Value> {
+        let mut rows: Vec<Value> = rows
+            .into_iter()
+            .filter(|row| self.filters.iter().all(|f| f.matches(row)))
+            .collect();
+        if let Some(field) = self.order_by_desc {
+            rows.sort_by(|a, b| compare(b.get(field), a.get(field)));
+        }
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        rows
+    }
+}
+
+/// order of field values: missing < null < numbers < strings < others
+fn compare(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    fn rank(v: Option<&Value>) -> u8 {
+        match v {
+            None => 0,
+            Some(Value::Null) => 1,
+            Some(Value::Number(_)) => 2,
+            Some(Value::String(_)) => 3,
+            Some(_) => 4,
+        }
+    }
+
+    match (a, b) {
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// rows of a view matching the query
+pub async fn query<T: DeserializeOwned>(
+    state: &AppState,
+    gym: &str,
+    view: &dyn View,
+    query: &ViewQuery,
+) -> Result<Vec<T>, AppError> {
+    let rows = state.db.query_view(gym, view.collection(), query).await?;
+    rows.into_iter().map(parse_row).collect()
+}
+
+/// the row of an object in a view
+pub async fn lookup<T: DeserializeOwned>(
+    state: &AppState,
+    gym: &str,
+    view: &dyn View,
+    object_id: &ObjectId,
+) -> Result<Option<T>, AppError> {
+    let row = state
+        .db
+        .lookup_view(gym, view.collection(), object_id)
+        .await?;
+    row.map(parse_row).transpose()
+}
+
+fn parse_row<T: DeserializeOwned>(row: Value) -> Result<T, AppError> {
+    from_value(row.clone())
+        .map_err(|e| AppError::ParseError(format!("{e} in: {row}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn query_rows() {
+        let rows = vec![
+            json!({"id": "a", "removed": 0, "setDate": 1}),
+            json!({"id": "b", "removed": 0, "setDate": 3}),
+            json!({"id": "c", "removed": 5, "setDate": 2}),
+            json!({"id": "d", "setDate": 4}),
+        ];
+        let ids = |query: ViewQuery| -> Vec<Value> {
+            query
+                .apply(rows.clone())
+                .iter()
+                .filter_map(|row| row.get("id").cloned())
+                .collect()
+        };
+
+        let active = ViewQuery::default()
+            .filter(Filter::Eq("removed", json!(0)))
+            .order_by_desc("setDate");
+        assert_eq!(vec![json!("b"), json!("a")], ids(active));
+
+        // rows without the field do not match
+        let removed =
+            ViewQuery::default().filter(Filter::Ne("removed", json!(0)));
+        assert_eq!(vec![json!("c")], ids(removed));
+
+        let latest = ViewQuery::default().order_by_desc("setDate").limit(2);
+        assert_eq!(vec![json!("d"), json!("b")], ids(latest));
+    }
+
+    #[test]
+    fn every_object_type_has_at_most_one_view() {
+        for object_type in [
+            ObjectType::Account,
+            ObjectType::Boulder,
+            ObjectType::Passport,
+        ] {
+            let views = views()
+                .into_iter()
+                .filter(|view| view.object_type() == object_type)
+                .count();
+            assert!(views <= 1, "{object_type} has {views} views");
+        }
+    }
+}
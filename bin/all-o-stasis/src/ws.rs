@@ -1,11 +1,9 @@
-use std::{error::Error, net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
     body::Bytes,
     extract::ws::{Message, Utf8Bytes, WebSocket},
 };
-use chrono::{DateTime, Utc};
-use firestore::{FirestoreDb, FirestoreListenEvent, ParentPathBuilder};
 use futures::{
     SinkExt, StreamExt, TryStreamExt,
     stream::{SplitSink, SplitStream},
@@ -17,50 +15,52 @@ use tokio::sync::{
     mpsc::{Receiver, Sender},
 };
 
-use crate::{AppError, AppState, types::Patch};
+use crate::{
+    AppError, AppState,
+    backend::PatchStream,
+    policy::{self, ObjectFacts},
+    routes::Principal,
+    types::{Object, Patch},
+};
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
-struct WsPatchResponse {
-    content: Patch,
+struct WsResponse<T> {
+    content: T,
     #[serde(rename = "type")]
     ot_type: String,
 }
 
-async fn handle_listener_event(
-    event: FirestoreListenEvent,
-    send_tx_patch: Sender<Message>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    // tracing::debug!("got listener change: {event:?}");
-    match event {
-        FirestoreListenEvent::DocumentChange(ref doc_change) => {
-            tracing::debug!("document changed: {doc_change:?}");
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GoneContent {
+    object_id: ObjectId,
+}
 
-            if let Some(doc) = &doc_change.document {
-                // here we need the object id so we need to parse
-                let patch: Patch =
-                    FirestoreDb::deserialize_doc_to::<Patch>(doc)?;
-                let msg = Message::Text(serde_json::to_string(&patch)?.into());
-                let ps = send_tx_patch.send(msg).await;
-                if let Err(err) = ps {
-                    tracing::error!("failed to sent patch with {err}");
-                }
-            }
-        }
-        _ => {
-            tracing::error!(
-                "received a listen response event to handle: {event:?}"
-            );
+/// messages to be sent to the client
+enum Outgoing {
+    Patch(Patch),
+    /// the client subscribed to a deleted object
+    Gone(ObjectId),
+    Ping,
+}
+
+async fn forward_patches(
+    mut patches: PatchStream,
+    send_tx_patch: Sender<Outgoing>,
+) {
+    while let Some(patch) = patches.next().await {
+        if let Err(err) = send_tx_patch.send(Outgoing::Patch(patch)).await {
+            tracing::error!("failed to sent patch with {err}");
+            break;
         }
     }
-
-    Ok(())
 }
 
-async fn ping_client(ws_tx: Sender<Message>) {
+async fn ping_client(ws_tx: Sender<Outgoing>) {
     loop {
         // TODO this eventually fills the channel?
-        let sp = ws_tx.send(Message::Ping(Bytes::from_static(&[1]))).await;
+        let sp = ws_tx.send(Outgoing::Ping).await;
         if let Err(err) = sp {
             tracing::error!("failed to send ping with {err}");
             break;
@@ -69,93 +69,51 @@ async fn ping_client(ws_tx: Sender<Message>) {
     }
 }
 
+fn to_message<T: Serialize>(ot_type: &str, content: T) -> Option<Message> {
+    let reply = WsResponse {
+        content,
+        ot_type: ot_type.to_string(),
+    };
+    match serde_json::to_string(&reply) {
+        Ok(json) => Some(Message::Text(json.into())),
+        Err(e) => {
+            tracing::error!("failed to serialize reply: {e}");
+            None
+        }
+    }
+}
+
 async fn drain_channel(
-    ws_rx: &mut Receiver<Message>,
+    ws_rx: &mut Receiver<Outgoing>,
     subscriptions: Arc<Mutex<Vec<ObjectId>>>,
     sender: &mut SplitSink<WebSocket, Message>,
-    listener_start_time: DateTime<Utc>,
 ) {
     loop {
-        match ws_rx.recv().await {
-            None => {
-                tracing::error!("drain_channel: should never be in this case!");
-            }
-            Some(msg) => {
-                let processed_msg = match msg {
-                    Message::Text(t) => {
-                        let patch: Patch = match serde_json::from_str(&t) {
-                            Ok(patch) => patch,
-                            Err(e) => {
-                                tracing::error!("failed to parse patch: {e}");
-                                continue;
-                            }
-                        };
-
-                        // only send patches that came in after we started the
-                        // listener
-                        if let Some(created) = patch.created_at {
-                            if created < listener_start_time {
-                                // tracing::debug!("skipping patch {}",
-                                // patch.revision_id);
-                                continue;
-                            }
-                        } else {
-                            tracing::error!("patch without created_at");
-                            continue;
-                        }
-
-                        // only send out patches the client subscribed
-                        match subscriptions.try_lock() {
-                            Ok(subscriptions) => {
-                                if subscriptions.contains(&patch.object_id) {
-                                    let reply = WsPatchResponse {
-                                        content: patch,
-                                        ot_type: String::from("patch"),
-                                    };
-                                    let json = match serde_json::to_string(
-                                        &reply,
-                                    ) {
-                                        Ok(json) => json,
-                                        Err(e) => {
-                                            tracing::error!(
-                                                "failed to serialize reply: {e}"
-                                            );
-                                            continue;
-                                        }
-                                    };
-                                    Message::Text(json.into())
-                                } else {
-                                    continue;
-                                }
-                            }
-                            Err(_) => {
-                                tracing::debug!(
-                                    "failed to lock subscriptions.. retrying"
-                                );
-                                tokio::time::sleep(
-                                    std::time::Duration::from_millis(10),
-                                )
-                                .await;
-                                continue;
-                            }
-                        }
-                    }
-                    Message::Ping(bytes) => Message::Ping(bytes),
-                    t => {
-                        tracing::error!(
-                            "received unexpected message on ws_send: {t:?}"
-                        );
-                        continue;
-                    }
-                };
+        let Some(outgoing) = ws_rx.recv().await else {
+            tracing::error!("drain_channel: should never be in this case!");
+            break;
+        };
 
-                if let Err(err) = sender.send(processed_msg).await {
-                    tracing::error!(
-                        "failed send message over websocket with {err}"
-                    );
-                    break;
+        let msg = match outgoing {
+            Outgoing::Patch(patch) => {
+                // only send out patches the client subscribed
+                if !subscriptions.lock().await.contains(&patch.object_id) {
+                    continue;
                 }
+                to_message("patch", patch)
             }
+            Outgoing::Gone(object_id) => {
+                to_message("gone", GoneContent { object_id })
+            }
+            Outgoing::Ping => Some(Message::Ping(Bytes::from_static(&[1]))),
+        };
+        let Some(msg) = msg else {
+            continue;
+        };
+
+        if let Err(err) = sender.send(msg).await {
+            tracing::error!("failed send message over websocket with {err}");
+            break;
         }
     }
 }
@@ -176,10 +134,31 @@ fn handle_subscribe(t: &Utf8Bytes) -> Result<String, AppError> {
     }
 }
 
+/// whether the client may subscribe to the object, deleted objects are gone
+async fn subscribable(
+    state: &AppState,
+    gym: &str,
+    reader: Option<&Principal>,
+    object_id: &ObjectId,
+) -> Result<bool, AppError> {
+    match Object::lookup(state, gym, object_id).await {
+        Ok(object) => {
+            policy::authorize_read_history(reader, &ObjectFacts::of(&object))?;
+            Ok(true)
+        }
+        Err(AppError::Gone(_)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 async fn sub(
     receiver: &mut SplitStream<WebSocket>,
     subscriptions: Arc<Mutex<Vec<ObjectId>>>,
     who: SocketAddr,
+    state: AppState,
+    gym: String,
+    reader: Option<Principal>,
+    ws_tx: Sender<Outgoing>,
 ) {
     loop {
         match receiver.try_next().await {
@@ -198,9 +177,27 @@ async fn sub(
             Ok(Some(msg)) => match msg {
                 Message::Text(t) => match handle_subscribe(&t) {
                     Ok(object_id) => {
-                        subscriptions.lock().await.push(object_id.clone());
-                        // tracing::debug!("+++ {who} subscribing to
-                        // {object_id}");
+                        match subscribable(
+                            &state,
+                            &gym,
+                            reader.as_ref(),
+                            &object_id,
+                        )
+                        .await
+                        {
+                            Ok(true) => {
+                                subscriptions.lock().await.push(object_id);
+                            }
+                            Ok(false) => {
+                                let _ =
+                                    ws_tx.send(Outgoing::Gone(object_id)).await;
+                            }
+                            Err(e) => {
+                                tracing::error!(
+                                    "{who} can not subscribe {object_id}: {e:?}"
+                                );
+                            }
+                        }
                     }
                     Err(e) => {
                         tracing::error!("{who} sent unexpected message: {e:?}");
@@ -228,35 +225,35 @@ pub(crate) async fn handle_socket(
     socket: WebSocket,
     who: SocketAddr,
     state: AppState,
-    parent_path: ParentPathBuilder,
+    gym: String,
+    reader: Option<Principal>,
 ) {
     let (mut sender, mut receiver) = socket.split();
 
     // TODO use unbounded channel?
     // channel for messages to be sent back
     let (ws_tx, mut ws_rx) = mpsc::channel(1000);
-    // firestore listener also needs to be able to sned on the channel
-    let ws_tx_listener = ws_tx.clone();
+    // patch subscription and subscribe handler also need to be able to send
+    // on the channel
+    let ws_tx_patches = ws_tx.clone();
+    let ws_tx_subs = ws_tx.clone();
 
     // collect all objects ids the client wants to get notified about changes
     let subscriptions: Arc<Mutex<Vec<ObjectId>>> =
         Arc::new(Mutex::new(Vec::new()));
 
-    let mut listener = match Patch::listener(&state, &parent_path, who).await {
-        Some(listener) => listener,
-        None => return,
+    // only patches stored after subscribing are streamed
+    let patches = match Patch::subscribe(&state, &gym).await {
+        Ok(patches) => patches,
+        Err(e) => {
+            tracing::error!("{who} failed to subscribe to patches: {e:?}");
+            return;
+        }
     };
-
-    // so this calls tokio::spawn
-    // starting the listener_loop: https://docs.rs/firestore/0.44.1/src/firestore/db/listen_changes.rs.html#360
-    let _ = listener
-        .start(move |event| {
-            handle_listener_event(event, ws_tx_listener.clone())
-        })
-        .await;
-    // hack to only send out patches that are added to the collection after we
-    // start the listener
-    let listener_start_time = Utc::now();
+    let mut listen =
+        tokio::spawn(
+            async move { forward_patches(patches, ws_tx_patches).await },
+        );
 
     // ping the client every 10 seconds
     let mut ping = tokio::spawn(async move { ping_client(ws_tx).await });
@@ -267,28 +264,32 @@ pub(crate) async fn handle_socket(
     // it implements the filtering
     let subs_for_sender = Arc::clone(&subscriptions);
     let mut ws_send = tokio::spawn(async move {
-        drain_channel(
-            &mut ws_rx,
-            subs_for_sender,
-            &mut sender,
-            listener_start_time,
-        )
-        .await;
+        drain_channel(&mut ws_rx, subs_for_sender, &mut sender).await;
     });
 
     // recieve object ids the client wants to subscibe
-    let mut handle_obj_subs =
-        tokio::spawn(
-            async move { sub(&mut receiver, subscriptions, who).await },
-        );
+    let mut handle_obj_subs = tokio::spawn(async move {
+        sub(
+            &mut receiver,
+            subscriptions,
+            who,
+            state,
+            gym,
+            reader,
+            ws_tx_subs,
+        )
+        .await
+    });
 
     tokio::select! {
+        _ = &mut listen => { tracing::debug!(">>> listen aborted") },
         _ = &mut ping => { tracing::debug!(">>> ping aborted") },
         _ = &mut ws_send => {tracing::debug!(">>> ws_send aborted") },
         _ = &mut handle_obj_subs => {tracing::debug!(">>> handle_subscriptions aborted") },
     }
 
-    let _ = listener.shutdown().await;
+    // dropping the patch stream stops the subscription
+    listen.abort();
     ping.abort();
     ws_send.abort();
     handle_obj_subs.abort();
@@ -0,0 +1,113 @@
+use serde_json::Value;
+
+use crate::{Operation, Path};
+
+/// Compute [`Operation`]s which turn `from` into `to`.
+///
+/// Objects are compared key by key, keys missing in `to` are removed. All
+/// other values (including arrays) are replaced as a whole when they differ.
+///
+/// ```
+/// use otp::{Operation, diff};
+/// use serde_json::json;
+///
+/// let from = json!({"grade": "blue", "sector": "kurswand", "name": "x"});
+/// let to = json!({"grade": "red", "sector": "kurswand"});
+///
+/// let ops = diff(&from, &to);
+/// let content = ops
+///     .iter()
+///     .try_fold(from, |content, op| op.apply_to(content))
+///     .unwrap();
+/// assert_eq!(to, content);
+/// assert_eq!(2, ops.len());
+/// ```
+pub fn diff(from: &Value, to: &Value) -> Vec<Operation> {
+    let mut ops = Vec::new();
+    diff_at("", from, to, &mut ops);
+    ops
+}
+
+fn diff_at(path: &str, from: &Value, to: &Value, ops: &mut Vec<Operation>) {
+    if from == to {
+        return;
+    }
+
+    let join = |key: &str| -> Path {
+        if path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{path}.{key}")
+        }
+    };
+
+    match (from, to) {
+        (Value::Object(f), Value::Object(t)) => {
+            for key in f.keys().filter(|key| !t.contains_key(*key)) {
+                ops.push(Operation::Set {
+                    path: join(key),
+                    value: None,
+                });
+            }
+            for (key, value) in t {
+                match f.get(key) {
+                    Some(old) => diff_at(&join(key), old, value, ops),
+                    None => {
+                        ops.push(Operation::new_set(join(key), value.clone()))
+                    }
+                }
+            }
+        }
+        _ => ops.push(Operation::new_set(path, to.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn apply(ops: &[Operation], content: Value) -> Value {
+        ops.iter()
+            .try_fold(content, |content, op| op.apply_to(content))
+            .expect("diff operations apply")
+    }
+
+    #[test]
+    fn diff_of_equal_values_is_empty() {
+        let value = json!({"a": [1, 2], "b": {"c": null}});
+        assert!(diff(&value, &value).is_empty());
+    }
+
+    #[test]
+    fn diff_nested_objects() {
+        let from = json!({"a": {"b": 1, "c": 2}, "d": [1, 2], "e": "x"});
+        let to = json!({"a": {"b": 1, "c": 3, "f": true}, "d": [2]});
+
+        let ops = diff(&from, &to);
+        assert_eq!(
+            vec![
+                Operation::Set {
+                    path: "e".into(),
+                    value: None
+                },
+                Operation::new_set("a.c", json!(3)),
+                Operation::new_set("a.f", json!(true)),
+                Operation::new_set("d", json!([2])),
+            ],
+            ops
+        );
+        assert_eq!(to, apply(&ops, from));
+    }
+
+    #[test]
+    fn diff_replaces_root_of_different_types() {
+        let from = json!({"a": 1});
+        let to = json!([1, 2]);
+
+        let ops = diff(&from, &to);
+        assert_eq!(vec![Operation::new_set("", to.clone())], ops);
+        assert_eq!(to, apply(&ops, from));
+    }
+}
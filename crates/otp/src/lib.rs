@@ -2,15 +2,23 @@
 //!
 //! Clients apply [`Operation`]s optimistically and use [`rebase`] to adjust
 //! any pending local ops around server patches that arrive concurrently.
+//! [`rebase_many`] does the same for a whole batch of pending ops at once.
+//! [`diff`] computes the ops which turn one value into another.
 //! The server serializes all ops and is the single source of truth.
 
 use std::{error::Error, fmt};
 
+mod diff;
 mod operation;
 mod path;
 mod rebase;
 
-pub use crate::{operation::Operation, rebase::rebase};
+pub use crate::{
+    diff::diff,
+    operation::Operation,
+    path::{is_subpath, leaf_paths, value_at},
+    rebase::{rebase, rebase_many},
+};
 
 pub type Path = String;
 
@@ -4,21 +4,24 @@ use crate::Path;
 
 /// Check if path is reachable starting from value
 pub(crate) fn is_reachable(path: impl Into<Path>, value: &Value) -> bool {
-    let path = path.into();
+    value_at(&path.into(), value).is_some()
+}
+
+/// Lookup the value at `path` starting from `value`.
+///
+/// Keys of [`Value::Object`]s are followed by name and elements of
+/// [`Value::Array`]s by their "id" field. Primitive values (and elements of
+/// arrays without an "id") are not reachable through a path.
+pub fn value_at<'a>(path: &str, value: &'a Value) -> Option<&'a Value> {
     if path.is_empty() {
-        return true;
+        return Some(value);
     }
 
     let mut content = value;
-
-    let paths: Vec<&str> = path.split('.').collect();
-    for p in paths {
+    for p in path.split('.') {
         content = match content {
-            Value::Object(o) => match o.get(p) {
-                Some(v) => v,
-                None => return false,
-            },
-            Value::Array(a) => match a.iter().find(|element| match element {
+            Value::Object(o) => o.get(p)?,
+            Value::Array(a) => a.iter().find(|element| match element {
                 // only can reach objects in list and objects need matching
                 // "id"s
                 Value::Object(o) => {
@@ -26,15 +29,61 @@ pub(crate) fn is_reachable(path: impl Into<Path>, value: &Value) -> bool {
                 }
                 // other types in lists are not reachable (primitive types)
                 _ => false,
-            }) {
-                Some(v) => v,
-                None => return false,
-            },
-            _ => return false,
+            })?,
+            _ => return None,
         }
     }
 
-    true
+    Some(content)
+}
+
+/// Check if `path` is equal to `prefix` or a path below it.
+///
+/// The root path is a prefix of every path.
+pub fn is_subpath(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Collect the paths of all leaves of `value`, where `value` is located at
+/// `prefix`.
+///
+/// Leaves are all values which can not be traversed further by a path:
+/// primitives, empty objects and arrays and arrays whose elements are not all
+/// objects with a string "id".
+pub fn leaf_paths(prefix: &str, value: &Value) -> Vec<Path> {
+    let join = |key: &str| {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{prefix}.{key}")
+        }
+    };
+
+    match value {
+        Value::Object(o) if !o.is_empty() => o
+            .iter()
+            .flat_map(|(key, v)| leaf_paths(&join(key), v))
+            .collect(),
+        Value::Array(a) if !a.is_empty() => {
+            let ids: Option<Vec<&str>> = a
+                .iter()
+                .map(|element| element.get("id").and_then(Value::as_str))
+                .collect();
+            match ids {
+                Some(ids) => ids
+                    .into_iter()
+                    .zip(a)
+                    .flat_map(|(id, v)| leaf_paths(&join(id), v))
+                    .collect(),
+                None => vec![prefix.to_string()],
+            }
+        }
+        _ => vec![prefix.to_string()],
+    }
 }
 
 #[cfg(test)]
@@ -94,4 +143,60 @@ mod tests {
         let value = json!(["a", "b", "c"]);
         assert!(!is_reachable(Path::from("c"), &value));
     }
+
+    #[test]
+    fn value_at_follows_ids() {
+        let value = json!({"xx": [{"id": "a", "yy": 1}, {"id": "b", "yy": 2}]});
+        assert_eq!(Some(&value), value_at("", &value));
+        assert_eq!(Some(&json!(2)), value_at("xx.b.yy", &value));
+        assert_eq!(None, value_at("xx.c.yy", &value));
+    }
+
+    #[test]
+    fn is_subpath_for_paths() {
+        assert!(is_subpath("foo", ""));
+        assert!(is_subpath("foo", "foo"));
+        assert!(is_subpath("foo.bar", "foo"));
+        assert!(!is_subpath("foobar", "foo"));
+        assert!(!is_subpath("foo", "foo.bar"));
+        assert!(!is_subpath("", "foo"));
+    }
+
+    #[test]
+    fn leaf_paths_for_primitive_values() {
+        assert_eq!(vec![""], leaf_paths("", &json!(null)));
+        assert_eq!(vec!["x"], leaf_paths("x", &json!(42)));
+        assert_eq!(vec!["x"], leaf_paths("x", &json!({})));
+        assert_eq!(vec!["x"], leaf_paths("x", &json!([])));
+    }
+
+    #[test]
+    fn leaf_paths_for_nested_values() {
+        let value = json!({
+            "name": "foo",
+            "xx": {"yy": "zz", "empty": {}},
+            "numbers": [1, 2, 3],
+            "items": [{"id": "a", "bar": true}, {"id": "b", "bar": false}],
+            "no_ids": [{"bar": true}],
+        });
+
+        let mut leaves = leaf_paths("", &value);
+        leaves.sort();
+        assert_eq!(
+            vec![
+                "items.a.bar",
+                "items.a.id",
+                "items.b.bar",
+                "items.b.id",
+                "name",
+                "no_ids",
+                "numbers",
+                "xx.empty",
+                "xx.yy",
+            ],
+            leaves
+        );
+        // all leaves are reachable
+        assert!(leaves.iter().all(|leaf| is_reachable(leaf, &value)));
+    }
 }
@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+
 use serde_json::Value;
 
 use crate::{OtError, operation::Operation, path::is_reachable};
@@ -67,6 +69,93 @@ pub fn rebase<'a>(
     Ok(op)
 }
 
+/// Rebase a whole list of pending `ops`, all created against `content`, on
+/// top of `operations` in a single pass.
+///
+/// This is equivalent to calling [`rebase`] for every op in turn, where each
+/// op is rebased through `operations` followed by all earlier ops that were
+/// accepted, but `operations` are only applied to the content once.
+///
+/// Returns one entry per op in `ops`: the rebased operation or `None` if it
+/// conflicts. Returns [`OtError::Rebase`] if `operations` do not apply cleanly
+/// to `content`.
+///
+/// A rebased op which fails to apply or leaves the content unchanged is
+/// returned as is but not used to rebase the ops following it. Callers are
+/// expected to skip (or report) it when applying the result.
+///
+/// ## Example
+///
+/// ```rust
+/// use serde_json::json;
+/// use otp::{rebase_many, Operation};
+///
+/// let content = json!({"array": [1, 2, 3]});
+/// let base = Operation::Splice {
+///     path: String::from("array"),
+///     index: 0,
+///     remove: 1,
+///     insert: json!([]),
+/// };
+/// let op = Operation::Splice {
+///     path: String::from("array"),
+///     index: 2,
+///     remove: 1,
+///     insert: json!([]),
+/// };
+///
+/// let rebased = rebase_many(content, [op], [base].iter()).unwrap();
+/// assert_eq!(
+///     rebased,
+///     vec![Some(Operation::Splice {
+///         path: String::from("array"),
+///         index: 1,
+///         remove: 1,
+///         insert: json!([]),
+///     })]
+/// );
+/// ```
+pub fn rebase_many<'a>(
+    content: Value,
+    ops: impl IntoIterator<Item = Operation>,
+    operations: impl Iterator<Item = &'a Operation>,
+) -> Result<Vec<Option<Operation>>, OtError> {
+    let mut content = content;
+    let mut pending: VecDeque<Option<Operation>> =
+        ops.into_iter().map(Some).collect();
+
+    // transform all pending ops through each base operation at once
+    for operation in operations {
+        content = operation.apply_to(content).map_err(|e| {
+            OtError::Rebase(format!(
+                "unexpected failure while applying patches: {e}"
+            ))
+        })?;
+        for op in pending.iter_mut() {
+            *op = op.take().and_then(|op| op_ot(&content, operation, op));
+        }
+    }
+
+    // every accepted op becomes a base operation for the ops following it
+    let mut rebased = Vec::with_capacity(pending.len());
+    while let Some(op) = pending.pop_front() {
+        if let Some(operation) = &op
+            && let Ok(value) = operation.apply_to(content.clone())
+            && value != content
+        {
+            content = value;
+            for next in pending.iter_mut() {
+                *next = next
+                    .take()
+                    .and_then(|next| op_ot(&content, operation, next));
+            }
+        }
+        rebased.push(op);
+    }
+
+    Ok(rebased)
+}
+
 /// Apply `op` on top of `base` with values `content`.
 /// Conflict resolution:
 /// ```plain
@@ -276,7 +365,9 @@ mod tests {
             value: Some(json!(a2)),
         };
 
-        Some(op2.clone()) == rebase(base_val, op2, [op1].iter()).unwrap()
+        // setting the same value twice is a duplicate, which is dropped
+        a1 == a2
+            || Some(op2.clone()) == rebase(base_val, op2, [op1].iter()).unwrap()
     }
 
     #[quickcheck]
@@ -446,6 +537,121 @@ mod tests {
         )
     }
 
+    #[test]
+    fn rebase_many_through_none() {
+        let content = json!({"name": "test", "array": [1, 2, 3]});
+        let ops = vec![
+            Operation::Set {
+                path: "name".into(),
+                value: Some(json!("new")),
+            },
+            Operation::Splice {
+                path: "array".into(),
+                index: 0,
+                remove: 1,
+                insert: json!([]),
+            },
+        ];
+
+        let rebased = rebase_many(content, ops.clone(), [].iter()).unwrap();
+        assert_eq!(ops.into_iter().map(Some).collect::<Vec<_>>(), rebased)
+    }
+
+    #[test]
+    fn rebase_many_matches_sequential_rebase() {
+        let content = json!({"array": [1, 2, 3, 4, 5], "name": "test"});
+        let base = [
+            Operation::Set {
+                path: "name".into(),
+                value: Some(json!("new name")),
+            },
+            Operation::Splice {
+                path: "array".into(),
+                index: 0,
+                remove: 2,
+                insert: json!([]),
+            },
+        ];
+        let ops = vec![
+            Operation::Splice {
+                path: "array".into(),
+                index: 3,
+                remove: 0,
+                insert: json!([10, 20]),
+            },
+            Operation::Set {
+                path: "name".into(),
+                value: Some(json!("other name")),
+            },
+            Operation::Splice {
+                path: "array".into(),
+                index: 4,
+                remove: 1,
+                insert: json!([30]),
+            },
+        ];
+
+        // rebase every op through the base and all previously accepted ops
+        let mut accepted: Vec<Operation> = Vec::new();
+        let mut expected = Vec::new();
+        for op in ops.clone() {
+            let rebased =
+                rebase(content.clone(), op, base.iter().chain(accepted.iter()))
+                    .unwrap();
+            if let Some(op) = &rebased {
+                accepted.push(op.clone());
+            }
+            expected.push(rebased);
+        }
+
+        assert_eq!(expected, rebase_many(content, ops, base.iter()).unwrap())
+    }
+
+    #[test]
+    fn rebase_many_conflicts() {
+        let content = json!({"user": {"name": "test", "age": 30}});
+        let base = [Operation::Set {
+            path: "user.name".into(),
+            value: Some(json!("new name")),
+        }];
+        let ops = vec![
+            // conflicts with the base operation
+            Operation::Set {
+                path: "user".into(),
+                value: Some(json!({"name": "other", "age": 31})),
+            },
+            Operation::Set {
+                path: "user.age".into(),
+                value: Some(json!(32)),
+            },
+        ];
+
+        assert_eq!(
+            vec![None, ops.get(1).cloned()],
+            rebase_many(content, ops, base.iter()).unwrap()
+        )
+    }
+
+    #[test]
+    fn rebase_many_fails_on_invalid_base() {
+        let content = json!({"name": "test"});
+        let base = [Operation::Splice {
+            path: "name".into(),
+            index: 0,
+            remove: 1,
+            insert: json!([]),
+        }];
+        let ops = vec![Operation::Set {
+            path: "name".into(),
+            value: Some(json!("new")),
+        }];
+
+        assert!(matches!(
+            rebase_many(content, ops, base.iter()),
+            Err(OtError::Rebase(_))
+        ))
+    }
+
     // Tests for op_ot function
 
     #[quickcheck]
@@ -503,8 +709,9 @@ mod tests {
             value: Some(json!(val2)),
         };
 
-        // When both Set operations target the same path, op2 should be returned
-        Some(op2.clone()) == op_ot(&content, &op1, op2)
+        // When both Set operations target the same path, op2 should be
+        // returned unless it is a duplicate
+        val1 == val2 || Some(op2.clone()) == op_ot(&content, &op1, op2)
     }
 
     #[test]
@@ -55,9 +55,10 @@
 
           CLOUD_RUN_SERVICE_NAME=api-dev
           MAILEROO_API_KEY=$(op read "op://personal/maileroo boulderapp/credential" --no-newline)
+          TOKEN_KEY=$(op read "op://personal/all-o-stasis dev token key/credential" --no-newline)
 
           gcloud --project $PROJECT_ID run deploy $CLOUD_RUN_SERVICE_NAME --image=$IMAGE --region=europe-west1 \
-            --set-env-vars MAILEROO_API_KEY=$MAILEROO_API_KEY,FIRESTORE_DATABASE_ID=dev-db
+            --set-env-vars MAILEROO_API_KEY=$MAILEROO_API_KEY,TOKEN_KEY=$TOKEN_KEY,FIRESTORE_DATABASE_ID=dev-db,ENVIRONMENT=development,FRONTEND_URL=https://dev.boulderhalle.app,TRUSTED_PROXY_HOPS=1
         '';
 
         app = pkgs.rustPlatform.buildRustPackage {